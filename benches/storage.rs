@@ -0,0 +1,53 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use kv::{MemTable, SledDb, Storage};
+
+const SIZES: [usize; 3] = [1_000, 10_000, 100_000];
+
+fn fill(store: &impl Storage, size: usize) {
+    for i in 0..size {
+        store
+            .set("bench", format!("key-{}", i).into(), (i as i64).into())
+            .unwrap();
+    }
+}
+
+fn bench_store(c: &mut Criterion, name: &str, store: &impl Storage, size: usize) {
+    let mut group = c.benchmark_group(name);
+
+    group.bench_with_input(BenchmarkId::new("get_all", size), &size, |b, _| {
+        b.iter(|| black_box(store.get_all("bench").unwrap()))
+    });
+    group.bench_with_input(BenchmarkId::new("get_iter", size), &size, |b, _| {
+        b.iter(|| {
+            for pair in store.get_iter("bench").unwrap() {
+                black_box(pair);
+            }
+        })
+    });
+    // only pull the first item to show the cost of starting an iteration
+    group.bench_with_input(BenchmarkId::new("get_iter_first", size), &size, |b, _| {
+        b.iter(|| black_box(store.get_iter("bench").unwrap().next()))
+    });
+
+    group.finish();
+}
+
+fn memtable(c: &mut Criterion) {
+    for size in SIZES {
+        let store = MemTable::new();
+        fill(&store, size);
+        bench_store(c, "memtable", &store, size);
+    }
+}
+
+fn sled(c: &mut Criterion) {
+    for size in SIZES {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SledDb::new(dir.path());
+        fill(&store, size);
+        bench_store(c, "sled", &store, size);
+    }
+}
+
+criterion_group!(benches, memtable, sled);
+criterion_main!(benches);
@@ -3,7 +3,18 @@ use std::process::Command;
 fn main() {
     let mut config = prost_build::Config::new();
     config.bytes(["."]);
-    config.type_attribute(".", "#[derive(PartialOrd)]");
+    // ordered, and unlike HashMap comparable
+    config.btree_map(["."]);
+    // prost derives PartialOrd on plain enums already, only messages and
+    // their oneofs need it
+    config.message_attribute(".", "#[derive(PartialOrd)]");
+    for oneof in [
+        ".abi.CommandRequest.request_data",
+        ".abi.Value.value",
+        ".abi.Auth.credential",
+    ] {
+        config.enum_attribute(oneof, "#[derive(PartialOrd)]");
+    }
     config
         .out_dir("src/pb")
         .compile_protos(&["abi.proto"], &["."])
@@ -3,7 +3,7 @@ use std::sync::Arc;
 use anyhow::Result;
 use async_prost::AsyncProstStream;
 use futures::prelude::*;
-use kv::{CommandRequest, CommandResponse, MemTable, Service};
+use kv::{CommandRequest, CommandResponse, MemTable, Service, Session};
 use tokio::net::TcpListener;
 use tracing::info;
 
@@ -19,11 +19,12 @@ async fn main() -> Result<()> {
         info!("Client {:?} connected", addr);
         let service_c = service.clone();
         tokio::spawn(async move {
+            let session = Arc::new(Session::new());
             let mut stream =
                 AsyncProstStream::<_, CommandRequest, CommandResponse, _>::from(stream).for_async();
             while let Some(Ok(msg)) = stream.next().await {
                 info!("Got a new command: {:?}", msg);
-                let mut resp = service_c.execute(msg);
+                let mut resp = service_c.execute(msg, &session);
                 let data = resp.next().await.unwrap();
                 stream.send(data.into()).await.unwrap();
             }
@@ -1,6 +1,7 @@
-use std::io;
+use std::{io, time::Duration};
 
-use crate::Value;
+use crate::{ErrorCode, ErrorDetail, Value};
+use http::StatusCode;
 use thiserror::Error;
 
 #[derive(Error, Debug, PartialEq)]
@@ -23,14 +24,117 @@ pub enum KvError {
     CertificateParseError(&'static str, &'static str),
     #[error("IO error: {0}")]
     IOError(String),
+    #[error("Unauthenticated: {0}")]
+    Unauthenticated(String),
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+    #[error("Rate limited: {0}, retry after {1}ms")]
+    RateLimited(String, u32),
+    #[error("Invalid config: {0}")]
+    ConfigError(String),
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+    #[error("Service unavailable: {0}")]
+    Unavailable(String),
     #[error("Internal error: {0}")]
     Internal(String),
+    #[error("Audit log check failed: {0}")]
+    AuditError(String),
     #[error("Yamux Connection error")]
     YamuxConnectionError(String),
     #[error("Quic connection error")]
     QuicConnectionError(#[from] s2n_quic::connection::Error),
     #[error("Failed to access sled db")]
     SledError(#[from] sled::Error),
+    #[error("Server error: {0}")]
+    Remote(Box<ResponseError>),
+}
+
+impl KvError {
+    /// The machine-readable code reported to clients.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::NotFound(..) => ErrorCode::NotFound,
+            Self::InvalidCommand(_) => ErrorCode::InvalidCommand,
+            Self::ConvertError(..) => ErrorCode::TypeMismatch,
+            Self::StorageError(..) | Self::SledError(_) => ErrorCode::Storage,
+            Self::EncodeError(_) => ErrorCode::Encode,
+            Self::DecodeError(_) => ErrorCode::Decode,
+            Self::FrameError(_) => ErrorCode::Frame,
+            Self::CertificateParseError(..) => ErrorCode::Certificate,
+            Self::IOError(_) => ErrorCode::Io,
+            Self::Internal(_) | Self::AuditError(_) => ErrorCode::Internal,
+            Self::YamuxConnectionError(_) | Self::QuicConnectionError(_) => ErrorCode::Connection,
+            Self::Unauthenticated(_) => ErrorCode::Unauthenticated,
+            Self::PermissionDenied(_) => ErrorCode::PermissionDenied,
+            Self::RateLimited(..) => ErrorCode::RateLimited,
+            Self::ConfigError(_) => ErrorCode::Config,
+            Self::QuotaExceeded(_) => ErrorCode::QuotaExceeded,
+            Self::Unavailable(_) => ErrorCode::Unavailable,
+            Self::Remote(e) => e.code,
+        }
+    }
+
+    /// The HTTP-like status reported to clients.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::NotFound(..) => StatusCode::NOT_FOUND,
+            Self::InvalidCommand(_)
+            | Self::ConvertError(..)
+            | Self::DecodeError(_)
+            | Self::FrameError(_) => StatusCode::BAD_REQUEST,
+            Self::Unauthenticated(_) => StatusCode::UNAUTHORIZED,
+            Self::PermissionDenied(_) => StatusCode::FORBIDDEN,
+            Self::RateLimited(..) => StatusCode::TOO_MANY_REQUESTS,
+            Self::QuotaExceeded(_) => StatusCode::INSUFFICIENT_STORAGE,
+            Self::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Remote(e) => {
+                StatusCode::from_u16(e.status as u16).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// The table, key or command the error is about, if any.
+    pub fn detail(&self) -> Option<ErrorDetail> {
+        match self {
+            Self::NotFound(table, key) => Some(ErrorDetail {
+                table: table.clone(),
+                key: key.clone(),
+                ..Default::default()
+            }),
+            Self::ConvertError(_, expected) => Some(ErrorDetail {
+                expected_type: expected.to_string(),
+                ..Default::default()
+            }),
+            Self::StorageError(command, table, key, _) => Some(ErrorDetail {
+                table: table.clone(),
+                key: key.clone(),
+                command: command.to_string(),
+                ..Default::default()
+            }),
+            Self::Remote(e) => e.detail.clone(),
+            _ => None,
+        }
+    }
+}
+
+/// A failure reported by the server in a `CommandResponse`.
+#[derive(Error, Debug, Clone, PartialEq)]
+#[error("{message} ({code:?}, status {status})")]
+pub struct ResponseError {
+    pub status: u32,
+    pub code: ErrorCode,
+    pub message: String,
+    pub detail: Option<ErrorDetail>,
+    /// How long to back off before retrying, if the server said so.
+    pub retry_after: Option<Duration>,
+}
+
+impl From<ResponseError> for KvError {
+    fn from(e: ResponseError) -> Self {
+        Self::Remote(Box::new(e))
+    }
 }
 
 impl From<io::Error> for KvError {
@@ -39,6 +143,12 @@ impl From<io::Error> for KvError {
     }
 }
 
+impl From<tokio::task::JoinError> for KvError {
+    fn from(e: tokio::task::JoinError) -> Self {
+        Self::Internal(e.to_string())
+    }
+}
+
 impl From<yamux::ConnectionError> for KvError {
     fn from(e: yamux::ConnectionError) -> Self {
         Self::YamuxConnectionError(e.to_string())
@@ -66,4 +176,29 @@ mod tests {
             err.to_string()
         );
     }
+
+    #[test]
+    fn error_should_map_to_code_and_status() {
+        let err = KvError::NotFound("t1".into(), "k1".into());
+        assert_eq!(err.code(), ErrorCode::NotFound);
+        assert_eq!(err.status(), StatusCode::NOT_FOUND);
+        let detail = err.detail().unwrap();
+        assert_eq!((detail.table.as_str(), detail.key.as_str()), ("t1", "k1"));
+
+        let err = KvError::ConvertError("v".into(), "Integer");
+        assert_eq!(err.code(), ErrorCode::TypeMismatch);
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(err.detail().unwrap().expected_type, "Integer");
+
+        let err = KvError::StorageError("set", "t1".into(), "k1".into(), "disk".into());
+        assert_eq!(err.code(), ErrorCode::Storage);
+        assert_eq!(err.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(err.detail().unwrap().command, "set");
+
+        assert_eq!(
+            KvError::RateLimited("x".into(), 5).code(),
+            ErrorCode::RateLimited
+        );
+        assert_eq!(KvError::Internal("x".into()).detail(), None);
+    }
 }
@@ -0,0 +1,95 @@
+use crate::{AsyncStorage, KvError, Service};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{info, warn};
+
+/// Longest request head we bother reading
+const MAX_HEAD: usize = 8192;
+
+/// Serve `Service::metrics` over HTTP at `GET /metrics` for Prometheus to
+/// scrape. Every response closes the connection.
+pub async fn serve_metrics<Store: AsyncStorage>(
+    listener: TcpListener,
+    service: Service<Store>,
+) -> Result<(), KvError> {
+    info!("serving metrics on {}", listener.local_addr()?);
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let service = service.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &service).await {
+                warn!("failed to serve metrics to {}: {}", addr, e);
+            }
+        });
+    }
+}
+
+async fn respond<Store: AsyncStorage>(
+    mut stream: TcpStream,
+    service: &Service<Store>,
+) -> Result<(), KvError> {
+    let mut head = Vec::with_capacity(1024);
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_HEAD {
+            return write(&mut stream, "431 Request Header Fields Too Large", "").await;
+        }
+        if stream.read_buf(&mut head).await? == 0 {
+            return Ok(());
+        }
+    }
+
+    let line = head.split(|b| *b == b'\r').next().unwrap_or_default();
+    let line = String::from_utf8_lossy(line);
+    let mut parts = line.split_whitespace();
+    let (method, target) = (parts.next(), parts.next().unwrap_or_default());
+    let path = target.split('?').next().unwrap_or_default();
+
+    match (method, path) {
+        (Some("GET"), "/metrics") => write(&mut stream, "200 OK", &service.metrics().await).await,
+        (Some("GET"), _) => write(&mut stream, "404 Not Found", "not found\n").await,
+        _ => write(&mut stream, "405 Method Not Allowed", "").await,
+    }
+}
+
+async fn write(stream: &mut TcpStream, status: &str, body: &str) -> Result<(), KvError> {
+    let res = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(res.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemTable;
+
+    async fn get(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await.unwrap();
+        res
+    }
+
+    #[tokio::test]
+    async fn metrics_endpoint_should_serve_text() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_metrics(listener, Service::new(MemTable::new())));
+
+        let res = get(addr, "/metrics?x=1").await;
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(res.contains("\r\n\r\n# HELP kv_uptime_seconds"));
+
+        let res = get(addr, "/").await;
+        assert!(res.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -1,24 +1,41 @@
 mod frame;
+mod metrics;
 mod multiplex;
 mod noise;
+mod pipeline;
 mod stream;
 mod stream_result;
 mod tls;
 mod tokio_codec;
 
 use self::{frame::read_frame, stream_result::StreamResult, tokio_codec::CompressionCodec};
-use crate::{CommandRequest, CommandResponse, KvError, Service, Storage};
+use crate::{
+    command_request::RequestData, AsyncStorage, CommandRequest, CommandResponse, KvError, Kvpair,
+    Service, Session,
+};
 use bytes::BytesMut;
 pub use frame::FrameCodec;
-use futures::{SinkExt, StreamExt};
+use futures::{
+    stream::{self as futures_stream, SelectAll},
+    SinkExt, Stream, StreamExt, TryStreamExt,
+};
+use http::StatusCode;
+pub use metrics::serve_metrics;
 pub use multiplex::*;
+pub use pipeline::PipelinedClient;
 use std::fmt::Debug;
 use std::future::Future;
 use std::marker;
+use std::sync::Arc;
 pub use tls::*;
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+pub use tokio_codec::{FrameStats, FRAME_STATS};
 use tokio_util::codec::Framed;
-use tracing::info;
+use tracing::{info, warn};
+
+/// Requests served at once on a connection; no more are read until one of
+/// them finishes
+const MAX_INFLIGHT: usize = 128;
 
 trait Acceptor<Input> {
     type Output: AsyncRead + AsyncWrite + Send + Unpin;
@@ -75,6 +92,7 @@ where
 
 pub struct ServerStream<S: AsyncRead + AsyncWrite, Store> {
     service: Service<Store>,
+    session: Arc<Session>,
     inner: Framed<S, CompressionCodec<CommandResponse, CommandRequest>>,
 }
 
@@ -84,26 +102,83 @@ pub struct ClientStream<S> {
 
 impl<S, Store> ServerStream<S, Store>
 where
-    Store: Storage,
+    Store: AsyncStorage,
     S: AsyncRead + AsyncWrite + Unpin + Send,
 {
     pub fn new(stream: S, service: Service<Store>) -> Self {
+        Self::with_session(stream, service, Arc::new(Session::new()))
+    }
+
+    /// Serve a connection whose session is already set up, e.g. with the
+    /// principal of a TLS client certificate.
+    pub fn with_session(stream: S, service: Service<Store>, session: Arc<Session>) -> Self {
         Self {
             inner: Framed::new(stream, CompressionCodec::new()),
             service,
+            session,
         }
     }
 
+    /// Serve requests until the client disconnects. Requests run
+    /// concurrently and their responses, tagged with the request's
+    /// `request_id`, are sent as soon as they are ready, so a slow request or
+    /// a subscription does not hold up the others.
+    ///
+    /// Once the client is done sending, the requests still in flight are
+    /// finished and answered; only its subscriptions are dropped.
+    ///
+    /// Subscriptions live until unsubscribed, so they do not count towards
+    /// `MAX_INFLIGHT`: a connection holding many of them must still be read
+    /// to get its `Unsubscribe`.
     pub async fn process(mut self) -> Result<(), KvError> {
-        while let Some(Ok(cmd)) = self.inner.next().await {
-            info!("process command: {:?}", cmd);
-            let mut res = self.service.execute(cmd);
-            while let Some(data) = res.next().await {
-                self.inner.send(data.into()).await?;
+        let _client = self.service.connect(&self.session);
+        let mut inflight = SelectAll::new();
+        let mut subscriptions = SelectAll::new();
+        let mut reading = true;
+        let mut failed = None;
+        while reading || !inflight.is_empty() {
+            tokio::select! {
+                cmd = self.inner.next(), if reading && inflight.len() < MAX_INFLIGHT => match cmd {
+                    Some(Ok(cmd)) => {
+                        info!("process command: {:?}", cmd);
+                        let id = cmd.request_id;
+                        let subscribe = matches!(
+                            cmd.request_data,
+                            Some(RequestData::Subscribe(_) | RequestData::Psubscribe(_))
+                        );
+                        let res = self.service.execute(cmd, &self.session);
+                        let res = res.map(move |res| {
+                            let mut res = CommandResponse::from(res);
+                            res.request_id = id;
+                            res
+                        });
+                        match subscribe {
+                            true => subscriptions.push(res),
+                            false => inflight.push(res),
+                        }
+                    }
+                    Some(Err(e)) => {
+                        warn!("failed to read request, stop reading: {}", e);
+                        failed = Some(e);
+                        reading = false;
+                        subscriptions.clear();
+                    }
+                    None => {
+                        reading = false;
+                        subscriptions.clear();
+                    }
+                },
+                Some(res) = inflight.next() => self.inner.send(res).await?,
+                Some(res) = subscriptions.next() => self.inner.send(res).await?,
+            }
+        }
+        match failed {
+            Some(e) => Err(e),
+            None => {
+                info!("process ok, client disconnect");
+                Ok(())
             }
         }
-        info!("process ok, client disconnect");
-        Ok(())
     }
 }
 
@@ -117,12 +192,46 @@ where
         }
     }
 
+    /// Send a command and wait for its response. A chunked response is
+    /// reassembled into a single `CommandResponse`.
     pub async fn execute(&mut self, cmd: &CommandRequest) -> Result<CommandResponse, KvError> {
         self.inner.send(cmd.clone()).await?;
-        self.inner
-            .next()
-            .await
-            .unwrap_or_else(|| Err(KvError::Internal("no response".into())))
+        let mut res = self.next_response().await?;
+        while res.has_more {
+            let chunk = self.next_response().await?;
+            if chunk.status != StatusCode::OK.as_u16() as u32 {
+                return Ok(chunk);
+            }
+            res.pairs.extend(chunk.pairs);
+            res.has_more = chunk.has_more;
+        }
+        Ok(res)
+    }
+
+    /// Send a command whose response carries pairs (e.g. `Hgetall`) and yield
+    /// the pairs as they arrive, chunk by chunk, instead of buffering them all.
+    pub async fn execute_pairs(
+        &mut self,
+        cmd: &CommandRequest,
+    ) -> Result<impl Stream<Item = Result<Kvpair, KvError>> + '_, KvError> {
+        self.inner.send(cmd.clone()).await?;
+
+        let chunks = futures_stream::try_unfold((self, true), |(this, has_more)| async move {
+            if !has_more {
+                return Ok::<_, KvError>(None);
+            }
+            let res = this.next_response().await?.into_result()?;
+            let has_more = res.has_more;
+            let pairs = futures_stream::iter(res.pairs.into_iter().map(Ok));
+            Ok(Some((pairs, (this, has_more))))
+        });
+
+        Ok(chunks.try_flatten())
+    }
+
+    /// Turn the connection into one that many requests can share at once.
+    pub fn pipelined(self) -> PipelinedClient<S> {
+        PipelinedClient::from_framed(self.inner)
     }
 
     pub async fn execute_streaming(self, cmd: &CommandRequest) -> Result<StreamResult, KvError> {
@@ -133,12 +242,22 @@ where
 
         StreamResult::new(stream).await
     }
+
+    async fn next_response(&mut self) -> Result<CommandResponse, KvError> {
+        self.inner
+            .next()
+            .await
+            .unwrap_or_else(|| Err(KvError::Internal("no response".into())))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{assert_res_ok, MemTable, Value};
+    use crate::{
+        assert_res_error, assert_res_ok, Authenticator, Credentials, ErrorCode, MemTable,
+        ServiceInner, Value,
+    };
     use anyhow::Result;
     use bytes::Bytes;
     use std::net::SocketAddr;
@@ -185,15 +304,149 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn client_server_chunked_hgetall_should_work() -> anyhow::Result<()> {
+        let addr = start_server().await?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ClientStream::new(stream);
+
+        // ~200KB of pairs, streamed back in several chunks
+        let v: Value = Bytes::from(vec![1u8; 1024]).into();
+        let pairs: Vec<_> = (0..200)
+            .map(|i| Kvpair::new(format!("k{:03}", i), v.clone()))
+            .collect();
+        client
+            .execute(&CommandRequest::new_hmset("t3", pairs.clone()))
+            .await?;
+
+        let cmd = CommandRequest::new_hgetall("t3");
+        let mut res = client.execute(&cmd).await?;
+        assert!(!res.has_more);
+        res.pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(res.pairs, pairs);
+
+        let mut data: Vec<_> = client.execute_pairs(&cmd).await?.try_collect().await?;
+        data.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(data, pairs);
+
+        // the connection is still usable afterwards
+        let res = client
+            .execute(&CommandRequest::new_hget("t3", "k000"))
+            .await?;
+        assert_res_ok(res, &[v], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_server_auth_should_be_per_connection() -> anyhow::Result<()> {
+        let mut credentials = Credentials::new();
+        credentials.add_token("ci", "t0ken");
+        let service = ServiceInner::new(MemTable::new())
+            .layer(Authenticator::new(credentials))
+            .into();
+        let addr = start_server_with(service).await?;
+
+        let mut client = ClientStream::new(TcpStream::connect(addr).await?);
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = client.execute(&cmd).await?;
+        assert_res_error(res, 401, "authentication required");
+
+        let res = client
+            .execute(&CommandRequest::new_auth_token("t0ken"))
+            .await?;
+        assert_res_ok(res, &[], &[]);
+        let res = client.execute(&cmd).await?;
+        assert_res_ok(res, &[Value::default()], &[]);
+
+        // a new connection starts unauthenticated
+        let mut client = ClientStream::new(TcpStream::connect(addr).await?);
+        let res = client
+            .execute(&CommandRequest::new_hget("t1", "k1"))
+            .await?;
+        assert_eq!(res.status, 401);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_should_get_typed_errors() -> anyhow::Result<()> {
+        let addr = start_server().await?;
+        let mut client = ClientStream::new(TcpStream::connect(addr).await?);
+
+        let res = client
+            .execute(&CommandRequest::new_hget("t1", "k1"))
+            .await?;
+        let err = res.error().unwrap();
+        assert_eq!(err.code, ErrorCode::NotFound);
+        assert_eq!(err.status, 404);
+        let detail = err.detail.unwrap();
+        assert_eq!((detail.table.as_str(), detail.key.as_str()), ("t1", "k1"));
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        assert!(client.execute(&cmd).await?.into_result().is_ok());
+
+        let cmd = CommandRequest {
+            request_data: None,
+            request_id: 0,
+            idempotency_key: String::new(),
+        };
+        match client.execute(&cmd).await?.into_result() {
+            Err(KvError::Remote(e)) => assert_eq!(e.code, ErrorCode::InvalidCommand),
+            v => panic!("expect a remote error, got {:?}", v),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pipelined_writes_should_be_answered_after_half_close() -> Result<()> {
+        let addr = start_server().await?;
+        let stream = TcpStream::connect(addr).await?;
+        let mut framed = Framed::new(
+            stream,
+            CompressionCodec::<CommandRequest, CommandResponse>::new(),
+        );
+
+        for i in 1..=20u64 {
+            let mut cmd = CommandRequest::new_hset("t4", format!("k{}", i), (i as i64).into());
+            cmd.request_id = i;
+            framed.send(cmd).await?;
+        }
+        framed.get_mut().shutdown().await?;
+
+        let mut ids: Vec<_> = framed
+            .map(|res| {
+                let res = res.unwrap();
+                assert_res_ok(res.clone(), &[Value::default()], &[]);
+                res.request_id
+            })
+            .collect()
+            .await;
+        ids.sort();
+        assert_eq!(ids, (1..=20).collect::<Vec<_>>());
+
+        let mut client = ClientStream::new(TcpStream::connect(addr).await?);
+        for i in 1..=20i64 {
+            let cmd = CommandRequest::new_hget("t4", format!("k{}", i));
+            assert_res_ok(client.execute(&cmd).await?, &[i.into()], &[]);
+        }
+        Ok(())
+    }
+
     async fn start_server() -> Result<SocketAddr> {
+        start_server_with(Service::new(MemTable::new())).await
+    }
+
+    async fn start_server_with(service: Service) -> Result<SocketAddr> {
         let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
         let addr = listener.local_addr().unwrap();
 
         tokio::spawn(async move {
             loop {
                 let (stream, _) = listener.accept().await.unwrap();
-                let service: Service = Service::new(MemTable::new());
-                let server = ServerStream::new(stream, service);
+                let server = ServerStream::new(stream, service.clone());
                 tokio::spawn(server.process());
             }
         });
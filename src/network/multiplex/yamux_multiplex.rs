@@ -111,7 +111,7 @@ mod tests {
     use crate::{
         assert_res_ok,
         network::noise::{NoiseClient, NoiseServer},
-        CommandRequest, MemTable, ServerStream, Service, ServiceInner, Storage, TlsClient,
+        AsyncStorage, CommandRequest, MemTable, ServerStream, Service, ServiceInner, TlsClient,
         TlsServer,
     };
     use anyhow::Result;
@@ -200,7 +200,7 @@ mod tests {
         store: S,
     ) -> Result<SocketAddr>
     where
-        S: Storage + Send + Sync + 'static,
+        S: AsyncStorage,
     {
         let addr: SocketAddr = addr.parse().unwrap();
         let listener = TcpListener::bind(addr).await.unwrap();
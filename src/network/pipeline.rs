@@ -0,0 +1,287 @@
+use super::{stream_result::StreamResult, tokio_codec::CompressionCodec};
+use crate::{CommandRequest, CommandResponse, KvError};
+use futures::{stream::SplitSink, SinkExt, Stream, StreamExt};
+use http::StatusCode;
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{self, mpsc},
+};
+use tokio_util::codec::Framed;
+use tracing::warn;
+
+type Codec = CompressionCodec<CommandRequest, CommandResponse>;
+
+/// Senders of the requests in flight by `request_id`, `None` once the
+/// connection is gone.
+type Pending = Mutex<Option<HashMap<u64, mpsc::UnboundedSender<CommandResponse>>>>;
+
+/// A client connection shared by concurrent requests.
+///
+/// Every request gets its own `request_id`, and a background task routes the
+/// responses back to the request they belong to in whatever order the server
+/// finishes them. Clones share the connection.
+pub struct PipelinedClient<S> {
+    sink: Arc<sync::Mutex<SplitSink<Framed<S, Codec>, CommandRequest>>>,
+    pending: Arc<Pending>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl<S> Clone for PipelinedClient<S> {
+    fn clone(&self) -> Self {
+        Self {
+            sink: Arc::clone(&self.sink),
+            pending: Arc::clone(&self.pending),
+            next_id: Arc::clone(&self.next_id),
+        }
+    }
+}
+
+impl<S> PipelinedClient<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    pub fn new(stream: S) -> Self {
+        Self::from_framed(Framed::new(stream, CompressionCodec::new()))
+    }
+
+    pub(crate) fn from_framed(framed: Framed<S, Codec>) -> Self {
+        let (sink, mut stream) = framed.split();
+        let pending: Arc<Pending> = Arc::new(Mutex::new(Some(HashMap::new())));
+
+        let routes = Arc::clone(&pending);
+        tokio::spawn(async move {
+            while let Some(Ok(res)) = stream.next().await {
+                let id = res.request_id;
+                let tx = match routes.lock().unwrap().as_ref() {
+                    Some(routes) => routes.get(&id).cloned(),
+                    None => None,
+                };
+                match tx {
+                    // the request may have given up waiting, that's fine
+                    Some(tx) => drop(tx.send(res)),
+                    None => warn!("response to unknown request {}", id),
+                }
+            }
+            // fail every request still waiting
+            routes.lock().unwrap().take();
+        });
+
+        Self {
+            sink: Arc::new(sync::Mutex::new(sink)),
+            pending,
+            next_id: Arc::new(AtomicU64::new(1)),
+        }
+    }
+
+    /// Send a command and wait for its response. A chunked response is
+    /// reassembled into a single `CommandResponse`.
+    pub async fn execute(&self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let mut responses = self.send(cmd).await?;
+        let mut res = responses.recv().await?;
+        while res.has_more {
+            let chunk = responses.recv().await?;
+            if chunk.status != StatusCode::OK.as_u16() as u32 {
+                return Ok(chunk);
+            }
+            res.pairs.extend(chunk.pairs);
+            res.has_more = chunk.has_more;
+        }
+        Ok(res)
+    }
+
+    /// Send a streaming command such as `Subscribe`. The connection stays
+    /// usable for other requests meanwhile.
+    pub async fn execute_streaming(&self, cmd: CommandRequest) -> Result<StreamResult, KvError> {
+        StreamResult::new(self.send(cmd).await?).await
+    }
+
+    async fn send(&self, mut cmd: CommandRequest) -> Result<Responses, KvError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        cmd.request_id = id;
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        match self.pending.lock().unwrap().as_mut() {
+            Some(routes) => routes.insert(id, tx),
+            None => return Err(KvError::Internal("connection closed".into())),
+        };
+        let responses = Responses {
+            id,
+            rx,
+            pending: Arc::clone(&self.pending),
+        };
+
+        self.sink.lock().await.send(cmd).await?;
+        Ok(responses)
+    }
+}
+
+/// The responses to one request; stops routing them once dropped.
+struct Responses {
+    id: u64,
+    rx: mpsc::UnboundedReceiver<CommandResponse>,
+    pending: Arc<Pending>,
+}
+
+impl Responses {
+    async fn recv(&mut self) -> Result<CommandResponse, KvError> {
+        self.rx
+            .recv()
+            .await
+            .ok_or_else(|| KvError::Internal("connection closed".into()))
+    }
+}
+
+impl Stream for Responses {
+    type Item = Result<CommandResponse, KvError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx).map(|res| res.map(Ok))
+    }
+}
+
+impl Drop for Responses {
+    fn drop(&mut self) {
+        if let Some(routes) = self.pending.lock().unwrap().as_mut() {
+            routes.remove(&self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assert_res_ok, network::MAX_INFLIGHT, AsyncStorage, BlockingStorage, Kvpair, MemTable,
+        ServerStream, Service, Storage, Value,
+    };
+    use bytes::Bytes;
+    use futures::future;
+    use std::{net::SocketAddr, thread, time::Duration};
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        time::{self, Instant},
+    };
+
+    /// A store whose reads of table `slow` take a while.
+    struct SlowStore(MemTable);
+
+    impl Storage for SlowStore {
+        fn get(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+            if table == "slow" {
+                thread::sleep(Duration::from_millis(200));
+            }
+            Storage::get(&self.0, table, key)
+        }
+        fn set(&self, table: &str, key: Bytes, value: Value) -> Result<Option<Value>, KvError> {
+            Storage::set(&self.0, table, key, value)
+        }
+        fn contains(&self, table: &str, key: &[u8]) -> Result<bool, KvError> {
+            Storage::contains(&self.0, table, key)
+        }
+        fn del(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+            Storage::del(&self.0, table, key)
+        }
+        fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+            Storage::get_all(&self.0, table)
+        }
+        fn get_iter(&self, table: &str) -> Result<impl Iterator<Item = Kvpair>, KvError> {
+            Storage::get_iter(&self.0, table)
+        }
+        fn key_counts(&self) -> Result<Vec<(String, u64)>, KvError> {
+            Storage::key_counts(&self.0)
+        }
+    }
+
+    async fn start_server<Store: AsyncStorage>(store: Store) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = Service::new(store);
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(ServerStream::new(stream, service.clone()).process());
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn pipelined_responses_should_arrive_out_of_order() -> anyhow::Result<()> {
+        let addr = start_server(BlockingStorage::new(SlowStore(MemTable::new()))).await;
+        let client = PipelinedClient::new(TcpStream::connect(addr).await?);
+
+        let timed = |cmd| {
+            let client = client.clone();
+            async move {
+                let res = client.execute(cmd).await;
+                (res, Instant::now())
+            }
+        };
+
+        let ((slow, slow_done), (fast, fast_done)) = future::join(
+            timed(CommandRequest::new_hget("slow", "k1")),
+            timed(CommandRequest::new_hset("fast", "k1", "v1".into())),
+        )
+        .await;
+        assert_eq!(slow?.status, 404);
+        assert_res_ok(fast?, &[Value::default()], &[]);
+        // the fast request did not wait behind the slow one
+        assert!(fast_done < slow_done);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn subscription_should_not_monopolize_the_connection() -> anyhow::Result<()> {
+        let addr = start_server(MemTable::new()).await;
+        let client = PipelinedClient::new(TcpStream::connect(addr).await?);
+
+        let mut sub = client
+            .execute_streaming(CommandRequest::new_subscribe("lobby"))
+            .await?;
+
+        // same connection, while the subscription is open
+        let cmd = CommandRequest::new_publish("lobby", vec!["hi".into()]);
+        assert_res_ok(client.execute(cmd).await?, &[], &[]);
+
+        let msg = sub.next().await.unwrap()?;
+        assert_eq!(msg.values, vec!["hi".into()]);
+
+        let cmd = CommandRequest::new_unsubscribe("lobby", sub.id);
+        assert_res_ok(client.execute(cmd).await?, &[], &[]);
+        assert!(sub.next().await.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn subscriptions_should_not_use_up_the_inflight_budget() -> anyhow::Result<()> {
+        let addr = start_server(MemTable::new()).await;
+        let client = PipelinedClient::new(TcpStream::connect(addr).await?);
+
+        let mut subs = vec![];
+        for i in 0..MAX_INFLIGHT {
+            let cmd = CommandRequest::new_subscribe(format!("t{}", i));
+            subs.push(client.execute_streaming(cmd).await?);
+        }
+
+        // the connection is still read, so they can all be unsubscribed
+        for (i, sub) in subs.iter_mut().enumerate() {
+            let cmd = CommandRequest::new_unsubscribe(format!("t{}", i), sub.id);
+            let res = time::timeout(Duration::from_secs(1), client.execute(cmd)).await??;
+            assert_res_ok(res, &[], &[]);
+            assert!(sub.next().await.is_none());
+        }
+
+        Ok(())
+    }
+}
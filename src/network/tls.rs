@@ -15,6 +15,7 @@ use tokio_rustls::{
 
 use crate::network::{Acceptor, Connector};
 use crate::KvError;
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName, prelude::FromDer};
 
 const ALPN: &str = "kv";
 
@@ -77,6 +78,34 @@ impl TlsServer {
     }
 }
 
+/// The identity of a client that authenticated with a certificate: the first
+/// DNS, email or URI subject alternative name, or else the subject's common
+/// name. `None` if the client sent no certificate.
+pub fn peer_principal<S>(stream: &ServerTlsStream<S>) -> Option<String> {
+    let certs = stream.get_ref().1.peer_certificates()?;
+    // the end-entity certificate comes first
+    cert_principal(&certs.first()?.0)
+}
+
+fn cert_principal(der: &[u8]) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(der).ok()?;
+
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        let name = san.value.general_names.iter().find_map(|name| match name {
+            GeneralName::DNSName(v) | GeneralName::RFC822Name(v) | GeneralName::URI(v) => {
+                Some(v.to_string())
+            }
+            _ => None,
+        });
+        if name.is_some() {
+            return name;
+        }
+    }
+
+    let cn = cert.subject().iter_common_name().next()?;
+    cn.as_str().ok().map(str::to_owned)
+}
+
 impl<S> Acceptor<S> for TlsServer
 where
     S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
@@ -190,6 +219,10 @@ mod tests {
     use std::net::SocketAddr;
 
     use super::*;
+    use crate::{
+        assert_res_ok, Authenticator, ClientStream, CommandRequest, Credentials, MemTable,
+        ServerStream, Service, ServiceInner, Session, Value,
+    };
     use anyhow::Result;
     use tokio::{
         io::{AsyncReadExt, AsyncWriteExt},
@@ -250,6 +283,52 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn cert_principal_should_prefer_san() {
+        let server = load_certs(SERVER_CERT).unwrap();
+        assert_eq!(cert_principal(&server[0].0), Some("kv.test.com".into()));
+
+        // no subject alternative names, fall back to the common name
+        let client = load_certs(CLIENT_CERT).unwrap();
+        assert_eq!(cert_principal(&client[0].0), Some("kv client".into()));
+    }
+
+    #[tokio::test]
+    async fn client_cert_should_authenticate_session() -> Result<()> {
+        let acceptor = TlsServer::new(SERVER_CERT, SERVER_KEY, Some(CA_CERT))?;
+        // no passwords or tokens at all, only certificates get in
+        let service: Service = ServiceInner::new(MemTable::new())
+            .layer(Authenticator::new(Credentials::new()))
+            .into();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let stream = acceptor.accept(stream).await.unwrap();
+            let session = Session::new();
+            if let Some(principal) = peer_principal(&stream) {
+                session.bind_principal(principal);
+            }
+            let server = ServerStream::with_session(stream, service, session.into());
+            server.process().await.unwrap();
+        });
+
+        let connector = TlsClient::new(
+            "kv.test.com",
+            Some((CLIENT_CERT, CLIENT_KEY)),
+            Some(CA_CERT),
+        )?;
+        let stream = connector.connect(TcpStream::connect(addr).await?).await?;
+        let mut client = ClientStream::new(stream);
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = client.execute(&cmd).await?;
+        assert_res_ok(res, &[Value::default()], &[]);
+
+        Ok(())
+    }
+
     async fn start_server(ca: Option<&str>) -> Result<SocketAddr> {
         let acceptor = TlsServer::new(SERVER_CERT, SERVER_KEY, ca)?;
 
@@ -5,6 +5,7 @@ use prost::Message;
 use std::{
     io::{Read, Write},
     marker,
+    sync::atomic::{AtomicU64, Ordering},
 };
 use tokio_util::codec::{Decoder, Encoder};
 use tracing::debug;
@@ -15,6 +16,45 @@ const MAX_FRAME: usize = 2u32.pow(31) as usize;
 const COMPRESSION_LIMIT: usize = 1436;
 const COMPRESSION_BIT: usize = 1 << 31;
 
+/// Bytes through every `CompressionCodec` of the process, both as messages
+/// and as sent over the wire, which is smaller when frames get compressed.
+#[derive(Debug, Default)]
+pub struct FrameStats {
+    message_bytes_in: AtomicU64,
+    message_bytes_out: AtomicU64,
+    wire_bytes_in: AtomicU64,
+    wire_bytes_out: AtomicU64,
+}
+
+pub static FRAME_STATS: FrameStats = FrameStats {
+    message_bytes_in: AtomicU64::new(0),
+    message_bytes_out: AtomicU64::new(0),
+    wire_bytes_in: AtomicU64::new(0),
+    wire_bytes_out: AtomicU64::new(0),
+};
+
+impl FrameStats {
+    pub fn message_bytes_in(&self) -> u64 {
+        self.message_bytes_in.load(Ordering::Relaxed)
+    }
+
+    pub fn message_bytes_out(&self) -> u64 {
+        self.message_bytes_out.load(Ordering::Relaxed)
+    }
+
+    pub fn wire_bytes_in(&self) -> u64 {
+        self.wire_bytes_in.load(Ordering::Relaxed)
+    }
+
+    pub fn wire_bytes_out(&self) -> u64 {
+        self.wire_bytes_out.load(Ordering::Relaxed)
+    }
+
+    fn add(counter: &AtomicU64, n: usize) {
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
 pub struct CompressionCodec<T: Message + Sized + Default, U: Message + Sized + Default> {
     _t: marker::PhantomData<T>,
     _u: marker::PhantomData<U>,
@@ -60,12 +100,15 @@ impl<T: Message + Sized + Default, U: Message + Sized + Default> Encoder<T>
             // compression flag & length
             dst.put_u32((msg.len() | COMPRESSION_BIT) as u32);
             // msg paylod
+            let wire = msg.len();
             dst.unsplit(msg);
-            Ok(())
+            FrameStats::add(&FRAME_STATS.wire_bytes_out, LENGTH + wire);
         } else {
             item.encode(dst)?;
-            Ok(())
+            FrameStats::add(&FRAME_STATS.wire_bytes_out, LENGTH + size);
         }
+        FrameStats::add(&FRAME_STATS.message_bytes_out, size);
+        Ok(())
     }
 }
 
@@ -93,10 +136,14 @@ impl<T: Message + Sized + Default, U: Message + Sized + Default> Decoder
 
             let msg = Self::Item::decode(&buf1[..buf1.len()])?;
             buf.advance(len);
+            FrameStats::add(&FRAME_STATS.message_bytes_in, buf1.len());
+            FrameStats::add(&FRAME_STATS.wire_bytes_in, LENGTH + len);
             Ok(Some(msg))
         } else {
             let msg = Self::Item::decode(&buf[..len])?;
             buf.advance(len);
+            FrameStats::add(&FRAME_STATS.message_bytes_in, len);
+            FrameStats::add(&FRAME_STATS.wire_bytes_in, LENGTH + len);
             Ok(Some(msg))
         }
     }
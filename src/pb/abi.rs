@@ -1,11 +1,20 @@
+// This file is @generated by prost-build.
 /// Request from client
 #[derive(PartialOrd)]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct CommandRequest {
+    /// Chosen by the client and echoed back on every response to this request,
+    /// so that responses to pipelined requests can be told apart
+    #[prost(uint64, tag = "64")]
+    pub request_id: u64,
+    /// Set on a write to have retries with the same key replay the response of
+    /// the first attempt instead of running it again
+    #[prost(string, tag = "65")]
+    pub idempotency_key: ::prost::alloc::string::String,
     #[prost(
         oneof = "command_request::RequestData",
-        tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12"
+        tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24"
     )]
     pub request_data: ::core::option::Option<command_request::RequestData>,
 }
@@ -39,14 +48,192 @@ pub mod command_request {
         Unsubscribe(super::Unsubscribe),
         #[prost(message, tag = "12")]
         Publish(super::Publish),
+        #[prost(message, tag = "13")]
+        Auth(super::Auth),
+        #[prost(message, tag = "14")]
+        Custom(super::Custom),
+        #[prost(message, tag = "15")]
+        SlowlogGet(super::SlowlogGet),
+        #[prost(message, tag = "16")]
+        SlowlogReset(super::SlowlogReset),
+        #[prost(message, tag = "17")]
+        Info(super::Info),
+        #[prost(message, tag = "18")]
+        SelectNamespace(super::SelectNamespace),
+        #[prost(message, tag = "19")]
+        ListNamespaces(super::ListNamespaces),
+        #[prost(message, tag = "20")]
+        SetMode(super::SetMode),
+        #[prost(message, tag = "21")]
+        Psubscribe(super::Psubscribe),
+        #[prost(message, tag = "22")]
+        Punsubscribe(super::Punsubscribe),
+        #[prost(message, tag = "23")]
+        Ack(super::Ack),
+        #[prost(message, tag = "24")]
+        Pending(super::Pending),
     }
 }
+/// Authenticate the connection, required before any other command when the
+/// server has authentication enabled
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Auth {
+    #[prost(oneof = "auth::Credential", tags = "1, 2")]
+    pub credential: ::core::option::Option<auth::Credential>,
+}
+/// Nested message and enum types in `Auth`.
+pub mod auth {
+    #[derive(PartialOrd)]
+    #[allow(clippy::derive_partial_eq_without_eq)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Credential {
+        #[prost(message, tag = "1")]
+        Password(super::Password),
+        #[prost(string, tag = "2")]
+        Token(::prost::alloc::string::String),
+    }
+}
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Password {
+    #[prost(string, tag = "1")]
+    pub username: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub password: ::prost::alloc::string::String,
+}
+/// A command registered by the embedding application, see
+/// `ServiceInner::command`
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Custom {
+    #[prost(string, tag = "1")]
+    pub name: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag = "2")]
+    pub args: ::prost::alloc::vec::Vec<Value>,
+}
+/// Recent commands slower than the slowlog threshold, newest first
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SlowlogGet {
+    /// At most this many entries, all of them when 0
+    #[prost(uint32, tag = "1")]
+    pub count: u32,
+}
+/// Forget the recorded slow commands
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SlowlogReset {}
+/// Scope the table and topic names of the connection to a namespace, or
+/// stop scoping them when empty
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SelectNamespace {
+    #[prost(string, tag = "1")]
+    pub name: ::prost::alloc::string::String,
+}
+/// Namespaces holding data, only its own for a connection bound to one
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ListNamespaces {}
+/// Switch the server mode, e.g. to stop writes during a migration
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SetMode {
+    #[prost(enumeration = "ServerMode", tag = "1")]
+    pub mode: i32,
+    /// Told to the clients whose commands get rejected
+    #[prost(string, tag = "2")]
+    pub reason: ::prost::alloc::string::String,
+}
+/// Server statistics
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Info {}
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ServerInfo {
+    #[prost(uint64, tag = "1")]
+    pub uptime_secs: u64,
+    /// Open connections by transport
+    #[prost(btree_map = "string, uint64", tag = "2")]
+    pub clients: ::prost::alloc::collections::BTreeMap<::prost::alloc::string::String, u64>,
+    /// Commands received by name
+    #[prost(btree_map = "string, uint64", tag = "3")]
+    pub commands: ::prost::alloc::collections::BTreeMap<::prost::alloc::string::String, u64>,
+    /// Error responses by status
+    #[prost(btree_map = "uint32, uint64", tag = "4")]
+    pub errors: ::prost::alloc::collections::BTreeMap<u32, u64>,
+    /// Topics with at least one subscriber
+    #[prost(uint64, tag = "5")]
+    pub topics: u64,
+    #[prost(uint64, tag = "6")]
+    pub subscriptions: u64,
+    /// Keys held by each table
+    #[prost(btree_map = "string, uint64", tag = "7")]
+    pub table_keys: ::prost::alloc::collections::BTreeMap<::prost::alloc::string::String, u64>,
+    #[prost(enumeration = "ServerMode", tag = "8")]
+    pub mode: i32,
+    /// Why the server is not in read-write mode
+    #[prost(string, tag = "9")]
+    pub mode_reason: ::prost::alloc::string::String,
+    /// Messages buffered for each subscription, waiting for its subscriber
+    #[prost(btree_map = "uint32, uint64", tag = "10")]
+    pub subscription_lag: ::prost::alloc::collections::BTreeMap<u32, u64>,
+}
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SlowlogEntry {
+    /// Increases by one for every recorded command
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    /// When the command started, in milliseconds since the Unix epoch
+    #[prost(uint64, tag = "2")]
+    pub timestamp_ms: u64,
+    #[prost(string, tag = "3")]
+    pub command: ::prost::alloc::string::String,
+    /// Table or topic the command worked on, if any
+    #[prost(string, tag = "4")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(uint32, tag = "5")]
+    pub key_count: u32,
+    #[prost(uint64, tag = "6")]
+    pub duration_us: u64,
+    /// Address of the client, empty if unknown
+    #[prost(string, tag = "7")]
+    pub client: ::prost::alloc::string::String,
+}
 #[derive(PartialOrd)]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Subscribe {
     #[prost(string, tag = "1")]
     pub topic: ::prost::alloc::string::String,
+    /// Where to start on a durable topic, other topics only have new messages
+    #[prost(enumeration = "SubscribeFrom", tag = "2")]
+    pub from: i32,
+    /// The first sequence number wanted with `SUBSCRIBE_FROM_OFFSET`
+    #[prost(uint64, tag = "3")]
+    pub offset: u64,
+    /// Join this consumer group of a durable topic, getting a share of its
+    /// messages to acknowledge instead of all of them. `from` only matters for
+    /// the first member, which creates the group
+    #[prost(string, tag = "4")]
+    pub group: ::prost::alloc::string::String,
+    /// Shown in `Pending`, the subscription id when empty
+    #[prost(string, tag = "5")]
+    pub consumer: ::prost::alloc::string::String,
 }
 #[derive(PartialOrd)]
 #[allow(clippy::derive_partial_eq_without_eq)]
@@ -66,6 +253,67 @@ pub struct Publish {
     #[prost(message, repeated, tag = "2")]
     pub data: ::prost::alloc::vec::Vec<Value>,
 }
+/// Subscribe to every topic matching a glob, where `*` matches any run of
+/// characters and `?` exactly one
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Psubscribe {
+    #[prost(string, tag = "1")]
+    pub pattern: ::prost::alloc::string::String,
+}
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Punsubscribe {
+    #[prost(string, tag = "1")]
+    pub pattern: ::prost::alloc::string::String,
+    #[prost(uint32, tag = "2")]
+    pub id: u32,
+}
+/// Acknowledge messages a consumer group delivered, so they are not
+/// redelivered. Answered with the number of messages that were pending
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Ack {
+    #[prost(string, tag = "1")]
+    pub topic: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub group: ::prost::alloc::string::String,
+    #[prost(uint64, repeated, tag = "3")]
+    pub sequences: ::prost::alloc::vec::Vec<u64>,
+}
+/// Messages of a consumer group delivered but not acknowledged yet
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Pending {
+    #[prost(string, tag = "1")]
+    pub topic: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub group: ::prost::alloc::string::String,
+    /// Only those of this consumer when set
+    #[prost(string, tag = "3")]
+    pub consumer: ::prost::alloc::string::String,
+}
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct PendingMessage {
+    #[prost(uint64, tag = "1")]
+    pub sequence: u64,
+    #[prost(string, tag = "2")]
+    pub consumer: ::prost::alloc::string::String,
+    #[prost(uint32, tag = "3")]
+    pub subscription: u32,
+    /// Deliveries so far, including the current one
+    #[prost(uint32, tag = "4")]
+    pub attempts: u32,
+    /// Since the last delivery
+    #[prost(uint64, tag = "5")]
+    pub idle_ms: u64,
+}
 /// Server response
 #[derive(PartialOrd)]
 #[allow(clippy::derive_partial_eq_without_eq)]
@@ -83,15 +331,69 @@ pub struct CommandResponse {
     /// Kvpair
     #[prost(message, repeated, tag = "4")]
     pub pairs: ::prost::alloc::vec::Vec<Kvpair>,
+    /// More chunks of this response follow, unset on the last one
+    #[prost(bool, tag = "5")]
+    pub has_more: bool,
+    /// When rate limited (429), milliseconds to wait before retrying
+    #[prost(uint32, tag = "6")]
+    pub retry_after_ms: u32,
+    /// `request_id` of the request this responds to
+    #[prost(uint64, tag = "7")]
+    pub request_id: u64,
+    /// Machine-readable reason of a failure, `ERROR_CODE_OK` on success
+    #[prost(enumeration = "ErrorCode", tag = "8")]
+    pub error_code: i32,
+    /// What a failure was about, if it concerns a table, key or command
+    #[prost(message, optional, tag = "9")]
+    pub error_detail: ::core::option::Option<ErrorDetail>,
+    /// Entries answering `SlowlogGet`
+    #[prost(message, repeated, tag = "10")]
+    pub slowlog: ::prost::alloc::vec::Vec<SlowlogEntry>,
+    /// Answer to `Info`
+    #[prost(message, optional, tag = "11")]
+    pub info: ::core::option::Option<ServerInfo>,
+    /// Replayed from an earlier request with the same idempotency key
+    #[prost(bool, tag = "12")]
+    pub replayed: bool,
+    /// The topic a message was published to, set on messages delivered to a
+    /// pattern subscription
+    #[prost(string, tag = "13")]
+    pub topic: ::prost::alloc::string::String,
+    /// Position of a message in its topic, also answering `Publish`. Durable
+    /// topics keep counting across restarts, others start over at 1
+    #[prost(uint64, tag = "14")]
+    pub sequence: u64,
+    /// Answer to `Pending`
+    #[prost(message, repeated, tag = "15")]
+    pub pending: ::prost::alloc::vec::Vec<PendingMessage>,
+    /// Messages dropped right before this one because the subscriber fell
+    /// behind, set on a response carrying nothing else
+    #[prost(uint64, tag = "16")]
+    pub gap: u64,
 }
 #[derive(PartialOrd)]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
-pub struct Hget {
+pub struct ErrorDetail {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
+    /// The key as text, non UTF-8 bytes escaped
     #[prost(string, tag = "2")]
     pub key: ::prost::alloc::string::String,
+    #[prost(string, tag = "3")]
+    pub command: ::prost::alloc::string::String,
+    /// Type a value failed to convert to
+    #[prost(string, tag = "4")]
+    pub expected_type: ::prost::alloc::string::String,
+}
+#[derive(PartialOrd)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hget {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub key: ::prost::bytes::Bytes,
 }
 #[derive(PartialOrd)]
 #[allow(clippy::derive_partial_eq_without_eq)]
@@ -106,8 +408,8 @@ pub struct Hgetall {
 pub struct Hmget {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, repeated, tag = "2")]
-    pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(bytes = "bytes", repeated, tag = "2")]
+    pub keys: ::prost::alloc::vec::Vec<::prost::bytes::Bytes>,
 }
 #[derive(PartialOrd)]
 #[allow(clippy::derive_partial_eq_without_eq)]
@@ -138,8 +440,8 @@ pub mod value {
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Kvpair {
-    #[prost(string, tag = "1")]
-    pub key: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "1")]
+    pub key: ::prost::bytes::Bytes,
     #[prost(message, optional, tag = "2")]
     pub value: ::core::option::Option<Value>,
 }
@@ -167,8 +469,8 @@ pub struct Hmset {
 pub struct Hdel {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, tag = "2")]
-    pub key: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub key: ::prost::bytes::Bytes,
 }
 #[derive(PartialOrd)]
 #[allow(clippy::derive_partial_eq_without_eq)]
@@ -176,8 +478,8 @@ pub struct Hdel {
 pub struct Hmdel {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, repeated, tag = "2")]
-    pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(bytes = "bytes", repeated, tag = "2")]
+    pub keys: ::prost::alloc::vec::Vec<::prost::bytes::Bytes>,
 }
 #[derive(PartialOrd)]
 #[allow(clippy::derive_partial_eq_without_eq)]
@@ -185,8 +487,8 @@ pub struct Hmdel {
 pub struct Hexist {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, tag = "2")]
-    pub key: ::prost::alloc::string::String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub key: ::prost::bytes::Bytes,
 }
 #[derive(PartialOrd)]
 #[allow(clippy::derive_partial_eq_without_eq)]
@@ -194,6 +496,149 @@ pub struct Hexist {
 pub struct Hmexist {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, repeated, tag = "2")]
-    pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(bytes = "bytes", repeated, tag = "2")]
+    pub keys: ::prost::alloc::vec::Vec<::prost::bytes::Bytes>,
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum ServerMode {
+    ReadWrite = 0,
+    /// Writes are rejected, reads and pubsub keep working
+    ReadOnly = 1,
+    /// All storage commands are rejected so clients move elsewhere, pubsub
+    /// keeps working
+    MaintenanceDrain = 2,
+}
+impl ServerMode {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            ServerMode::ReadWrite => "SERVER_MODE_READ_WRITE",
+            ServerMode::ReadOnly => "SERVER_MODE_READ_ONLY",
+            ServerMode::MaintenanceDrain => "SERVER_MODE_MAINTENANCE_DRAIN",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "SERVER_MODE_READ_WRITE" => Some(Self::ReadWrite),
+            "SERVER_MODE_READ_ONLY" => Some(Self::ReadOnly),
+            "SERVER_MODE_MAINTENANCE_DRAIN" => Some(Self::MaintenanceDrain),
+            _ => None,
+        }
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum SubscribeFrom {
+    /// Only messages published from now on
+    Latest = 0,
+    /// The earliest retained message
+    Earliest = 1,
+    Offset = 2,
+}
+impl SubscribeFrom {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            SubscribeFrom::Latest => "SUBSCRIBE_FROM_LATEST",
+            SubscribeFrom::Earliest => "SUBSCRIBE_FROM_EARLIEST",
+            SubscribeFrom::Offset => "SUBSCRIBE_FROM_OFFSET",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "SUBSCRIBE_FROM_LATEST" => Some(Self::Latest),
+            "SUBSCRIBE_FROM_EARLIEST" => Some(Self::Earliest),
+            "SUBSCRIBE_FROM_OFFSET" => Some(Self::Offset),
+            _ => None,
+        }
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum ErrorCode {
+    Ok = 0,
+    /// A failure the server did not classify
+    Unknown = 1,
+    NotFound = 2,
+    InvalidCommand = 3,
+    TypeMismatch = 4,
+    Storage = 5,
+    Encode = 6,
+    Decode = 7,
+    Frame = 8,
+    Certificate = 9,
+    Io = 10,
+    Internal = 11,
+    Connection = 12,
+    Unauthenticated = 13,
+    PermissionDenied = 14,
+    RateLimited = 15,
+    Config = 16,
+    /// A namespace would hold more keys than allowed
+    QuotaExceeded = 17,
+    /// Rejected by the current server mode
+    Unavailable = 18,
+}
+impl ErrorCode {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            ErrorCode::Ok => "ERROR_CODE_OK",
+            ErrorCode::Unknown => "ERROR_CODE_UNKNOWN",
+            ErrorCode::NotFound => "ERROR_CODE_NOT_FOUND",
+            ErrorCode::InvalidCommand => "ERROR_CODE_INVALID_COMMAND",
+            ErrorCode::TypeMismatch => "ERROR_CODE_TYPE_MISMATCH",
+            ErrorCode::Storage => "ERROR_CODE_STORAGE",
+            ErrorCode::Encode => "ERROR_CODE_ENCODE",
+            ErrorCode::Decode => "ERROR_CODE_DECODE",
+            ErrorCode::Frame => "ERROR_CODE_FRAME",
+            ErrorCode::Certificate => "ERROR_CODE_CERTIFICATE",
+            ErrorCode::Io => "ERROR_CODE_IO",
+            ErrorCode::Internal => "ERROR_CODE_INTERNAL",
+            ErrorCode::Connection => "ERROR_CODE_CONNECTION",
+            ErrorCode::Unauthenticated => "ERROR_CODE_UNAUTHENTICATED",
+            ErrorCode::PermissionDenied => "ERROR_CODE_PERMISSION_DENIED",
+            ErrorCode::RateLimited => "ERROR_CODE_RATE_LIMITED",
+            ErrorCode::Config => "ERROR_CODE_CONFIG",
+            ErrorCode::QuotaExceeded => "ERROR_CODE_QUOTA_EXCEEDED",
+            ErrorCode::Unavailable => "ERROR_CODE_UNAVAILABLE",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "ERROR_CODE_OK" => Some(Self::Ok),
+            "ERROR_CODE_UNKNOWN" => Some(Self::Unknown),
+            "ERROR_CODE_NOT_FOUND" => Some(Self::NotFound),
+            "ERROR_CODE_INVALID_COMMAND" => Some(Self::InvalidCommand),
+            "ERROR_CODE_TYPE_MISMATCH" => Some(Self::TypeMismatch),
+            "ERROR_CODE_STORAGE" => Some(Self::Storage),
+            "ERROR_CODE_ENCODE" => Some(Self::Encode),
+            "ERROR_CODE_DECODE" => Some(Self::Decode),
+            "ERROR_CODE_FRAME" => Some(Self::Frame),
+            "ERROR_CODE_CERTIFICATE" => Some(Self::Certificate),
+            "ERROR_CODE_IO" => Some(Self::Io),
+            "ERROR_CODE_INTERNAL" => Some(Self::Internal),
+            "ERROR_CODE_CONNECTION" => Some(Self::Connection),
+            "ERROR_CODE_UNAUTHENTICATED" => Some(Self::Unauthenticated),
+            "ERROR_CODE_PERMISSION_DENIED" => Some(Self::PermissionDenied),
+            "ERROR_CODE_RATE_LIMITED" => Some(Self::RateLimited),
+            "ERROR_CODE_CONFIG" => Some(Self::Config),
+            "ERROR_CODE_QUOTA_EXCEEDED" => Some(Self::QuotaExceeded),
+            "ERROR_CODE_UNAVAILABLE" => Some(Self::Unavailable),
+            _ => None,
+        }
+    }
 }
@@ -7,16 +7,16 @@ use bytes::Bytes;
 use futures::stream;
 use http::StatusCode;
 use prost::Message;
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 impl CommandRequest {
-    pub fn dispatch(self, store: &impl Storage) -> CommandResponse {
+    pub async fn dispatch(self, store: &impl AsyncStorage) -> CommandResponse {
         match self.request_data {
             Some(request_data) => {
                 if request_data.is_streaming() {
                     KvError::InvalidCommand("Not command".into()).into()
                 } else {
-                    service::CommandService::execute(request_data, store)
+                    service::CommandService::execute(request_data, store).await
                 }
             }
             None => KvError::InvalidCommand("Request has no data".into()).into(),
@@ -40,53 +40,68 @@ impl CommandRequest {
         }
     }
 
-    pub fn new_hget(table: impl Into<String>, key: impl Into<String>) -> Self {
+    pub fn new_hget(table: impl Into<String>, key: impl AsRef<[u8]>) -> Self {
         Self {
+            request_id: 0,
+            idempotency_key: String::new(),
             request_data: Some(RequestData::Hget(Hget {
                 table: table.into(),
-                key: key.into(),
+                key: Bytes::copy_from_slice(key.as_ref()),
             })),
         }
     }
 
-    pub fn new_hset(table: impl Into<String>, key: impl Into<String>, value: Value) -> Self {
+    pub fn new_hset(table: impl Into<String>, key: impl AsRef<[u8]>, value: Value) -> Self {
         Self {
+            request_id: 0,
+            idempotency_key: String::new(),
             request_data: Some(RequestData::Hset(Hset {
                 table: table.into(),
-                pair: Some(Kvpair::new(key, value)),
+                pair: Some(Kvpair::new(Bytes::copy_from_slice(key.as_ref()), value)),
             })),
         }
     }
 
-    pub fn new_hdel(table: impl Into<String>, key: impl Into<String>) -> Self {
+    pub fn new_hdel(table: impl Into<String>, key: impl AsRef<[u8]>) -> Self {
         Self {
+            request_id: 0,
+            idempotency_key: String::new(),
             request_data: Some(RequestData::Hdel(Hdel {
                 table: table.into(),
-                key: key.into(),
+                key: Bytes::copy_from_slice(key.as_ref()),
             })),
         }
     }
 
-    pub fn new_hexist(table: impl Into<String>, key: impl Into<String>) -> Self {
+    pub fn new_hexist(table: impl Into<String>, key: impl AsRef<[u8]>) -> Self {
         Self {
+            request_id: 0,
+            idempotency_key: String::new(),
             request_data: Some(RequestData::Hexist(Hexist {
                 table: table.into(),
-                key: key.into(),
+                key: Bytes::copy_from_slice(key.as_ref()),
             })),
         }
     }
 
-    pub fn new_hmget(table: impl Into<String>, keys: Vec<String>) -> Self {
+    pub fn new_hmget(
+        table: impl Into<String>,
+        keys: impl IntoIterator<Item = impl Into<Bytes>>,
+    ) -> Self {
         Self {
+            request_id: 0,
+            idempotency_key: String::new(),
             request_data: Some(RequestData::Hmget(Hmget {
                 table: table.into(),
-                keys,
+                keys: keys.into_iter().map(Into::into).collect(),
             })),
         }
     }
 
     pub fn new_hgetall(table: impl Into<String>) -> Self {
         Self {
+            request_id: 0,
+            idempotency_key: String::new(),
             request_data: Some(RequestData::Hgetall(Hgetall {
                 table: table.into(),
             })),
@@ -95,6 +110,8 @@ impl CommandRequest {
 
     pub fn new_hmset(table: impl Into<String>, pairs: Vec<Kvpair>) -> Self {
         Self {
+            request_id: 0,
+            idempotency_key: String::new(),
             request_data: Some(RequestData::Hmset(Hmset {
                 table: table.into(),
                 pairs,
@@ -102,34 +119,76 @@ impl CommandRequest {
         }
     }
 
-    pub fn new_hmdel(table: impl Into<String>, keys: Vec<String>) -> Self {
+    pub fn new_hmdel(
+        table: impl Into<String>,
+        keys: impl IntoIterator<Item = impl Into<Bytes>>,
+    ) -> Self {
         Self {
+            request_id: 0,
+            idempotency_key: String::new(),
             request_data: Some(RequestData::Hmdel(Hmdel {
                 table: table.into(),
-                keys,
+                keys: keys.into_iter().map(Into::into).collect(),
             })),
         }
     }
 
-    pub fn new_hmexist(table: impl Into<String>, keys: Vec<String>) -> Self {
+    pub fn new_hmexist(
+        table: impl Into<String>,
+        keys: impl IntoIterator<Item = impl Into<Bytes>>,
+    ) -> Self {
         Self {
+            request_id: 0,
+            idempotency_key: String::new(),
             request_data: Some(RequestData::Hmexist(Hmexist {
                 table: table.into(),
-                keys,
+                keys: keys.into_iter().map(Into::into).collect(),
             })),
         }
     }
 
     pub fn new_subscribe(topic: impl Into<String>) -> Self {
         Self {
+            request_id: 0,
+            idempotency_key: String::new(),
             request_data: Some(RequestData::Subscribe(Subscribe {
                 topic: topic.into(),
+                ..Default::default()
+            })),
+        }
+    }
+
+    /// Subscribe to a durable topic from the earliest retained message.
+    pub fn new_subscribe_earliest(topic: impl Into<String>) -> Self {
+        Self {
+            request_id: 0,
+            idempotency_key: String::new(),
+            request_data: Some(RequestData::Subscribe(Subscribe {
+                topic: topic.into(),
+                from: SubscribeFrom::Earliest as i32,
+                ..Default::default()
+            })),
+        }
+    }
+
+    /// Subscribe to a durable topic from sequence number `offset`.
+    pub fn new_subscribe_at(topic: impl Into<String>, offset: u64) -> Self {
+        Self {
+            request_id: 0,
+            idempotency_key: String::new(),
+            request_data: Some(RequestData::Subscribe(Subscribe {
+                topic: topic.into(),
+                from: SubscribeFrom::Offset as i32,
+                offset,
+                ..Default::default()
             })),
         }
     }
 
     pub fn new_publish(topic: impl Into<String>, data: Vec<Value>) -> Self {
         Self {
+            request_id: 0,
+            idempotency_key: String::new(),
             request_data: Some(RequestData::Publish(Publish {
                 topic: topic.into(),
                 data,
@@ -137,8 +196,175 @@ impl CommandRequest {
         }
     }
 
+    pub fn new_custom(name: impl Into<String>, args: Vec<Value>) -> Self {
+        Self {
+            request_id: 0,
+            idempotency_key: String::new(),
+            request_data: Some(RequestData::Custom(Custom {
+                name: name.into(),
+                args,
+            })),
+        }
+    }
+
+    /// Have retries of this request with the same `key` replay its response.
+    pub fn with_idempotency_key(mut self, key: impl Into<String>) -> Self {
+        self.idempotency_key = key.into();
+        self
+    }
+
+    pub fn new_slowlog_get(count: u32) -> Self {
+        Self {
+            request_id: 0,
+            idempotency_key: String::new(),
+            request_data: Some(RequestData::SlowlogGet(SlowlogGet { count })),
+        }
+    }
+
+    pub fn new_slowlog_reset() -> Self {
+        Self {
+            request_id: 0,
+            idempotency_key: String::new(),
+            request_data: Some(RequestData::SlowlogReset(SlowlogReset {})),
+        }
+    }
+
+    pub fn new_info() -> Self {
+        Self {
+            request_id: 0,
+            idempotency_key: String::new(),
+            request_data: Some(RequestData::Info(Info {})),
+        }
+    }
+
+    pub fn new_select_namespace(name: impl Into<String>) -> Self {
+        Self {
+            request_id: 0,
+            idempotency_key: String::new(),
+            request_data: Some(RequestData::SelectNamespace(SelectNamespace {
+                name: name.into(),
+            })),
+        }
+    }
+
+    pub fn new_set_mode(mode: ServerMode, reason: impl Into<String>) -> Self {
+        Self {
+            request_id: 0,
+            idempotency_key: String::new(),
+            request_data: Some(RequestData::SetMode(SetMode {
+                mode: mode as i32,
+                reason: reason.into(),
+            })),
+        }
+    }
+
+    pub fn new_list_namespaces() -> Self {
+        Self {
+            request_id: 0,
+            idempotency_key: String::new(),
+            request_data: Some(RequestData::ListNamespaces(ListNamespaces {})),
+        }
+    }
+
+    pub fn new_auth_password(username: impl Into<String>, password: impl Into<String>) -> Self {
+        Self {
+            request_id: 0,
+            idempotency_key: String::new(),
+            request_data: Some(RequestData::Auth(Auth {
+                credential: Some(auth::Credential::Password(Password {
+                    username: username.into(),
+                    password: password.into(),
+                })),
+            })),
+        }
+    }
+
+    pub fn new_auth_token(token: impl Into<String>) -> Self {
+        Self {
+            request_id: 0,
+            idempotency_key: String::new(),
+            request_data: Some(RequestData::Auth(Auth {
+                credential: Some(auth::Credential::Token(token.into())),
+            })),
+        }
+    }
+
+    pub fn new_psubscribe(pattern: impl Into<String>) -> Self {
+        Self {
+            request_id: 0,
+            idempotency_key: String::new(),
+            request_data: Some(RequestData::Psubscribe(Psubscribe {
+                pattern: pattern.into(),
+            })),
+        }
+    }
+
+    pub fn new_punsubscribe(pattern: impl Into<String>, id: u32) -> Self {
+        Self {
+            request_id: 0,
+            idempotency_key: String::new(),
+            request_data: Some(RequestData::Punsubscribe(Punsubscribe {
+                pattern: pattern.into(),
+                id,
+            })),
+        }
+    }
+
+    /// Join consumer `group` of a durable topic as `consumer`.
+    pub fn new_subscribe_group(
+        topic: impl Into<String>,
+        group: impl Into<String>,
+        consumer: impl Into<String>,
+    ) -> Self {
+        Self {
+            request_id: 0,
+            idempotency_key: String::new(),
+            request_data: Some(RequestData::Subscribe(Subscribe {
+                topic: topic.into(),
+                group: group.into(),
+                consumer: consumer.into(),
+                ..Default::default()
+            })),
+        }
+    }
+
+    pub fn new_ack(
+        topic: impl Into<String>,
+        group: impl Into<String>,
+        sequences: Vec<u64>,
+    ) -> Self {
+        Self {
+            request_id: 0,
+            idempotency_key: String::new(),
+            request_data: Some(RequestData::Ack(Ack {
+                topic: topic.into(),
+                group: group.into(),
+                sequences,
+            })),
+        }
+    }
+
+    /// Pending messages of `group`, of all consumers when `consumer` is empty.
+    pub fn new_pending(
+        topic: impl Into<String>,
+        group: impl Into<String>,
+        consumer: impl Into<String>,
+    ) -> Self {
+        Self {
+            request_id: 0,
+            idempotency_key: String::new(),
+            request_data: Some(RequestData::Pending(Pending {
+                topic: topic.into(),
+                group: group.into(),
+                consumer: consumer.into(),
+            })),
+        }
+    }
+
     pub fn new_unsubscribe(topic: impl Into<String>, id: u32) -> Self {
         Self {
+            request_id: 0,
+            idempotency_key: String::new(),
             request_data: Some(RequestData::Unsubscribe(Unsubscribe {
                 topic: topic.into(),
                 id,
@@ -148,7 +374,7 @@ impl CommandRequest {
 }
 
 impl Kvpair {
-    pub fn new(key: impl Into<String>, value: Value) -> Self {
+    pub fn new(key: impl Into<Bytes>, value: Value) -> Self {
         Self {
             key: key.into(),
             value: Some(value),
@@ -219,6 +445,12 @@ impl From<(String, Value)> for Kvpair {
     }
 }
 
+impl From<(Bytes, Value)> for Kvpair {
+    fn from(t: (Bytes, Value)) -> Self {
+        Self::new(t.0, t.1)
+    }
+}
+
 impl From<Value> for CommandResponse {
     fn from(v: Value) -> Self {
         Self {
@@ -241,12 +473,7 @@ impl From<Vec<Value>> for CommandResponse {
 
 impl From<Arc<CommandResponse>> for CommandResponse {
     fn from(a: Arc<CommandResponse>) -> Self {
-        Self {
-            status: a.status,
-            message: a.message.clone(),
-            values: a.values.clone(),
-            pairs: a.pairs.clone(),
-        }
+        Arc::unwrap_or_clone(a)
     }
 }
 
@@ -305,23 +532,95 @@ impl From<Vec<Kvpair>> for CommandResponse {
     }
 }
 
+impl From<Vec<SlowlogEntry>> for CommandResponse {
+    fn from(v: Vec<SlowlogEntry>) -> Self {
+        Self {
+            status: StatusCode::OK.as_u16() as u32,
+            slowlog: v,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<Vec<PendingMessage>> for CommandResponse {
+    fn from(v: Vec<PendingMessage>) -> Self {
+        Self {
+            status: StatusCode::OK.as_u16() as u32,
+            pending: v,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<ServerInfo> for CommandResponse {
+    fn from(info: ServerInfo) -> Self {
+        Self {
+            status: StatusCode::OK.as_u16() as u32,
+            info: Some(info),
+            ..Default::default()
+        }
+    }
+}
+
 impl From<KvError> for CommandResponse {
     fn from(e: KvError) -> Self {
+        if let KvError::Remote(e) = e {
+            return (*e).into();
+        }
         let mut result = Self {
-            status: StatusCode::INTERNAL_SERVER_ERROR.as_u16() as u32,
+            status: e.status().as_u16() as u32,
             message: e.to_string(),
+            error_code: e.code() as i32,
+            error_detail: e.detail(),
             ..Default::default()
         };
-        match e {
-            KvError::NotFound(_, _) => result.status = StatusCode::NOT_FOUND.as_u16() as u32,
-            KvError::InvalidCommand(_) => result.status = StatusCode::BAD_REQUEST.as_u16() as u32,
-            _ => {}
+        if let KvError::RateLimited(_, retry_after_ms) = e {
+            result.retry_after_ms = retry_after_ms;
         }
         result
     }
 }
 
+impl From<ResponseError> for CommandResponse {
+    fn from(e: ResponseError) -> Self {
+        Self {
+            status: e.status,
+            message: e.message,
+            error_code: e.code as i32,
+            error_detail: e.detail,
+            retry_after_ms: e.retry_after.map_or(0, |d| d.as_millis() as u32),
+            ..Default::default()
+        }
+    }
+}
+
 impl CommandResponse {
+    /// The failure this response reports, if any. Responses from servers that
+    /// do not send error codes are classified by status alone.
+    pub fn error(&self) -> Option<ResponseError> {
+        let code = match self.error_code() {
+            ErrorCode::Ok if self.status < 400 => return None,
+            ErrorCode::Ok => ErrorCode::Unknown,
+            code => code,
+        };
+        Some(ResponseError {
+            status: self.status,
+            code,
+            message: self.message.clone(),
+            detail: self.error_detail.clone(),
+            retry_after: (self.retry_after_ms > 0)
+                .then(|| Duration::from_millis(self.retry_after_ms as u64)),
+        })
+    }
+
+    /// `Err` with the typed failure if the response reports one.
+    pub fn into_result(self) -> Result<Self, KvError> {
+        match self.error() {
+            Some(e) => Err(e.into()),
+            None => Ok(self),
+        }
+    }
+
     pub fn ok() -> Self {
         let mut result = CommandResponse::default();
         result.status = StatusCode::OK.as_u16() as u32;
@@ -331,6 +630,13 @@ impl CommandResponse {
     pub fn unsubscribe_ack() -> Self {
         CommandResponse::default()
     }
+
+    /// Tells a subscriber `n` messages were dropped at this point.
+    pub fn gap(n: u64) -> Self {
+        let mut result = CommandResponse::ok();
+        result.gap = n;
+        result
+    }
 }
 
 impl From<KvError> for Value {
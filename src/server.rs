@@ -1,15 +1,37 @@
-use anyhow::{Error, Result};
-use kv::{MemTable, ServerStream, Service, TlsServer, YamuxCtrl};
+use anyhow::{anyhow, Error, Result};
+use kv::{
+    peer_principal, serve_metrics, topic::PubSub, verify_audit_log, AccessControl, AuditLog,
+    Authenticator, Credentials, Idempotency, MemTable, Namespaces, Quota, RateLimiter, Retention,
+    ServerMode, ServerStream, Service, ServiceInner, Session, SledLog, Slowlog, TlsServer,
+    YamuxCtrl, DEFAULT_CAPACITY,
+};
 use s2n_quic::Server;
 use s2n_quic_rustls::server::Builder;
-use std::{future::Future, str::FromStr};
-use tokio::{net::TcpListener, signal};
+use std::{env, future::Future, path::PathBuf, process, str::FromStr, sync::Arc, time::Duration};
+use tokio::{
+    net::TcpListener,
+    signal::{self, unix},
+};
 use tokio_util::compat::FuturesAsyncReadCompatExt;
-use tracing::{error, info, span};
+use tracing::{error, info, span, warn};
 use tracing_subscriber::{prelude::*, EnvFilter};
 
+/// Start a new audit file past this size
+const AUDIT_FILE_SIZE: u64 = 64 << 20;
+
 #[tokio::main]
 async fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let [cmd, sub, paths @ ..] = &args[..] {
+        if cmd == "audit" && sub == "verify" && !paths.is_empty() {
+            process::exit(audit_verify(paths));
+        }
+    }
+    if !args.is_empty() {
+        eprintln!("usage: kvs [audit verify <file or dir>...]");
+        process::exit(2);
+    }
+
     let tracer = opentelemetry_jaeger::new_pipeline()
         .with_service_name("kv-server")
         .install_simple()
@@ -29,8 +51,190 @@ async fn main() {
     run(signal::ctrl_c()).await
 }
 
-async fn run_quic_server() -> Result<(), Error> {
-    let service = Service::new(MemTable::new());
+fn audit_verify(paths: &[String]) -> i32 {
+    let paths: Vec<PathBuf> = paths.iter().map(PathBuf::from).collect();
+    match verify_audit_log(&paths) {
+        Ok(summary) => {
+            println!(
+                "ok: {} records, last hash {}",
+                summary.records, summary.last_hash
+            );
+            0
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+/// Expose Prometheus metrics on `KVS_METRICS_ADDR` if set.
+async fn serve_metrics_if_enabled(service: &Service) -> Result<()> {
+    if let Ok(addr) = env::var("KVS_METRICS_ADDR") {
+        let listener = TcpListener::bind(addr).await?;
+        let service = service.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_metrics(listener, service).await {
+                error!(cause = %e, "metrics listener failed");
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Require clients to authenticate when `KVS_CREDENTIALS` names a credential
+/// file, and enforce the rules in `KVS_ACL` (reloaded on SIGHUP) if set.
+/// `KVS_RATE_LIMIT` and `KVS_BYTE_RATE_LIMIT` limit the requests and request
+/// bytes each client sends per second, as in `100:200` for a burst of 200,
+/// and `KVS_MAX_SUBSCRIPTIONS` the subscriptions a connection holds.
+/// `KVS_SLOWLOG_MS` overrides the slowlog threshold, and `KVS_AUDIT_DIR` keeps
+/// an audit trail of the changes made. `KVS_NAMESPACE_QUOTAS` limits the keys
+/// of namespaces, as in `acme=1000,globex=500`, and `KVS_IDEMPOTENCY_SECS`
+/// enables idempotency keys remembered that long. `KVS_DURABLE_TOPICS` makes
+/// topics durable, as in `orders.*=1000:3600` to keep the last 1000 messages
+/// of at most an hour, logged in `KVS_TOPIC_LOG_DIR` if set.
+/// `KVS_SUBSCRIBER_BUFFER` sizes subscriber buffers and says what happens
+/// when one is full, as in `256:drop-oldest`, the default policy. A
+/// `block`ing subscriber holds publishers back for 5 seconds at most before
+/// it is disconnected. SIGUSR1 makes the server
+/// read-only, SIGUSR2 read-write again. The credentials are returned too, to
+/// bind client certificates to their namespace.
+fn new_service() -> Result<(Service, Option<Arc<Credentials>>)> {
+    let mut inner = ServiceInner::new(MemTable::new());
+    let mut credentials = None;
+    if let Ok(path) = env::var("KVS_CREDENTIALS") {
+        info!("authentication enabled with {}", path);
+        let loaded = Arc::new(Credentials::load(path)?);
+        inner = inner.layer(Authenticator::from(Arc::clone(&loaded)));
+        credentials = Some(loaded);
+    }
+    // after the authenticator, so that quotas are per principal
+    let mut limiter = None;
+    if let Ok(spec) = env::var("KVS_RATE_LIMIT") {
+        limiter = Some(RateLimiter::new().requests(parse_quota(&spec)?));
+    }
+    if let Ok(spec) = env::var("KVS_BYTE_RATE_LIMIT") {
+        limiter = Some(limiter.unwrap_or_default().bytes(parse_quota(&spec)?));
+    }
+    if let Ok(max) = env::var("KVS_MAX_SUBSCRIPTIONS") {
+        limiter = Some(limiter.unwrap_or_default().max_subscriptions(max.parse()?));
+    }
+    if let Some(limiter) = limiter {
+        inner = inner.layer(limiter);
+    }
+    if let Ok(path) = env::var("KVS_ACL") {
+        info!("access control enabled with {}", path);
+        let access = AccessControl::load(path)?;
+        reload_on_hangup(access.clone())?;
+        inner = inner.layer(access);
+    }
+    if let Ok(secs) = env::var("KVS_IDEMPOTENCY_SECS") {
+        let window = Duration::from_secs(secs.parse()?);
+        inner = inner.layer(Idempotency::new(window));
+    }
+    if let Ok(dir) = env::var("KVS_AUDIT_DIR") {
+        info!("audit trail kept in {}", dir);
+        inner = inner.layer(AuditLog::open(dir, AUDIT_FILE_SIZE)?);
+    }
+    if let Ok(ms) = env::var("KVS_SLOWLOG_MS") {
+        let threshold = Duration::from_millis(ms.parse()?);
+        inner = inner.slowlog(Slowlog::new(threshold, DEFAULT_CAPACITY));
+    }
+    if let Ok(quotas) = env::var("KVS_NAMESPACE_QUOTAS") {
+        let mut namespaces = Namespaces::new();
+        for quota in quotas.split(',') {
+            let (name, max_keys) = quota
+                .split_once('=')
+                .ok_or_else(|| anyhow!("expect `<namespace>=<max keys>`, got `{}`", quota))?;
+            namespaces = namespaces.quota(name.trim(), max_keys.trim().parse()?);
+        }
+        inner = inner.namespaces(namespaces);
+    }
+    let mut pubsub = PubSub::new();
+    if let Ok(topics) = env::var("KVS_DURABLE_TOPICS") {
+        if let Ok(dir) = env::var("KVS_TOPIC_LOG_DIR") {
+            info!("durable topics logged in {}", dir);
+            pubsub = pubsub.log(SledLog::new(dir)?);
+        }
+        for topic in topics.split(',') {
+            let (pattern, retention) = parse_retention(topic)?;
+            pubsub = pubsub.durable(pattern, retention);
+        }
+    }
+    if let Ok(buffer) = env::var("KVS_SUBSCRIBER_BUFFER") {
+        let (capacity, overflow) = buffer
+            .split_once(':')
+            .ok_or_else(|| anyhow!("expect `<capacity>:<overflow policy>`, got `{}`", buffer))?;
+        pubsub = pubsub.buffer(capacity.trim().parse()?, overflow.trim().parse()?);
+    }
+    inner = inner.pubsub(pubsub);
+    let service: Service = inner.into();
+    switch_mode_on_signals(service.clone())?;
+    Ok((service, credentials))
+}
+
+/// Parse `<per second>[:burst]`.
+fn parse_quota(spec: &str) -> Result<Quota> {
+    let (rate, burst) = match spec.split_once(':') {
+        Some((rate, burst)) => (rate, Some(burst)),
+        None => (spec, None),
+    };
+    let mut quota = Quota::per_second(rate.trim().parse()?);
+    if let Some(burst) = burst {
+        quota = quota.with_burst(burst.trim().parse()?);
+    }
+    Ok(quota)
+}
+
+/// Parse `<pattern>=[max messages]:[max age secs]`, either limit optional.
+fn parse_retention(spec: &str) -> Result<(&str, Retention)> {
+    let expect = || {
+        anyhow!(
+            "expect `<pattern>=[max messages]:[max age secs]`, got `{}`",
+            spec
+        )
+    };
+    let (pattern, limits) = spec.split_once('=').ok_or_else(expect)?;
+    let (messages, secs) = limits.split_once(':').ok_or_else(expect)?;
+    let mut retention = Retention::new();
+    if !messages.trim().is_empty() {
+        retention = retention.max_messages(messages.trim().parse()?);
+    }
+    if !secs.trim().is_empty() {
+        retention = retention.max_age(Duration::from_secs(secs.trim().parse()?));
+    }
+    Ok((pattern.trim(), retention))
+}
+
+fn reload_on_hangup(access: AccessControl) -> Result<()> {
+    let mut hangup = unix::signal(unix::SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            // errors are logged and the previous rules kept
+            let _ = access.reload();
+        }
+    });
+    Ok(())
+}
+
+fn switch_mode_on_signals(service: Service) -> Result<()> {
+    let mut read_only = unix::signal(unix::SignalKind::user_defined1())?;
+    let mut read_write = unix::signal(unix::SignalKind::user_defined2())?;
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                Some(()) = read_only.recv() => {
+                    service.set_mode(ServerMode::ReadOnly, "set by SIGUSR1")
+                }
+                Some(()) = read_write.recv() => service.set_mode(ServerMode::ReadWrite, ""),
+                else => break,
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn run_quic_server(service: Service) -> Result<(), Error> {
     let addr = "127.0.0.1:5000";
 
     let server_cert = include_str!("../certs/server.crt");
@@ -57,7 +261,12 @@ async fn run_quic_server() -> Result<(), Error> {
                     info!("client {:?} connected", addr);
                     let svc1 = svc.clone();
                     tokio::spawn(async move {
-                        let stream = ServerStream::new(stream, svc1.clone());
+                        let session = remote
+                            .map(Session::with_peer_addr)
+                            .unwrap_or_default()
+                            .with_transport("quic");
+                        let stream =
+                            ServerStream::with_session(stream, svc1.clone(), session.into());
                         let _ = stream.process().await;
                         info!("client {:?} disconnected", remote);
                     });
@@ -68,8 +277,13 @@ async fn run_quic_server() -> Result<(), Error> {
     }
 }
 
-async fn run_tcp_server() -> Result<(), Error> {
-    let service = Service::new(MemTable::new());
+/// Serve TLS over TCP, clients with a certificate signed by the CA being
+/// authenticated as its identity, and bound to its namespace in
+/// `credentials`.
+async fn run_tcp_server(
+    service: Service,
+    credentials: Option<Arc<Credentials>>,
+) -> Result<(), Error> {
     let addr = "127.0.0.1:5000";
 
     let server_cert = include_str!("../certs/server.crt");
@@ -85,14 +299,35 @@ async fn run_tcp_server() -> Result<(), Error> {
 
         let tls = acceptor.clone();
         let svc = service.clone();
+        let credentials = credentials.clone();
         tokio::spawn(async move {
-            let stream = tls.accept(stream).await.unwrap();
+            let stream = match tls.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("TLS handshake with {:?} failed: {}", addr, e);
+                    return;
+                }
+            };
+            // every yamux stream acts as the identity of the client certificate
+            let principal = peer_principal(&stream);
+            info!("client {:?} authenticated as {:?}", addr, principal);
             YamuxCtrl::new_server(stream, None, move |stream| {
                 let svc1 = svc.clone();
+                let session = Session::with_peer_addr(addr).with_transport("tcp");
+                match (&principal, &credentials) {
+                    (Some(principal), Some(credentials)) => {
+                        credentials.bind_certificate(&session, principal.clone())
+                    }
+                    (Some(principal), None) => session.bind_principal(principal.clone()),
+                    _ => {}
+                }
                 async move {
-                    let server = ServerStream::new(stream.compat(), svc1.clone());
-                    server.process().await.unwrap();
-                    info!("client {:?} disconnected", addr);
+                    let server =
+                        ServerStream::with_session(stream.compat(), svc1.clone(), session.into());
+                    match server.process().await {
+                        Ok(()) => info!("client {:?} disconnected", addr),
+                        Err(e) => warn!("client {:?} disconnected: {}", addr, e),
+                    }
                     Ok(())
                 }
             });
@@ -101,8 +336,26 @@ async fn run_tcp_server() -> Result<(), Error> {
 }
 
 async fn run(shutdown: impl Future) {
+    let (service, credentials) = match new_service() {
+        Ok(service) => service,
+        Err(err) => {
+            error!(cause = %err, "failed to start");
+            return;
+        }
+    };
+    if let Err(err) = serve_metrics_if_enabled(&service).await {
+        error!(cause = %err, "failed to serve metrics");
+        return;
+    }
+
+    // QUIC and TCP listen on the same port number, one over UDP
     tokio::select! {
-        res = run_quic_server() => {
+        res = run_quic_server(service.clone()) => {
+            if let Err(err) = res {
+                error!(cause = %err, "failed to accept");
+            }
+        }
+        res = run_tcp_server(service, credentials) => {
             if let Err(err) = res {
                 error!(cause = %err, "failed to accept");
             }
@@ -0,0 +1,464 @@
+use crate::{
+    command_request::RequestData, CommandRequest, KvError, Middleware, Next, StreamingResponse,
+};
+use futures::{future, future::BoxFuture, FutureExt, StreamExt};
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+use tracing::{info, warn};
+
+/// Commands that can be named in a rule.
+const COMMANDS: &[&str] = &[
+    "hget",
+    "hgetall",
+    "hmget",
+    "hset",
+    "hmset",
+    "hdel",
+    "hmdel",
+    "hexist",
+    "hmexist",
+    "subscribe",
+    "unsubscribe",
+    "publish",
+    "psubscribe",
+    "punsubscribe",
+    "ack",
+    "pending",
+    "custom",
+    "slowlogget",
+    "slowlogreset",
+    "info",
+    "selectnamespace",
+    "listnamespaces",
+    "setmode",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Effect {
+    Allow,
+    Deny,
+}
+
+/// One line of an ACL: whether `principals` may run `commands` on the tables,
+/// topics, custom command names or namespaces matching `resources`.
+/// Principals and resources are globs where `*` matches any run of characters
+/// and `?` a single one. Server-wide commands such as `slowlogget` have an
+/// empty resource that only `*` matches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub effect: Effect,
+    pub principals: Vec<String>,
+    pub commands: Vec<String>,
+    pub resources: Vec<String>,
+}
+
+impl Rule {
+    /// An unauthenticated connection has no principal and only matches `*`.
+    fn matches(&self, principal: Option<&str>, command: &str, resource: &str) -> bool {
+        self.principals.iter().any(|p| match principal {
+            Some(principal) => glob_match(p, principal),
+            None => p == "*",
+        }) && self.commands.iter().any(|c| c == "*" || c == command)
+            && self.resources.iter().any(|r| glob_match(r, resource))
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let effect = match self.effect {
+            Effect::Allow => "allow",
+            Effect::Deny => "deny",
+        };
+        write!(
+            f,
+            "{} {} {} {}",
+            effect,
+            self.principals.join(","),
+            self.commands.join(","),
+            self.resources.join(",")
+        )
+    }
+}
+
+/// An ordered list of rules; the first rule matching a request decides, and
+/// a request no rule matches is denied.
+///
+/// The file form has one rule per line, blank lines and `#` comments are
+/// skipped, lists are comma separated and `*` stands for every command:
+///
+/// ```text
+/// # effect principals commands      resources
+/// allow    analytics  hget,hgetall  metrics:*
+/// allow    ops        publish       alerts
+/// deny     *          publish       alerts
+/// allow    *          *             *
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Acl {
+    rules: Vec<Rule>,
+}
+
+impl Acl {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Self { rules }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// Check whether `principal` may run `command` on `resource`.
+    pub fn check(
+        &self,
+        principal: Option<&str>,
+        command: &str,
+        resource: &str,
+    ) -> Result<(), KvError> {
+        let who = principal.unwrap_or("anonymous");
+        match self
+            .rules
+            .iter()
+            .enumerate()
+            .find(|(_, rule)| rule.matches(principal, command, resource))
+        {
+            Some((_, rule)) if rule.effect == Effect::Allow => Ok(()),
+            Some((i, rule)) => Err(KvError::PermissionDenied(format!(
+                "{} {} on {} denied by rule {} `{}`",
+                who,
+                command,
+                resource,
+                i + 1,
+                rule
+            ))),
+            None => Err(KvError::PermissionDenied(format!(
+                "{} {} on {} matches no rule",
+                who, command, resource
+            ))),
+        }
+    }
+}
+
+impl FromStr for Acl {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rules = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let err = |msg: String| KvError::ConfigError(format!("line {}: {}", i + 1, msg));
+
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let [effect, principals, commands, resources] = fields[..] else {
+                return Err(err(
+                    "expect `<allow|deny> <principals> <commands> <resources>`".into(),
+                ));
+            };
+
+            let effect = match effect {
+                "allow" => Effect::Allow,
+                "deny" => Effect::Deny,
+                v => return Err(err(format!("unknown effect `{}`", v))),
+            };
+            let list = |s: &str| s.split(',').map(str::to_owned).collect::<Vec<_>>();
+            let commands = list(&commands.to_lowercase());
+            if let Some(c) = commands
+                .iter()
+                .find(|c| *c != "*" && !COMMANDS.contains(&c.as_str()))
+            {
+                return Err(err(format!("unknown command `{}`", c)));
+            }
+
+            rules.push(Rule {
+                effect,
+                principals: list(principals),
+                commands,
+                resources: list(resources),
+            });
+        }
+        Ok(Self { rules })
+    }
+}
+
+/// Middleware enforcing an `Acl` on every table and topic command, with the
+/// principal taken from the session. Clones share the same rules, so a
+/// handle kept aside can swap them while the server runs.
+#[derive(Debug, Clone)]
+pub struct AccessControl {
+    acl: Arc<RwLock<Acl>>,
+    path: Option<PathBuf>,
+}
+
+impl AccessControl {
+    pub fn new(acl: Acl) -> Self {
+        Self {
+            acl: Arc::new(RwLock::new(acl)),
+            path: None,
+        }
+    }
+
+    /// Load the rules from a file that `reload` will read again.
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, KvError> {
+        let path = path.into();
+        let mut this = Self::new(Acl::load(&path)?);
+        this.path = Some(path);
+        Ok(this)
+    }
+
+    /// Re-read the file given to `load`. The current rules stay in place if
+    /// the file is invalid.
+    pub fn reload(&self) -> Result<(), KvError> {
+        let path = self
+            .path
+            .as_ref()
+            .ok_or_else(|| KvError::ConfigError("acl was not loaded from a file".into()))?;
+        match Acl::load(path) {
+            Ok(acl) => {
+                info!("reloaded {} acl rules from {:?}", acl.rules.len(), path);
+                self.set(acl);
+                Ok(())
+            }
+            Err(e) => {
+                warn!("failed to reload acl from {:?}: {}", path, e);
+                Err(e)
+            }
+        }
+    }
+
+    pub fn set(&self, acl: Acl) {
+        *self.acl.write().unwrap() = acl;
+    }
+}
+
+impl Middleware for AccessControl {
+    fn call<'a>(&'a self, req: CommandRequest, next: Next<'a>) -> BoxFuture<'a, StreamingResponse> {
+        let checked = req.request_data.as_ref().and_then(|data| {
+            // whoever may connect may authenticate
+            if let RequestData::Auth(_) = data {
+                return None;
+            }
+            let resource = data.resource().unwrap_or_default();
+            let principal = next.session().principal();
+            let acl = self.acl.read().unwrap();
+            Some(acl.check(principal.as_deref(), data.name(), resource))
+        });
+
+        match checked {
+            Some(Err(e)) => {
+                let res: StreamingResponse = crate::CommandResponse::from(e).into();
+                async move { res }.boxed()
+            }
+            Some(Ok(())) if matches!(req.request_data, Some(RequestData::Psubscribe(_))) => {
+                // a pattern may match topics the principal is denied, so each
+                // message is checked against the topic it comes from
+                let acl = Arc::clone(&self.acl);
+                let session = Arc::clone(next.session());
+                next.run(req)
+                    .map(move |res| {
+                        let res = res.filter(move |msg| {
+                            let principal = session.principal();
+                            let acl = acl.read().unwrap();
+                            future::ready(
+                                msg.topic.is_empty()
+                                    || acl
+                                        .check(principal.as_deref(), "psubscribe", &msg.topic)
+                                        .is_ok(),
+                            )
+                        });
+                        Box::pin(res) as StreamingResponse
+                    })
+                    .boxed()
+            }
+            _ => next.run(req),
+        }
+    }
+}
+
+/// Match `text` against a glob where `*` matches any run of characters
+/// (including none) and `?` exactly one.
+pub(crate) fn glob_match(pattern: &str, text: &str) -> bool {
+    let (p, t): (Vec<char>, Vec<char>) = (pattern.chars().collect(), text.chars().collect());
+    let (mut pi, mut ti) = (0, 0);
+    // position of the last `*` and the text index it currently stands for
+    let mut star = None;
+
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ti));
+            pi += 1;
+        } else if let Some((sp, st)) = star {
+            // let the last `*` swallow one more character
+            pi = sp + 1;
+            ti = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assert_res_ref_error, MemTable, Service, ServiceInner, Session};
+    use futures::StreamExt;
+    use std::io::Write;
+
+    const RULES: &str = "
+        # analytics only reads metrics
+        allow analytics hget,hgetall metrics:*
+        deny  analytics *            *
+        allow ops       publish      alerts
+        deny  *         publish      alerts
+        allow *         *            *
+    ";
+
+    #[test]
+    fn glob_match_should_work() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("metrics:*", "metrics:cpu"));
+        assert!(glob_match("metrics:*", "metrics:"));
+        assert!(!glob_match("metrics:*", "metric"));
+        assert!(glob_match("a*b*c", "aXXbYYc"));
+        assert!(!glob_match("a*b*c", "aXXbYY"));
+        assert!(glob_match("t?", "t1"));
+        assert!(!glob_match("t?", "t"));
+        assert!(glob_match("表*", "表格"));
+    }
+
+    #[test]
+    fn acl_should_use_first_matching_rule() {
+        let acl: Acl = RULES.parse().unwrap();
+        assert_eq!(acl.rules().len(), 5);
+
+        assert!(acl.check(Some("analytics"), "hget", "metrics:cpu").is_ok());
+        assert!(acl
+            .check(Some("analytics"), "hgetall", "metrics:mem")
+            .is_ok());
+        assert!(acl.check(Some("ops"), "publish", "alerts").is_ok());
+        assert!(acl.check(Some("bob"), "hset", "users").is_ok());
+        assert!(acl.check(None, "hget", "users").is_ok());
+
+        let err = acl
+            .check(Some("analytics"), "hset", "metrics:cpu")
+            .unwrap_err();
+        assert_eq!(
+            err,
+            KvError::PermissionDenied(
+                "analytics hset on metrics:cpu denied by rule 2 `deny analytics * *`".into()
+            )
+        );
+        let err = acl.check(None, "publish", "alerts").unwrap_err();
+        assert!(err.to_string().contains("rule 4"));
+
+        let acl: Acl = "allow ops * *".parse().unwrap();
+        let err = acl.check(Some("bob"), "hget", "t1").unwrap_err();
+        assert!(err.to_string().contains("matches no rule"));
+        assert!(acl.check(None, "hget", "t1").is_err());
+    }
+
+    #[test]
+    fn acl_parse_errors_should_name_the_line() {
+        let err = "allow * *".parse::<Acl>().unwrap_err();
+        assert!(err.to_string().contains("line 1"));
+        let err = "\nmaybe * * *".parse::<Acl>().unwrap_err();
+        assert!(err.to_string().contains("line 2: unknown effect"));
+        let err = "allow * hget,hsett *".parse::<Acl>().unwrap_err();
+        assert!(err.to_string().contains("unknown command `hsett`"));
+    }
+
+    #[tokio::test]
+    async fn access_control_should_reject_with_403() {
+        let access = AccessControl::new(RULES.parse().unwrap());
+        let service: Service = ServiceInner::new(MemTable::new()).layer(access).into();
+        let session = Arc::new(Session::new());
+        session.set_principal("analytics");
+
+        let cmd = CommandRequest::new_hget("metrics:cpu", "k1");
+        let mut res = service.execute(cmd, &session);
+        assert_eq!(res.next().await.unwrap().status, 404);
+
+        let cmd = CommandRequest::new_hset("metrics:cpu", "k1", "v1".into());
+        let mut res = service.execute(cmd, &session);
+        assert_res_ref_error(&res.next().await.unwrap(), 403, "rule 2");
+
+        let cmd = CommandRequest::new_publish("alerts", vec!["fire".into()]);
+        let mut res = service.execute(cmd, &session);
+        assert_res_ref_error(&res.next().await.unwrap(), 403, "publish on alerts");
+    }
+
+    #[tokio::test]
+    async fn access_control_should_check_server_wide_commands() {
+        let rules = "allow ops slowlogget *\nallow * hget *";
+        let access = AccessControl::new(rules.parse().unwrap());
+        let service: Service = ServiceInner::new(MemTable::new()).layer(access).into();
+        let session = Arc::new(Session::new());
+
+        let mut res = service.execute(CommandRequest::new_slowlog_get(0), &session);
+        assert_eq!(res.next().await.unwrap().status, 403);
+
+        session.set_principal("ops");
+        let mut res = service.execute(CommandRequest::new_slowlog_get(0), &session);
+        assert_eq!(res.next().await.unwrap().status, 200);
+    }
+
+    #[tokio::test]
+    async fn access_control_should_reload() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(file, "allow * hget *").unwrap();
+
+        let access = AccessControl::load(file.path()).unwrap();
+        let service: Service = ServiceInner::new(MemTable::new())
+            .layer(access.clone())
+            .into();
+        let session = Arc::default();
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let mut res = service.execute(cmd.clone(), &session);
+        assert_eq!(res.next().await.unwrap().status, 403);
+
+        fs::write(file.path(), "allow * hget,hset *").unwrap();
+        access.reload().unwrap();
+        let mut res = service.execute(cmd.clone(), &session);
+        assert_eq!(res.next().await.unwrap().status, 200);
+
+        // a broken file keeps the rules in place
+        fs::write(file.path(), "allow *").unwrap();
+        assert!(access.reload().is_err());
+        let mut res = service.execute(cmd, &session);
+        assert_eq!(res.next().await.unwrap().status, 200);
+    }
+
+    #[tokio::test]
+    async fn patterns_should_not_receive_denied_topics() {
+        let rules = "deny * subscribe,psubscribe secret*\nallow * * *";
+        let access = AccessControl::new(rules.parse().unwrap());
+        let service: Service = ServiceInner::new(MemTable::new()).layer(access).into();
+        let session = Arc::new(Session::new());
+
+        let mut sub = service.execute(CommandRequest::new_psubscribe("s*"), &session);
+        assert_eq!(sub.next().await.unwrap().status, 200);
+        for topic in ["secret", "secrets.db", "sales"] {
+            let cmd = CommandRequest::new_publish(topic, vec![topic.into()]);
+            let mut res = service.execute(cmd, &session);
+            assert_eq!(res.next().await.unwrap().status, 200);
+        }
+
+        let res = sub.next().await.unwrap();
+        assert_eq!(res.topic, "sales");
+    }
+}
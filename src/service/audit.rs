@@ -0,0 +1,473 @@
+use crate::{
+    command_request::RequestData, CommandRequest, KvError, Middleware, Next, StreamingResponse,
+    Value,
+};
+use bytes::Bytes;
+use futures::{future::BoxFuture, stream, FutureExt, StreamExt};
+use prost::Message;
+use sha2::{Digest, Sha256};
+use std::{
+    fmt::Write as _,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, info};
+
+/// `prev` of the very first record
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Middleware appending every successful `hset`, `hmset`, `hdel` and `hmdel`
+/// to an audit trail in a directory.
+///
+/// Each record is one tab-separated line:
+///
+/// ```text
+/// <seq> <unix ms> <principal> <command> <table> <keys> <old> <new> <prev> <hash>
+/// ```
+///
+/// where the principal and table are percent-encoded (`%`, control
+/// characters and a lone `-`), keys are hex encoded, `old` and `new` are the sha256 of each
+/// encoded value (`-` when there is none), and `hash` is the sha256 of the
+/// line up to `prev` included. Every `prev` is the `hash` of the record
+/// before, so editing, removing or reordering records breaks the chain, see
+/// [`verify`]. Files are named after their first `seq` and a new one is
+/// started once the current one exceeds the size limit.
+///
+/// Files are written by a thread of their own, so the runtime never waits on
+/// the disk. A response is sent once its record is written.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    records: mpsc::UnboundedSender<(String, oneshot::Sender<()>)>,
+}
+
+impl AuditLog {
+    /// Continue the trail in `dir`, or start one if it has none.
+    pub fn open(dir: impl Into<PathBuf>, max_file_size: u64) -> Result<Self, KvError> {
+        let mut writer = Writer::open(dir.into(), max_file_size)?;
+        let (records, mut rx) = mpsc::unbounded_channel::<(String, oneshot::Sender<()>)>();
+        // stops once every `AuditLog` is dropped
+        thread::Builder::new()
+            .name("audit-log".into())
+            .spawn(move || {
+                while let Some((fields, written)) = rx.blocking_recv() {
+                    // the change is already made, all we can do is make noise
+                    if let Err(e) = writer.append(&fields) {
+                        error!("failed to write audit record: {}", e);
+                    }
+                    let _ = written.send(());
+                }
+            })?;
+        Ok(Self { records })
+    }
+
+    async fn record(&self, principal: Option<String>, change: Change, old: &[Value]) {
+        let hashes = |values: &[Value]| {
+            let hashes: Vec<_> = (0..change.keys.len())
+                .map(|i| match values.get(i) {
+                    Some(v) if v.value.is_some() => hex(&Sha256::digest(v.encode_to_vec())),
+                    _ => "-".into(),
+                })
+                .collect();
+            hashes.join(",")
+        };
+        let keys: Vec<_> = change.keys.iter().map(|k| hex(k)).collect();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let fields = format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            timestamp,
+            principal.as_deref().map_or_else(|| "-".into(), escape),
+            change.command,
+            escape(&change.table),
+            keys.join(","),
+            hashes(old),
+            hashes(&change.new),
+        );
+
+        let (written, done) = oneshot::channel();
+        if self.records.send((fields, written)).is_err() || done.await.is_err() {
+            error!("failed to write audit record: the audit log writer is gone");
+        }
+    }
+}
+
+impl Middleware for AuditLog {
+    fn call<'a>(&'a self, req: CommandRequest, next: Next<'a>) -> BoxFuture<'a, StreamingResponse> {
+        // requests arrive scoped, so the table is recorded as stored
+        let Some(change) = Change::new(&req) else {
+            return next.run(req);
+        };
+        let principal = next.session().principal();
+
+        async move {
+            let mut res = next.run(req).await;
+            let Some(first) = res.next().await else {
+                return res;
+            };
+            if first.status == 200 {
+                self.record(principal, change, &first.values).await;
+            }
+            let res: StreamingResponse = Box::pin(stream::once(async move { first }).chain(res));
+            res
+        }
+        .boxed()
+    }
+}
+
+/// What a mutating command is about to change.
+struct Change {
+    command: &'static str,
+    table: String,
+    keys: Vec<Bytes>,
+    new: Vec<Value>,
+}
+
+impl Change {
+    fn new(req: &CommandRequest) -> Option<Self> {
+        let data = req.request_data.as_ref()?;
+        let (table, keys, new) = match data {
+            RequestData::Hset(v) => {
+                let pair = v.pair.clone().unwrap_or_default();
+                (
+                    &v.table,
+                    vec![pair.key],
+                    vec![pair.value.unwrap_or_default()],
+                )
+            }
+            RequestData::Hmset(v) => (
+                &v.table,
+                v.pairs.iter().map(|p| p.key.clone()).collect(),
+                v.pairs
+                    .iter()
+                    .map(|p| p.value.clone().unwrap_or_default())
+                    .collect(),
+            ),
+            RequestData::Hdel(v) => (&v.table, vec![v.key.clone()], vec![]),
+            RequestData::Hmdel(v) => (&v.table, v.keys.clone(), vec![]),
+            _ => return None,
+        };
+        Some(Self {
+            command: data.name(),
+            table: table.clone(),
+            keys,
+            new,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Writer {
+    dir: PathBuf,
+    max_file_size: u64,
+    file: Option<(File, u64)>,
+    seq: u64,
+    last_hash: String,
+}
+
+impl Writer {
+    fn open(dir: PathBuf, max_file_size: u64) -> Result<Self, KvError> {
+        fs::create_dir_all(&dir)?;
+        let mut writer = Self {
+            dir,
+            max_file_size,
+            file: None,
+            seq: 0,
+            last_hash: GENESIS.into(),
+        };
+
+        // pick up the chain where the last file left it
+        if let Some(path) = log_files(&writer.dir)?.pop() {
+            let mut chain = Chain::default();
+            chain.check_file(&path)?;
+            if let Some(seq) = chain.last_seq {
+                writer.seq = seq + 1;
+                writer.last_hash = chain.last_hash;
+            }
+            let file = OpenOptions::new().append(true).open(&path)?;
+            let size = file.metadata()?.len();
+            writer.file = Some((file, size));
+        }
+        Ok(writer)
+    }
+
+    fn append(&mut self, fields: &str) -> Result<(), KvError> {
+        let body = format!("{}\t{}\t{}", self.seq, fields, self.last_hash);
+        let hash = hex(&Sha256::digest(body.as_bytes()));
+        let line = format!("{}\t{}\n", body, hash);
+
+        let (file, size) = match &mut self.file {
+            Some((file, size)) if *size < self.max_file_size => (file, size),
+            _ => {
+                let path = self.dir.join(format!("audit-{:020}.log", self.seq));
+                info!("starting audit log {:?}", path);
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                let (file, size) = self.file.insert((file, 0));
+                (file, size)
+            }
+        };
+        file.write_all(line.as_bytes())?;
+        file.flush()?;
+        *size += line.len() as u64;
+
+        self.seq += 1;
+        self.last_hash = hash;
+        Ok(())
+    }
+}
+
+/// Result of a successful [`verify`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditSummary {
+    pub records: u64,
+    /// Hash of the last record. Records cut off the end of the trail can only
+    /// be noticed by comparing it with one kept elsewhere.
+    pub last_hash: String,
+}
+
+/// Check the hash chain of audit files, or of the whole trail in each
+/// directory given, in order. The first record must start the trail unless
+/// older files were removed on purpose, then the chain is checked from
+/// wherever it starts.
+pub fn verify(paths: &[PathBuf]) -> Result<AuditSummary, KvError> {
+    let mut chain = Chain::default();
+    for path in paths {
+        if path.is_dir() {
+            for file in log_files(path)? {
+                chain.check_file(&file)?;
+            }
+        } else {
+            chain.check_file(path)?;
+        }
+    }
+    Ok(AuditSummary {
+        records: chain.records,
+        last_hash: chain.last_hash,
+    })
+}
+
+#[derive(Debug)]
+struct Chain {
+    records: u64,
+    last_seq: Option<u64>,
+    last_hash: String,
+}
+
+impl Default for Chain {
+    fn default() -> Self {
+        Self {
+            records: 0,
+            last_seq: None,
+            last_hash: GENESIS.into(),
+        }
+    }
+}
+
+impl Chain {
+    fn check_file(&mut self, path: &Path) -> Result<(), KvError> {
+        let file = BufReader::new(File::open(path)?);
+        for (i, line) in file.lines().enumerate() {
+            let err =
+                |msg: &str| KvError::AuditError(format!("{}:{}: {}", path.display(), i + 1, msg));
+            let line = line?;
+            let (body, hash) = line
+                .rsplit_once('\t')
+                .ok_or_else(|| err("malformed record"))?;
+            let (_, prev) = body
+                .rsplit_once('\t')
+                .ok_or_else(|| err("malformed record"))?;
+            let seq: u64 = body
+                .split('\t')
+                .next()
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(|| err("malformed record"))?;
+
+            match self.last_seq {
+                Some(last) if seq != last + 1 => return Err(err("records missing or reordered")),
+                Some(_) if prev != self.last_hash => return Err(err("chain broken")),
+                None if seq == 0 && prev != GENESIS => return Err(err("chain broken")),
+                _ => {}
+            }
+            if hex(&Sha256::digest(body.as_bytes())) != hash {
+                return Err(err("record altered"));
+            }
+
+            self.records += 1;
+            self.last_seq = Some(seq);
+            self.last_hash = hash.into();
+        }
+        Ok(())
+    }
+}
+
+/// The audit files in `dir`, oldest first.
+fn log_files(dir: &Path) -> Result<Vec<PathBuf>, KvError> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        if name.starts_with("audit-") && name.ends_with(".log") {
+            files.push(path);
+        }
+    }
+    // zero-padded sequence numbers sort in order
+    files.sort();
+    Ok(files)
+}
+
+/// Percent-encode `%` and control characters, so a name stays within its
+/// field and line, and a lone `-` so it is not read as "none".
+fn escape(s: &str) -> String {
+    if s == "-" {
+        return "%2d".into();
+    }
+    s.chars().fold(String::new(), |mut out, c| {
+        if c == '%' || c.is_ascii_control() {
+            let _ = write!(out, "%{:02x}", c as u8);
+        } else {
+            out.push(c);
+        }
+        out
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{:02x}", b);
+        s
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Kvpair, MemTable, Service, ServiceInner, Session};
+    use std::sync::Arc;
+
+    fn service(dir: &Path, max_file_size: u64) -> Service {
+        let audit = AuditLog::open(dir, max_file_size).unwrap();
+        ServiceInner::new(MemTable::new()).layer(audit).into()
+    }
+
+    async fn run(service: &Service, cmd: CommandRequest) {
+        let session = Arc::new(Session::new());
+        session.set_principal("alice");
+        let mut res = service.execute(cmd, &session);
+        assert_eq!(res.next().await.unwrap().status, 200);
+    }
+
+    fn lines(dir: &Path) -> Vec<String> {
+        log_files(dir)
+            .unwrap()
+            .iter()
+            .flat_map(|f| {
+                fs::read_to_string(f)
+                    .unwrap()
+                    .lines()
+                    .map(String::from)
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn audit_log_should_record_mutations() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = service(dir.path(), 1 << 20);
+
+        run(&service, CommandRequest::new_hset("t1", "k1", "v1".into())).await;
+        run(&service, CommandRequest::new_hget("t1", "k1")).await;
+        run(&service, CommandRequest::new_hset("t1", "k1", "v2".into())).await;
+        run(&service, CommandRequest::new_hdel("t1", "k1")).await;
+
+        let lines = lines(dir.path());
+        assert_eq!(lines.len(), 3);
+        let fields: Vec<&str> = lines[1].split('\t').collect();
+        assert_eq!(fields[0], "1");
+        assert_eq!(&fields[2..6], ["alice", "hset", "t1", "6b31"]);
+        let v1 = hex(&Sha256::digest(Value::from("v1").encode_to_vec()));
+        assert_eq!(fields[6], v1);
+        let deleted: Vec<&str> = lines[2].split('\t').collect();
+        assert_eq!(deleted[7], "-");
+
+        let summary = verify(&[dir.path().into()]).unwrap();
+        assert_eq!(summary.records, 3);
+    }
+
+    #[tokio::test]
+    async fn audit_log_should_record_namespaced_tables_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = service(dir.path(), 1 << 20);
+        let session = Arc::new(Session::new());
+        session.bind_namespace("acme");
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let mut res = service.execute(cmd, &session);
+        assert_eq!(res.next().await.unwrap().status, 200);
+
+        let lines = lines(dir.path());
+        assert_eq!(lines[0].split('\t').nth(4), Some("acme/t1"));
+    }
+
+    #[tokio::test]
+    async fn audit_log_should_escape_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = service(dir.path(), 1 << 20);
+        let session = Arc::new(Session::new());
+        session.set_principal("-");
+
+        let cmd = CommandRequest::new_hset("a\tb\n100%", "k1", "v1".into());
+        let mut res = service.execute(cmd, &session);
+        assert_eq!(res.next().await.unwrap().status, 200);
+
+        let lines = lines(dir.path());
+        assert_eq!(lines.len(), 1);
+        let fields: Vec<&str> = lines[0].split('\t').collect();
+        assert_eq!(fields.len(), 10);
+        assert_eq!(&fields[2..5], ["%2d", "hset", "a%09b%0a100%25"]);
+        assert_eq!(verify(&[dir.path().into()]).unwrap().records, 1);
+    }
+
+    #[tokio::test]
+    async fn audit_log_should_rotate_and_resume() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = service(dir.path(), 200);
+        for i in 0..5i64 {
+            let pairs = vec![Kvpair::new(format!("k{}", i), i.into())];
+            run(&service, CommandRequest::new_hmset("t1", pairs)).await;
+        }
+        assert!(log_files(dir.path()).unwrap().len() > 1);
+
+        // a restarted server continues the same chain
+        let service = self::service(dir.path(), 200);
+        run(&service, CommandRequest::new_hmdel("t1", ["k1"])).await;
+        assert_eq!(verify(&[dir.path().into()]).unwrap().records, 6);
+    }
+
+    #[tokio::test]
+    async fn verify_should_detect_tampering() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = service(dir.path(), 1 << 20);
+        for i in 0..3i64 {
+            run(&service, CommandRequest::new_hset("t1", "k1", i.into())).await;
+        }
+        let file = log_files(dir.path()).unwrap().pop().unwrap();
+        let original = fs::read_to_string(&file).unwrap();
+
+        let altered = original.replacen("alice", "mallory", 1);
+        fs::write(&file, altered).unwrap();
+        let err = verify(std::slice::from_ref(&file)).unwrap_err();
+        assert!(err.to_string().contains(":1: record altered"));
+
+        let mut lines: Vec<_> = original.lines().collect();
+        lines.remove(1);
+        fs::write(&file, lines.join("\n")).unwrap();
+        let err = verify(&[file]).unwrap_err();
+        assert!(err.to_string().contains(":2: records missing"));
+    }
+}
@@ -0,0 +1,353 @@
+use crate::{
+    auth::Credential, command_request::RequestData, service::namespace, CommandRequest,
+    CommandResponse, KvError, Middleware, Next, Session, StreamingResponse,
+};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use futures::{future::BoxFuture, FutureExt};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, fs, path::Path, str::FromStr, sync::Arc};
+use tokio::task;
+use tracing::info;
+
+/// Users and bearer tokens allowed to connect.
+///
+/// Passwords are stored as argon2 PHC strings and tokens as the hex sha256 of
+/// the token, so the credential file never holds a usable secret. The file
+/// has one entry per line, blank lines and `#` comments are skipped. An
+/// optional namespace binds the connections authenticating with the entry to
+/// it:
+///
+/// ```text
+/// user  <username>  <argon2 hash>               [namespace]
+/// token <principal> <sha256 hex of the token>   [namespace]
+/// ```
+#[derive(Debug, Default)]
+pub struct Credentials {
+    users: HashMap<String, String>,
+    tokens: HashMap<String, String>,
+    namespaces: HashMap<String, String>,
+}
+
+impl Credentials {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    pub fn add_user(&mut self, username: impl Into<String>, password: &str) -> Result<(), KvError> {
+        self.users
+            .insert(username.into(), Self::hash_password(password)?);
+        Ok(())
+    }
+
+    pub fn add_token(&mut self, principal: impl Into<String>, token: &str) {
+        self.tokens
+            .insert(Self::hash_token(token), principal.into());
+    }
+
+    /// Bind the connections of `principal` to `namespace`.
+    pub fn bind_namespace(&mut self, principal: impl Into<String>, namespace: impl Into<String>) {
+        self.namespaces.insert(principal.into(), namespace.into());
+    }
+
+    /// The namespace `principal` is bound to, if any.
+    pub fn namespace(&self, principal: &str) -> Option<&str> {
+        self.namespaces.get(principal).map(String::as_str)
+    }
+
+    /// Bind `session` to `principal`, the identity of its client
+    /// certificate, and to the namespace of that principal if any.
+    pub fn bind_certificate(&self, session: &Session, principal: impl Into<String>) {
+        let principal = principal.into();
+        if let Some(namespace) = self.namespace(&principal) {
+            session.bind_namespace(namespace);
+        }
+        session.bind_principal(principal);
+    }
+
+    /// The argon2 hash of a password, as written in the credential file.
+    pub fn hash_password(password: &str) -> Result<String, KvError> {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| KvError::Internal(e.to_string()))
+    }
+
+    /// The sha256 of a token, as written in the credential file.
+    pub fn hash_token(token: &str) -> String {
+        Sha256::digest(token.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// Check a credential and return the principal it belongs to.
+    pub fn verify(&self, credential: &Credential) -> Option<String> {
+        match credential {
+            Credential::Password(p) => {
+                let hash = PasswordHash::new(self.users.get(&p.username)?).ok()?;
+                Argon2::default()
+                    .verify_password(p.password.as_bytes(), &hash)
+                    .ok()?;
+                Some(p.username.clone())
+            }
+            Credential::Token(token) => self.tokens.get(&Self::hash_token(token)).cloned(),
+        }
+    }
+}
+
+impl FromStr for Credentials {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut credentials = Self::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let (kind, principal, hash, namespace) = match fields[..] {
+                [kind, principal, hash] => (kind, principal, hash, None),
+                [kind, principal, hash, namespace] => (kind, principal, hash, Some(namespace)),
+                _ => ("", "", "", None),
+            };
+            match kind {
+                "user" => {
+                    PasswordHash::new(hash)
+                        .map_err(|e| KvError::ConfigError(format!("line {}: {}", i + 1, e)))?;
+                    credentials.users.insert(principal.into(), hash.into());
+                }
+                "token" => {
+                    credentials
+                        .tokens
+                        .insert(hash.to_lowercase(), principal.into());
+                }
+                _ => {
+                    return Err(KvError::ConfigError(format!(
+                        "line {}: expect `<user|token> <principal> <hash> [namespace]`",
+                        i + 1
+                    )))
+                }
+            }
+            if let Some(namespace) = namespace {
+                namespace::validate(namespace)
+                    .map_err(|e| KvError::ConfigError(format!("line {}: {}", i + 1, e)))?;
+                credentials.bind_namespace(principal, namespace);
+            }
+        }
+        Ok(credentials)
+    }
+}
+
+/// Middleware that answers `Auth` commands and rejects every other command
+/// with 401 until the connection has authenticated.
+pub struct Authenticator {
+    credentials: Arc<Credentials>,
+}
+
+impl Authenticator {
+    pub fn new(credentials: Credentials) -> Self {
+        Arc::new(credentials).into()
+    }
+}
+
+/// Share the credentials with what else needs them, such as binding TLS
+/// client certificates.
+impl From<Arc<Credentials>> for Authenticator {
+    fn from(credentials: Arc<Credentials>) -> Self {
+        Self { credentials }
+    }
+}
+
+impl Middleware for Authenticator {
+    fn call<'a>(&'a self, req: CommandRequest, next: Next<'a>) -> BoxFuture<'a, StreamingResponse> {
+        async move {
+            let session = next.session();
+            let res: CommandResponse = match req.request_data {
+                Some(RequestData::Auth(_)) if session.is_principal_bound() => {
+                    KvError::PermissionDenied(format!(
+                        "connection is authenticated as {} by its client certificate",
+                        session.principal().unwrap_or_default()
+                    ))
+                    .into()
+                }
+                Some(RequestData::Auth(auth)) => {
+                    let credentials = Arc::clone(&self.credentials);
+                    // argon2 is slow on purpose, keep it off the reactor
+                    let principal = task::spawn_blocking(move || {
+                        auth.credential.and_then(|c| credentials.verify(&c))
+                    })
+                    .await;
+
+                    match principal {
+                        Ok(Some(principal)) => {
+                            info!("authenticated as {}", principal);
+                            if let Some(namespace) = self.credentials.namespace(&principal) {
+                                session.bind_namespace(namespace);
+                            }
+                            session.set_principal(principal);
+                            CommandResponse::ok()
+                        }
+                        Ok(None) => KvError::Unauthenticated("invalid credentials".into()).into(),
+                        Err(e) => KvError::from(e).into(),
+                    }
+                }
+                _ if !session.is_authenticated() => {
+                    KvError::Unauthenticated("authentication required".into()).into()
+                }
+                _ => return next.run(req).await,
+            };
+            res.into()
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assert_res_ref_error, assert_res_ref_ok, MemTable, Service, ServiceInner, Session,
+    };
+    use futures::StreamExt;
+
+    fn service() -> Service {
+        let mut credentials = Credentials::new();
+        credentials.add_user("alice", "secret").unwrap();
+        credentials.add_token("ci", "t0ken");
+        credentials.bind_namespace("ci", "acme");
+        ServiceInner::new(MemTable::new())
+            .layer(Authenticator::new(credentials))
+            .into()
+    }
+
+    #[tokio::test]
+    async fn auth_should_be_required() {
+        let service = service();
+        let session = Arc::new(Session::new());
+
+        let mut res = service.execute(CommandRequest::new_hget("t1", "k1"), &session);
+        assert_res_ref_error(&res.next().await.unwrap(), 401, "authentication required");
+
+        let cmd = CommandRequest::new_auth_password("alice", "wrong");
+        let mut res = service.execute(cmd, &session);
+        assert_res_ref_error(&res.next().await.unwrap(), 401, "invalid credentials");
+        assert!(!session.is_authenticated());
+
+        let cmd = CommandRequest::new_auth_password("alice", "secret");
+        let mut res = service.execute(cmd, &session);
+        assert_res_ref_ok(&res.next().await.unwrap(), &[], &[]);
+        assert_eq!(session.principal(), Some("alice".into()));
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let mut res = service.execute(cmd, &session);
+        assert_res_ref_ok(&res.next().await.unwrap(), &[Default::default()], &[]);
+
+        // sessions are per connection
+        let other = Arc::new(Session::new());
+        let mut res = service.execute(CommandRequest::new_hget("t1", "k1"), &other);
+        assert_eq!(res.next().await.unwrap().status, 401);
+    }
+
+    #[tokio::test]
+    async fn auth_with_token_should_work() {
+        let service = service();
+        let session = Arc::new(Session::new());
+
+        let mut res = service.execute(CommandRequest::new_auth_token("nope"), &session);
+        assert_eq!(res.next().await.unwrap().status, 401);
+
+        let mut res = service.execute(CommandRequest::new_auth_token("t0ken"), &session);
+        assert_res_ref_ok(&res.next().await.unwrap(), &[], &[]);
+        assert_eq!(session.principal(), Some("ci".into()));
+        assert_eq!(session.namespace(), Some("acme".into()));
+        assert!(session.is_namespace_bound());
+    }
+
+    #[tokio::test]
+    async fn certificates_should_bind_the_namespace_of_their_principal() {
+        let mut credentials = Credentials::new();
+        credentials.bind_namespace("ci", "acme");
+        let credentials = Arc::new(credentials);
+        let service: Service = ServiceInner::new(MemTable::new())
+            .layer(Authenticator::from(Arc::clone(&credentials)))
+            .into();
+
+        let session = Arc::new(Session::new());
+        credentials.bind_certificate(&session, "ci");
+        assert!(session.is_principal_bound() && session.is_namespace_bound());
+        assert_eq!(session.namespace(), Some("acme".into()));
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let mut res = service.execute(cmd, &session);
+        assert_res_ref_ok(&res.next().await.unwrap(), &[Default::default()], &[]);
+
+        let session = Arc::new(Session::new());
+        credentials.bind_certificate(&session, "other");
+        assert_eq!(session.principal(), Some("other".into()));
+        assert!(!session.is_namespace_bound());
+    }
+
+    #[tokio::test]
+    async fn auth_should_not_override_certificate_principal() {
+        let service = service();
+        let session = Arc::new(Session::new());
+        session.bind_principal("kv client");
+
+        let cmd = CommandRequest::new_auth_password("alice", "secret");
+        let mut res = service.execute(cmd, &session);
+        assert_res_ref_error(
+            &res.next().await.unwrap(),
+            403,
+            "connection is authenticated as kv client by its client certificate",
+        );
+        assert_eq!(session.principal(), Some("kv client".into()));
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let mut res = service.execute(cmd, &session);
+        assert_res_ref_ok(&res.next().await.unwrap(), &[Default::default()], &[]);
+    }
+
+    #[test]
+    fn credentials_should_parse() {
+        let file = format!(
+            "# users\nuser alice {}\n\ntoken ci {} acme\n",
+            Credentials::hash_password("secret").unwrap(),
+            Credentials::hash_token("t0ken")
+        );
+        let credentials: Credentials = file.parse().unwrap();
+
+        let password = Credential::Password(crate::Password {
+            username: "alice".into(),
+            password: "secret".into(),
+        });
+        assert_eq!(credentials.verify(&password), Some("alice".into()));
+        assert_eq!(
+            credentials.verify(&Credential::Token("t0ken".into())),
+            Some("ci".into())
+        );
+        assert_eq!(
+            credentials.verify(&Credential::Token("secret".into())),
+            None
+        );
+        assert_eq!(credentials.namespace("ci"), Some("acme"));
+        assert_eq!(credentials.namespace("alice"), None);
+
+        assert!(matches!(
+            "user alice not-a-hash".parse::<Credentials>(),
+            Err(KvError::ConfigError(_))
+        ));
+        assert!(matches!(
+            "admin alice".parse::<Credentials>(),
+            Err(KvError::ConfigError(_))
+        ));
+    }
+}
@@ -0,0 +1,109 @@
+use crate::{CommandResponse, KvError, Kvpair};
+use futures::{stream, Stream, StreamExt};
+
+/// Max encoded size of the pairs carried by one chunk, so that a large table
+/// is sent as a sequence of frames instead of a single huge one.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Field number of `pairs` in `CommandResponse`
+const PAIRS_TAG: u32 = 4;
+
+/// Bytes `pair` adds to a response: its field key and length prefix as well
+/// as the pair itself.
+fn pair_len(pair: &Kvpair) -> usize {
+    prost::encoding::message::encoded_len(PAIRS_TAG, pair)
+}
+
+/// Split a stream of pairs into `CommandResponse` chunks of at most `limit`
+/// encoded bytes (a single oversized pair still gets its own chunk). Every
+/// chunk but the last one has `has_more` set; an empty input yields a single
+/// empty chunk so that the receiver always sees the end of the stream. An
+/// error ends the stream with an error response.
+pub fn chunk_pairs<S>(pairs: S, limit: usize) -> impl Stream<Item = CommandResponse> + Send
+where
+    S: Stream<Item = Result<Kvpair, KvError>> + Send + 'static,
+{
+    let pairs = Box::pin(pairs.peekable());
+
+    stream::unfold(Some(pairs), move |state| async move {
+        let mut pairs = state?;
+
+        let mut size = 0;
+        let mut res = CommandResponse::ok();
+        loop {
+            // leave the next pair to the next chunk if it does not fit
+            match pairs.as_mut().peek().await {
+                Some(Ok(pair)) if !res.pairs.is_empty() && size + pair_len(pair) > limit => break,
+                None => break,
+                _ => {}
+            }
+            match pairs.next().await {
+                Some(Ok(pair)) => {
+                    size += pair_len(&pair);
+                    res.pairs.push(pair);
+                }
+                Some(Err(e)) => return Some((e.into(), None)),
+                None => break,
+            }
+        }
+
+        res.has_more = pairs.as_mut().peek().await.is_some();
+        let next = if res.has_more { Some(pairs) } else { None };
+        Some((res, next))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message;
+
+    #[tokio::test]
+    async fn chunk_pairs_should_respect_limit() {
+        let pairs = (0..10).map(|i| Ok(Kvpair::new(format!("k{}", i), i.into())));
+        let size = pair_len(&Kvpair::new("k0", 0.into()));
+
+        let chunks: Vec<_> = chunk_pairs(stream::iter(pairs), size * 4).collect().await;
+        let lens: Vec<_> = chunks.iter().map(|c| c.pairs.len()).collect();
+        let more: Vec<_> = chunks.iter().map(|c| c.has_more).collect();
+        assert_eq!(lens, vec![4, 4, 2]);
+        assert_eq!(more, vec![true, true, false]);
+        for chunk in chunks {
+            let pairs = CommandResponse {
+                pairs: chunk.pairs,
+                ..Default::default()
+            };
+            assert!(pairs.encoded_len() <= size * 4);
+        }
+
+        // a pair that would overshoot the limit starts the next chunk
+        let pairs = (0..10).map(|i| Ok(Kvpair::new(format!("k{}", i), i.into())));
+        let chunks: Vec<_> = chunk_pairs(stream::iter(pairs), size * 4 - 1)
+            .collect()
+            .await;
+        let lens: Vec<_> = chunks.iter().map(|c| c.pairs.len()).collect();
+        assert_eq!(lens, vec![3, 3, 3, 1]);
+    }
+
+    #[tokio::test]
+    async fn chunk_pairs_empty_should_yield_one_chunk() {
+        let chunks: Vec<_> = chunk_pairs(stream::empty(), CHUNK_SIZE).collect().await;
+        assert_eq!(chunks.len(), 1);
+        assert!(chunks[0].pairs.is_empty());
+        assert!(!chunks[0].has_more);
+    }
+
+    #[tokio::test]
+    async fn chunk_pairs_error_should_end_stream() {
+        let pairs = vec![
+            Ok(Kvpair::new("k0", 0.into())),
+            Err(KvError::Internal("boom".into())),
+            Ok(Kvpair::new("k1", 1.into())),
+        ];
+
+        let chunks: Vec<_> = chunk_pairs(stream::iter(pairs), CHUNK_SIZE).collect().await;
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].status, 500);
+        assert!(!chunks[0].has_more);
+    }
+}
@@ -1,28 +1,38 @@
-use crate::{command_request::RequestData, *};
+use crate::{command_request::RequestData, storage::display_key, *};
 
 impl CommandService for Hget {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        match store.get(&self.table, &self.key) {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        match store.get(&self.table, &self.key).await {
             Ok(Some(v)) => v.into(),
-            Ok(None) => KvError::NotFound(self.table, self.key).into(),
+            Ok(None) => KvError::NotFound(self.table, display_key(&self.key)).into(),
             Err(e) => e.into(),
         }
     }
 }
 
 impl CommandService for RequestData {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
         match self {
-            RequestData::Hget(param) => param.execute(store),
-            RequestData::Hset(param) => param.execute(store),
-            RequestData::Hdel(param) => param.execute(store),
-            RequestData::Hexist(param) => param.execute(store),
-            RequestData::Hmget(param) => param.execute(store),
-            RequestData::Hmdel(param) => param.execute(store),
-            RequestData::Hmset(param) => param.execute(store),
-            RequestData::Hgetall(param) => param.execute(store),
-            RequestData::Hmexist(param) => param.execute(store),
-            _ => todo!(),
+            RequestData::Hget(param) => param.execute(store).await,
+            RequestData::Hset(param) => param.execute(store).await,
+            RequestData::Hdel(param) => param.execute(store).await,
+            RequestData::Hexist(param) => param.execute(store).await,
+            RequestData::Hmget(param) => param.execute(store).await,
+            RequestData::Hmdel(param) => param.execute(store).await,
+            RequestData::Hmset(param) => param.execute(store).await,
+            RequestData::Hgetall(param) => param.execute(store).await,
+            RequestData::Hmexist(param) => param.execute(store).await,
+            // answered by `Authenticator` when authentication is enabled
+            RequestData::Auth(_) => {
+                KvError::InvalidCommand("authentication is not enabled".into()).into()
+            }
+            // looked up in the registry by `Service`
+            RequestData::Custom(param) => {
+                KvError::InvalidCommand(format!("unknown command `{}`", param.name)).into()
+            }
+            cmd => {
+                KvError::InvalidCommand(format!("`{}` is not a storage command", cmd.name())).into()
+            }
         }
     }
 }
@@ -33,7 +43,15 @@ impl TopicService for RequestData {
             RequestData::Subscribe(param) => param.execute(chan),
             RequestData::Unsubscribe(param) => param.execute(chan),
             RequestData::Publish(param) => param.execute(chan),
-            _ => todo!(),
+            RequestData::Psubscribe(param) => param.execute(chan),
+            RequestData::Punsubscribe(param) => param.execute(chan),
+            RequestData::Ack(param) => param.execute(chan),
+            RequestData::Pending(param) => param.execute(chan),
+            cmd => {
+                let e =
+                    KvError::InvalidCommand(format!("`{}` is not a pubsub command", cmd.name()));
+                CommandResponse::from(e).into()
+            }
         }
     }
 }
@@ -42,15 +60,85 @@ impl RequestData {
     pub fn is_streaming(&self) -> bool {
         matches!(
             *self,
-            RequestData::Subscribe(_) | RequestData::Unsubscribe(_) | RequestData::Publish(_)
+            RequestData::Subscribe(_)
+                | RequestData::Unsubscribe(_)
+                | RequestData::Publish(_)
+                | RequestData::Psubscribe(_)
+                | RequestData::Punsubscribe(_)
+                | RequestData::Ack(_)
+                | RequestData::Pending(_)
         )
     }
+
+    /// Lowercase name of the command, e.g. `hget`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            RequestData::Hget(_) => "hget",
+            RequestData::Hgetall(_) => "hgetall",
+            RequestData::Hmget(_) => "hmget",
+            RequestData::Hset(_) => "hset",
+            RequestData::Hmset(_) => "hmset",
+            RequestData::Hdel(_) => "hdel",
+            RequestData::Hmdel(_) => "hmdel",
+            RequestData::Hexist(_) => "hexist",
+            RequestData::Hmexist(_) => "hmexist",
+            RequestData::Subscribe(_) => "subscribe",
+            RequestData::Unsubscribe(_) => "unsubscribe",
+            RequestData::Publish(_) => "publish",
+            RequestData::Auth(_) => "auth",
+            RequestData::Custom(_) => "custom",
+            RequestData::SlowlogGet(_) => "slowlogget",
+            RequestData::SlowlogReset(_) => "slowlogreset",
+            RequestData::Info(_) => "info",
+            RequestData::SelectNamespace(_) => "selectnamespace",
+            RequestData::ListNamespaces(_) => "listnamespaces",
+            RequestData::SetMode(_) => "setmode",
+            RequestData::Psubscribe(_) => "psubscribe",
+            RequestData::Punsubscribe(_) => "punsubscribe",
+            RequestData::Ack(_) => "ack",
+            RequestData::Pending(_) => "pending",
+        }
+    }
+
+    /// The table, topic or topic pattern the command works on, or the name of
+    /// a custom command or namespace.
+    pub fn resource(&self) -> Option<&str> {
+        match self {
+            RequestData::Hget(v) => Some(&v.table),
+            RequestData::Hgetall(v) => Some(&v.table),
+            RequestData::Hmget(v) => Some(&v.table),
+            RequestData::Hset(v) => Some(&v.table),
+            RequestData::Hmset(v) => Some(&v.table),
+            RequestData::Hdel(v) => Some(&v.table),
+            RequestData::Hmdel(v) => Some(&v.table),
+            RequestData::Hexist(v) => Some(&v.table),
+            RequestData::Hmexist(v) => Some(&v.table),
+            RequestData::Subscribe(v) => Some(&v.topic),
+            RequestData::Unsubscribe(v) => Some(&v.topic),
+            RequestData::Publish(v) => Some(&v.topic),
+            RequestData::Psubscribe(v) => Some(&v.pattern),
+            RequestData::Punsubscribe(v) => Some(&v.pattern),
+            RequestData::Ack(v) => Some(&v.topic),
+            RequestData::Pending(v) => Some(&v.topic),
+            RequestData::Auth(_) => None,
+            RequestData::Custom(v) => Some(&v.name),
+            RequestData::SelectNamespace(v) => Some(&v.name),
+            RequestData::SlowlogGet(_)
+            | RequestData::SlowlogReset(_)
+            | RequestData::Info(_)
+            | RequestData::ListNamespaces(_)
+            | RequestData::SetMode(_) => None,
+        }
+    }
 }
 
 impl CommandService for Hset {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
         match self.pair {
-            Some(v) => match store.set(&self.table, v.key, v.value.unwrap_or_default()) {
+            Some(v) => match store
+                .set(&self.table, v.key, v.value.unwrap_or_default())
+                .await
+            {
                 Ok(Some(v)) => v.into(),
                 Ok(None) => Value::default().into(),
                 Err(e) => e.into(),
@@ -61,8 +149,8 @@ impl CommandService for Hset {
 }
 
 impl CommandService for Hdel {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        match store.del(&self.table, &self.key) {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        match store.del(&self.table, &self.key).await {
             Ok(Some(v)) => v.into(),
             Ok(None) => Value::default().into(),
             Err(e) => e.into(),
@@ -71,8 +159,8 @@ impl CommandService for Hdel {
 }
 
 impl CommandService for Hexist {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        match store.contains(&self.table, &self.key) {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        match store.contains(&self.table, &self.key).await {
             Ok(v) => Value::from(v).into(),
             Err(e) => e.into(),
         }
@@ -80,8 +168,8 @@ impl CommandService for Hexist {
 }
 
 impl CommandService for Hgetall {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        match store.get_all(&self.table) {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        match store.get_all(&self.table).await {
             Ok(v) => v.into(),
             Err(e) => e.into(),
         }
@@ -89,55 +177,54 @@ impl CommandService for Hgetall {
 }
 
 impl CommandService for Hmset {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        self.pairs
-            .into_iter()
-            .map(
-                |pair| match store.set(&self.table, pair.key, pair.value.unwrap_or_default()) {
-                    Ok(Some(v)) => v,
-                    _ => Value::default(),
-                },
-            )
-            .collect::<Vec<_>>()
-            .into()
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        let mut values = Vec::with_capacity(self.pairs.len());
+        for pair in self.pairs {
+            let value = pair.value.unwrap_or_default();
+            values.push(match store.set(&self.table, pair.key, value).await {
+                Ok(Some(v)) => v,
+                _ => Value::default(),
+            });
+        }
+        values.into()
     }
 }
 
 impl CommandService for Hmget {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        self.keys
-            .iter()
-            .map(|key| match store.get(&self.table, key) {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        let mut values = Vec::with_capacity(self.keys.len());
+        for key in self.keys.iter() {
+            values.push(match store.get(&self.table, key).await {
                 Ok(Some(v)) => v,
                 _ => Value::default(),
-            })
-            .collect::<Vec<_>>()
-            .into()
+            });
+        }
+        values.into()
     }
 }
 impl CommandService for Hmexist {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        self.keys
-            .iter()
-            .map(|key| match store.contains(&self.table, key) {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        let mut values = Vec::with_capacity(self.keys.len());
+        for key in self.keys.iter() {
+            values.push(match store.contains(&self.table, key).await {
                 Ok(v) => Value::from(v),
                 Err(e) => e.into(),
-            })
-            .collect::<Vec<Value>>()
-            .into()
+            });
+        }
+        values.into()
     }
 }
 
 impl CommandService for Hmdel {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        self.keys
-            .iter()
-            .map(|key| match store.del(&self.table, key) {
+    async fn execute(self, store: &impl AsyncStorage) -> CommandResponse {
+        let mut values = Vec::with_capacity(self.keys.len());
+        for key in self.keys.iter() {
+            values.push(match store.del(&self.table, key).await {
                 Ok(Some(v)) => v,
                 _ => Value::default(),
-            })
-            .collect::<Vec<_>>()
-            .into()
+            });
+        }
+        values.into()
     }
 }
 
@@ -145,89 +232,107 @@ impl CommandService for Hmdel {
 mod tests {
     use super::*;
 
-    #[test]
-    fn hset_should_work() {
+    #[tokio::test]
+    async fn hset_should_work() {
         let store = MemTable::new();
         let cmd = CommandRequest::new_hset("t1", "hello", "world".into());
-        let res = cmd.clone().dispatch(&store);
+        let res = cmd.clone().dispatch(&store).await;
         assert_res_ok(res, &[Value::default()], &[]);
 
-        let res = cmd.dispatch(&store);
+        let res = cmd.dispatch(&store).await;
         assert_res_ok(res, &["world".into()], &[]);
     }
 
-    #[test]
-    fn hget_should_work() {
+    #[tokio::test]
+    async fn hget_should_work() {
         let store = MemTable::new();
         let cmd = CommandRequest::new_hset("score", "u1", 10.into());
-        cmd.dispatch(&store);
+        cmd.dispatch(&store).await;
         let cmd = CommandRequest::new_hget("score", "u1");
-        let res = cmd.dispatch(&store);
+        let res = cmd.dispatch(&store).await;
         assert_res_ok(res, &[10.into()], &[]);
     }
 
-    #[test]
-    fn hdel_should_work() {
+    #[tokio::test]
+    async fn hget_binary_key_should_work() {
         let store = MemTable::new();
-        set_key_pairs("score", vec![("u1", 10)], &store);
+        let key = [0xde, 0xad, 0xbe, 0xef, 0x00, b':'];
+        let cmd = CommandRequest::new_hset("ids", key, "v".into());
+        cmd.dispatch(&store).await;
+
+        let cmd = CommandRequest::new_hget("ids", key);
+        let res = cmd.dispatch(&store).await;
+        assert_res_ok(res, &["v".into()], &[]);
+
+        let cmd = CommandRequest::new_hget("ids", [0xff, 0x00]);
+        let res = cmd.dispatch(&store).await;
+        assert_res_error(res, 404, "key: \\xff\\x00");
+    }
+
+    #[tokio::test]
+    async fn hdel_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("score", vec![("u1", 10)], &store).await;
 
         let cmd = CommandRequest::new_hget("score", "u1");
-        let res = cmd.dispatch(&store);
+        let res = cmd.dispatch(&store).await;
         assert_res_ok(res, &[10.into()], &[]);
 
         let cmd = CommandRequest::new_hdel("score", "u1");
-        cmd.dispatch(&store);
+        cmd.dispatch(&store).await;
 
         let cmd = CommandRequest::new_hget("score", "u1");
-        let res = cmd.dispatch(&store);
+        let res = cmd.dispatch(&store).await;
         assert_res_error(res, 404, "");
     }
 
-    #[test]
-    fn hexist_should_work() {
+    #[tokio::test]
+    async fn hexist_should_work() {
         let store = MemTable::new();
-        set_key_pairs("score", vec![("u1", 10)], &store);
+        set_key_pairs("score", vec![("u1", 10)], &store).await;
 
         let cmd = CommandRequest::new_hexist("score", "u1");
-        let res = cmd.dispatch(&store);
+        let res = cmd.dispatch(&store).await;
         assert_res_ok(res, &[true.into()], &[]);
 
         let cmd = CommandRequest::new_hdel("score", "u1");
-        cmd.dispatch(&store);
+        cmd.dispatch(&store).await;
 
         let cmd = CommandRequest::new_hexist("score", "u1");
-        let res = cmd.dispatch(&store);
+        let res = cmd.dispatch(&store).await;
         assert_res_ok(res, &[false.into()], &[]);
     }
 
-    #[test]
-    fn hmget_should_work() {
+    #[tokio::test]
+    async fn hmget_should_work() {
         let store = MemTable::new();
 
         set_key_pairs(
             "score",
             vec![("u1", 10), ("u2", 8), ("u3", 22), ("u4", 6)],
             &store,
-        );
+        )
+        .await;
 
-        let cmd = CommandRequest::new_hmget("score", vec!["u1".into(), "u2".into(), "u3".into()]);
-        let res = cmd.dispatch(&store);
+        let cmd = CommandRequest::new_hmget("score", ["u1", "u2", "u3"]);
+        let res = cmd.dispatch(&store).await;
         let values = &[10.into(), 8.into(), 22.into()];
         assert_res_ok(res, values, &[]);
     }
 
-    #[test]
-    fn hgetall_should_work() {
+    #[tokio::test]
+    async fn hgetall_should_work() {
         let store = MemTable::new();
 
         set_key_pairs(
             "score",
             vec![("u1", 10), ("u2", 8), ("u3", 11), ("u1", 6)],
             &store,
-        );
+        )
+        .await;
 
         let cmd = CommandRequest::new_hgetall("score");
-        let res = cmd.dispatch(&store);
+        let res = cmd.dispatch(&store).await;
         let pairs = &[
             Kvpair::new("u1", 6.into()),
             Kvpair::new("u2", 8.into()),
@@ -236,35 +341,38 @@ mod tests {
         assert_res_ok(res, &[], pairs);
     }
 
-    #[test]
-    fn hmset_should_work() {
+    #[tokio::test]
+    async fn hmset_should_work() {
         let store = MemTable::new();
-        set_key_pairs("t1", vec![("u1", "world")], &store);
+        set_key_pairs("t1", vec![("u1", "world")], &store).await;
         let pairs = vec![
             Kvpair::new("u1", 10.1.into()),
             Kvpair::new("u2", 8.1.into()),
         ];
         let cmd = CommandRequest::new_hmset("t1", pairs);
-        let res = cmd.dispatch(&store);
+        let res = cmd.dispatch(&store).await;
         assert_res_ok(res, &["world".into(), Value::default()], &[]);
     }
 
-    #[test]
-    fn hmexist_should_work() {
+    #[tokio::test]
+    async fn hmexist_should_work() {
         let store = MemTable::new();
-        set_key_pairs("t1", vec![("u1", "v1"), ("u2", "v2")], &store);
+        set_key_pairs("t1", vec![("u1", "v1"), ("u2", "v2")], &store).await;
 
-        let cmd = CommandRequest::new_hmexist("t1", vec!["u1".into(), "u3".into()]);
-        let res = cmd.dispatch(&store);
+        let cmd = CommandRequest::new_hmexist("t1", vec!["u1".to_string(), "u3".to_string()]);
+        let res = cmd.dispatch(&store).await;
         assert_res_ok(res, &[true.into(), false.into()], &[]);
     }
 
-    fn set_key_pairs<T: Into<Value>>(table: &str, pairs: Vec<(&str, T)>, store: &impl Storage) {
-        pairs
-            .into_iter()
-            .map(|(k, v)| CommandRequest::new_hset(table, k, v.into()))
-            .for_each(|cmd| {
-                cmd.dispatch(store);
-            });
+    async fn set_key_pairs<T: Into<Value>>(
+        table: &str,
+        pairs: Vec<(&str, T)>,
+        store: &impl AsyncStorage,
+    ) {
+        for (k, v) in pairs {
+            CommandRequest::new_hset(table, k, v.into())
+                .dispatch(store)
+                .await;
+        }
     }
 }
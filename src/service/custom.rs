@@ -0,0 +1,274 @@
+use crate::{
+    service::topic::PubSub, value, AsyncStorage, CommandRequest, CommandResponse, ErrorCode,
+    KvError, Kvpair, Service, ServiceInner, Session, Value,
+};
+use bytes::Bytes;
+use futures::{future::BoxFuture, stream, Future, FutureExt, Stream, StreamExt};
+use std::sync::Arc;
+
+pub(crate) type Handler<Store> =
+    Box<dyn Fn(Vec<Value>, Context<Store>) -> BoxFuture<'static, CommandResponse> + Send + Sync>;
+
+/// What a custom command handler gets to work with besides its arguments.
+pub struct Context<Store> {
+    service: Service<Store>,
+    session: Arc<Session>,
+}
+
+impl<Store> Context<Store> {
+    pub(crate) fn new(
+        inner: Arc<ServiceInner<Store>>,
+        broadcaster: Arc<PubSub>,
+        session: Arc<Session>,
+    ) -> Self {
+        Self {
+            service: Service { inner, broadcaster },
+            session,
+        }
+    }
+
+    /// The store as the connection sees it, see [`SessionStore`].
+    pub fn store(&self) -> SessionStore<Store> {
+        SessionStore {
+            service: self.service.clone(),
+            session: Arc::clone(&self.session),
+        }
+    }
+
+    pub fn pubsub(&self) -> &Arc<PubSub> {
+        &self.service.broadcaster
+    }
+
+    /// The connection that sent the command.
+    pub fn session(&self) -> &Arc<Session> {
+        &self.session
+    }
+}
+
+/// Storage on behalf of the connection running a custom command. Every call
+/// runs as the matching command (`get` as `hget`, `set` as `hset`, ...)
+/// through the middlewares, so it is scoped to the namespace of the session
+/// and goes through access control, auditing, quotas and the server mode
+/// like a command the connection sent itself.
+pub struct SessionStore<Store> {
+    service: Service<Store>,
+    session: Arc<Session>,
+}
+
+impl<Store: AsyncStorage> SessionStore<Store> {
+    async fn run(&self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        match self.service.execute(cmd, &self.session).next().await {
+            Some(res) => res.as_ref().clone().into_result(),
+            None => Err(KvError::Internal("command ended without a response".into())),
+        }
+    }
+}
+
+/// The value a single-key command returned, `None` for a missing one.
+fn value_of(res: CommandResponse) -> Option<Value> {
+    res.values.into_iter().next().filter(|v| v.value.is_some())
+}
+
+impl<Store: AsyncStorage> AsyncStorage for SessionStore<Store> {
+    async fn get(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        match self.run(CommandRequest::new_hget(table, key)).await {
+            Ok(res) => Ok(value_of(res)),
+            Err(e) if e.code() == ErrorCode::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn set(&self, table: &str, key: Bytes, value: Value) -> Result<Option<Value>, KvError> {
+        let res = self
+            .run(CommandRequest::new_hset(table, key, value))
+            .await?;
+        Ok(value_of(res))
+    }
+
+    async fn contains(&self, table: &str, key: &[u8]) -> Result<bool, KvError> {
+        let res = self.run(CommandRequest::new_hexist(table, key)).await?;
+        Ok(matches!(
+            value_of(res).and_then(|v| v.value),
+            Some(value::Value::Bool(true))
+        ))
+    }
+
+    async fn del(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        let res = self.run(CommandRequest::new_hdel(table, key)).await?;
+        Ok(value_of(res))
+    }
+
+    async fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.get_iter(table)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect()
+    }
+
+    fn get_iter(
+        &self,
+        table: &str,
+    ) -> impl Stream<Item = Result<Kvpair, KvError>> + Send + 'static {
+        let res = self
+            .service
+            .execute(CommandRequest::new_hgetall(table), &self.session);
+        res.flat_map(|res| match res.as_ref().clone().into_result() {
+            Ok(res) => stream::iter(res.pairs.into_iter().map(Ok).collect::<Vec<_>>()),
+            Err(e) => stream::iter(vec![Err(e)]),
+        })
+    }
+
+    async fn key_counts(&self) -> Result<Vec<(String, u64)>, KvError> {
+        let res = self.run(CommandRequest::new_info()).await?;
+        Ok(res
+            .info
+            .unwrap_or_default()
+            .table_keys
+            .into_iter()
+            .collect())
+    }
+}
+
+pub(crate) fn handler<Store, F, Fut, R>(f: F) -> Handler<Store>
+where
+    F: Fn(Vec<Value>, Context<Store>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<R, KvError>> + Send + 'static,
+    R: Into<CommandResponse>,
+{
+    Box::new(move |args, ctx| {
+        f(args, ctx)
+            .map(|res| match res {
+                Ok(v) => v.into(),
+                Err(e) => e.into(),
+            })
+            .boxed()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value;
+    use crate::{
+        assert_res_error, assert_res_ok, service::topic::Topic, AccessControl, CommandRequest,
+        CommandService, MemTable, Namespaces,
+    };
+    use futures::StreamExt;
+
+    fn string(v: Value) -> Result<String, KvError> {
+        match v.value {
+            Some(value::Value::String(s)) => Ok(s),
+            _ => Err(KvError::ConvertError(v, "String")),
+        }
+    }
+
+    async fn incr(args: Vec<Value>, ctx: Context<MemTable>) -> Result<Value, KvError> {
+        let [table, key]: [Value; 2] = args
+            .try_into()
+            .map_err(|_| KvError::InvalidCommand("incr takes a table and a key".into()))?;
+        let (table, key) = (string(table)?, string(key)?);
+        let n = match ctx.store().get(&table, key.as_bytes()).await? {
+            Some(v) => i64::try_from(v)? + 1,
+            None => 1,
+        };
+        ctx.store().set(&table, key.into(), n.into()).await?;
+        Ok(n.into())
+    }
+
+    #[tokio::test]
+    async fn custom_command_should_use_store() {
+        let service: Service = ServiceInner::new(MemTable::new())
+            .command("incr", incr)
+            .into();
+        let cmd = CommandRequest::new_custom("incr", vec!["t1".into(), "k1".into()]);
+
+        for n in 1..=2 {
+            let res = service.execute(cmd.clone(), &Arc::default()).next().await;
+            assert_res_ok(res.unwrap().as_ref().clone(), &[n.into()], &[]);
+        }
+        let res = service
+            .execute(CommandRequest::new_hget("t1", "k1"), &Arc::default())
+            .next()
+            .await;
+        assert_res_ok(res.unwrap().as_ref().clone(), &[2.into()], &[]);
+
+        let cmd = CommandRequest::new_custom("incr", vec!["t1".into()]);
+        let res = service.execute(cmd, &Arc::default()).next().await;
+        assert_res_error(
+            res.unwrap().as_ref().clone(),
+            400,
+            "takes a table and a key",
+        );
+    }
+
+    #[tokio::test]
+    async fn custom_command_store_should_act_for_the_session() {
+        let acl = "deny * hset acme/locked\nallow * * *".parse().unwrap();
+        let service: Service = ServiceInner::new(MemTable::new())
+            .layer(AccessControl::new(acl))
+            .namespaces(Namespaces::new().quota("acme", 1))
+            .command("incr", incr)
+            .into();
+        let session = Arc::new(Session::new());
+        session.bind_namespace("acme");
+        let incr = |table: &str, key: &str| {
+            let cmd = CommandRequest::new_custom("incr", vec![table.into(), key.into()]);
+            let mut res = service.execute(cmd, &session);
+            async move { res.next().await.unwrap().as_ref().clone() }
+        };
+
+        assert_res_ok(incr("t1", "k1").await, &[1.into()], &[]);
+        let res = service
+            .execute(CommandRequest::new_hget("acme/t1", "k1"), &Arc::default())
+            .next()
+            .await;
+        assert_res_ok(res.unwrap().as_ref().clone(), &[1.into()], &[]);
+
+        assert_res_error(incr("t1", "k2").await, 507, "namespace acme holds 1 of 1");
+        assert_res_error(incr("locked", "k1").await, 403, "hset on locked");
+    }
+
+    #[tokio::test]
+    async fn custom_command_should_publish() {
+        let service: Service = ServiceInner::new(MemTable::new())
+            .command(
+                "shout",
+                |args: Vec<Value>, ctx: Context<MemTable>| async move {
+                    let n = args.len() as i64;
+                    let msg = CommandResponse::from(args);
+                    ctx.pubsub()
+                        .clone()
+                        .publish("lobby".into(), Arc::new(msg))
+                        .await;
+                    Ok(Value::from(n))
+                },
+            )
+            .into();
+
+        let mut sub = service.execute(CommandRequest::new_subscribe("lobby"), &Arc::default());
+        sub.next().await.unwrap();
+
+        let cmd = CommandRequest::new_custom("shout", vec!["hi".into()]);
+        let res = service.execute(cmd, &Arc::default()).next().await;
+        assert_res_ok(res.unwrap().as_ref().clone(), &[1.into()], &[]);
+
+        let msg = sub.next().await.unwrap();
+        assert_eq!(msg.values, vec!["hi".into()]);
+    }
+
+    #[tokio::test]
+    async fn unknown_command_should_be_rejected() {
+        let service: Service = Service::new(MemTable::new());
+        let cmd = CommandRequest::new_custom("nope", vec![]);
+        let res = service.execute(cmd, &Arc::default()).next().await;
+        assert_res_error(res.unwrap().as_ref().clone(), 400, "unknown command `nope`");
+
+        // pubsub commands have no meaning against the store alone
+        let cmd = CommandRequest::new_publish("lobby", vec![])
+            .request_data
+            .unwrap();
+        let res = cmd.execute(&MemTable::new()).await;
+        assert_res_error(res, 400, "`publish` is not a storage command");
+    }
+}
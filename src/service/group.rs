@@ -0,0 +1,509 @@
+use crate::{CommandResponse, KvError, PendingMessage, TopicLog};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Appended to a topic to name the topic its dead letters go to
+pub const DEAD_LETTER_SUFFIX: &str = ".dead";
+
+/// How long a consumer group waits for an `Ack` before redelivering, and how
+/// many deliveries a message gets before it is moved to the dead-letter
+/// topic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GroupConfig {
+    visibility_timeout: Duration,
+    max_attempts: u32,
+}
+
+impl Default for GroupConfig {
+    fn default() -> Self {
+        Self {
+            visibility_timeout: Duration::from_secs(30),
+            max_attempts: 5,
+        }
+    }
+}
+
+impl GroupConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn visibility_timeout(mut self, timeout: Duration) -> Self {
+        self.visibility_timeout = timeout;
+        self
+    }
+
+    pub fn max_attempts(mut self, n: u32) -> Self {
+        self.max_attempts = n.max(1);
+        self
+    }
+
+    /// How often pending messages are checked for expiry.
+    pub(crate) fn tick(&self) -> Duration {
+        (self.visibility_timeout / 4).max(Duration::from_millis(10))
+    }
+}
+
+/// What handing a message to a member came to.
+pub(crate) enum Delivery {
+    Sent,
+    /// Its buffer is full, another member may take the message
+    Full,
+    Gone,
+}
+
+#[derive(Debug)]
+struct Member {
+    id: u32,
+    consumer: String,
+    /// The connection the member subscribed from, `None` for in-process
+    /// members
+    owner: Option<u64>,
+}
+
+#[derive(Debug)]
+struct Pending {
+    member: u32,
+    consumer: String,
+    owner: Option<u64>,
+    attempts: u32,
+    delivered: Instant,
+}
+
+/// A message pending for a member or waiting to be redelivered may only be
+/// acknowledged from the connection holding it, or from within the server.
+fn may_ack(owner: Option<u64>, holder: Option<u64>) -> bool {
+    owner.is_none() || owner == holder
+}
+
+/// A consumer group of a durable topic. Messages are read from the topic log
+/// in order and handed to one member each, round robin, then stay pending
+/// until acknowledged.
+#[derive(Debug)]
+pub(crate) struct Group {
+    /// The next sequence number never delivered to the group
+    next: u64,
+    members: Vec<Member>,
+    cursor: usize,
+    pending: BTreeMap<u64, Pending>,
+    /// Expired messages with the attempts made so far and the connection
+    /// that last held them, delivered before new ones
+    redeliver: VecDeque<(u64, u32, Option<u64>)>,
+}
+
+impl Group {
+    pub(crate) fn new(next: u64) -> Self {
+        Self {
+            next,
+            members: Vec::new(),
+            cursor: 0,
+            pending: BTreeMap::new(),
+            redeliver: VecDeque::new(),
+        }
+    }
+
+    pub(crate) fn join(&mut self, id: u32, consumer: String, owner: Option<u64>) {
+        self.members.push(Member {
+            id,
+            consumer,
+            owner,
+        });
+    }
+
+    /// Remove the members `alive` says are gone, returning whether the group
+    /// is still worth keeping: it has members, messages pending or waiting to
+    /// be redelivered, or log entries up to `last` it has not read.
+    pub(crate) fn retain_members(&mut self, alive: impl Fn(u32) -> bool, last: u64) -> bool {
+        let mut i = 0;
+        while i < self.members.len() {
+            match alive(self.members[i].id) {
+                true => i += 1,
+                false => self.leave(i),
+            }
+        }
+        !self.members.is_empty()
+            || !self.pending.is_empty()
+            || !self.redeliver.is_empty()
+            || self.next <= last
+    }
+
+    /// Remove member `i`, queueing what it held for redelivery to the others
+    /// or to whoever joins next.
+    fn leave(&mut self, i: usize) {
+        let member = self.members.remove(i);
+        let held: Vec<u64> = self
+            .pending
+            .iter()
+            .filter(|(_, p)| p.member == member.id)
+            .map(|(seq, _)| *seq)
+            .collect();
+        for seq in held {
+            let pending = self.pending.remove(&seq).unwrap();
+            self.redeliver
+                .push_back((seq, pending.attempts, pending.owner));
+        }
+    }
+
+    /// Hand out redeliveries and new messages while some member has room.
+    pub(crate) fn pump(
+        &mut self,
+        log: &dyn TopicLog,
+        topic: &str,
+        deliver: &dyn Fn(u32, Arc<CommandResponse>) -> Delivery,
+    ) -> Result<(), KvError> {
+        while let Some((seq, attempts, holder)) = self.redeliver.pop_front() {
+            let message = log.read(topic, seq, 1)?.into_iter().next();
+            // trimmed from the log meanwhile
+            let Some(message) = message.filter(|m| m.sequence == seq) else {
+                continue;
+            };
+            if !self.hand_out(message, attempts, deliver) {
+                self.redeliver.push_front((seq, attempts, holder));
+                return Ok(());
+            }
+        }
+
+        loop {
+            let messages = log.read(topic, self.next, 64)?;
+            if messages.is_empty() {
+                return Ok(());
+            }
+            for message in messages {
+                let seq = message.sequence;
+                if !self.hand_out(message, 0, deliver) {
+                    return Ok(());
+                }
+                self.next = seq + 1;
+            }
+        }
+    }
+
+    /// Give `message` to the next member with room, false if none has.
+    fn hand_out(
+        &mut self,
+        message: CommandResponse,
+        attempts: u32,
+        deliver: &dyn Fn(u32, Arc<CommandResponse>) -> Delivery,
+    ) -> bool {
+        let seq = message.sequence;
+        let message = Arc::new(message);
+        let mut tried = 0;
+        while tried < self.members.len() {
+            let i = self.cursor % self.members.len();
+            let member = &self.members[i];
+            match deliver(member.id, Arc::clone(&message)) {
+                Delivery::Sent => {
+                    let pending = Pending {
+                        member: member.id,
+                        consumer: member.consumer.clone(),
+                        owner: member.owner,
+                        attempts: attempts + 1,
+                        delivered: Instant::now(),
+                    };
+                    self.pending.insert(seq, pending);
+                    self.cursor = i + 1;
+                    return true;
+                }
+                Delivery::Full => {
+                    self.cursor = i + 1;
+                    tried += 1;
+                }
+                Delivery::Gone => self.leave(i),
+            }
+        }
+        false
+    }
+
+    /// Queue the messages not acknowledged in time for redelivery, returning
+    /// those out of attempts.
+    pub(crate) fn expire(&mut self, config: &GroupConfig, now: Instant) -> Vec<u64> {
+        let expired: Vec<u64> = self
+            .pending
+            .iter()
+            .filter(|(_, p)| now.duration_since(p.delivered) >= config.visibility_timeout)
+            .map(|(seq, _)| *seq)
+            .collect();
+
+        let mut dead = vec![];
+        for seq in expired {
+            let pending = self.pending.remove(&seq).unwrap();
+            if pending.attempts >= config.max_attempts {
+                dead.push(seq);
+            } else {
+                self.redeliver
+                    .push_back((seq, pending.attempts, pending.owner));
+            }
+        }
+        dead
+    }
+
+    /// Forget the given messages held by connection `owner`, or by anyone
+    /// if `None`, returning how many were forgotten.
+    pub(crate) fn ack(&mut self, sequences: &[u64], owner: Option<u64>) -> u64 {
+        let mut acked = 0;
+        for seq in sequences {
+            if self
+                .pending
+                .get(seq)
+                .is_some_and(|p| may_ack(owner, p.owner))
+            {
+                self.pending.remove(seq);
+                acked += 1;
+            } else if let Some(i) = self
+                .redeliver
+                .iter()
+                .position(|(s, _, holder)| s == seq && may_ack(owner, *holder))
+            {
+                self.redeliver.remove(i);
+                acked += 1;
+            }
+        }
+        acked
+    }
+
+    /// Messages delivered but not acknowledged, of `consumer` only if set.
+    pub(crate) fn pending(&self, consumer: Option<&str>) -> Vec<PendingMessage> {
+        self.pending
+            .iter()
+            .filter(|(_, p)| consumer.is_none_or(|c| c == p.consumer))
+            .map(|(seq, p)| PendingMessage {
+                sequence: *seq,
+                consumer: p.consumer.clone(),
+                subscription: p.member,
+                attempts: p.attempts,
+                idle_ms: p.delivered.elapsed().as_millis() as u64,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assert_res_ok,
+        topic::{Connection, PubSub, Start, Subscriber, Topic},
+        Retention, Value,
+    };
+
+    async fn publish(b: &Arc<PubSub>, topic: &str, n: i64) {
+        b.clone()
+            .publish(topic.into(), Arc::new(Value::from(n).into()))
+            .await;
+    }
+
+    async fn recv(rx: &mut Subscriber) -> Arc<CommandResponse> {
+        tokio::time::timeout(Duration::from_secs(1), rx.recv())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn group_should_deliver_each_message_once() {
+        let b = Arc::new(PubSub::new().durable("orders", Retention::new()));
+        publish(&b, "orders", 1).await;
+
+        let mut alice = b.clone().join_group(
+            "orders".into(),
+            "billing".into(),
+            "alice".into(),
+            Start::Earliest,
+        );
+        recv(&mut alice).await;
+        let mut bob = b.clone().join_group(
+            "orders".into(),
+            "billing".into(),
+            "bob".into(),
+            Start::Earliest,
+        );
+        recv(&mut bob).await;
+        // another group gets everything again
+        let mut audit = b.clone().join_group(
+            "orders".into(),
+            "audit".into(),
+            "carol".into(),
+            Start::Earliest,
+        );
+        recv(&mut audit).await;
+
+        for n in 2..=4 {
+            publish(&b, "orders", n).await;
+        }
+        let mut seen = vec![];
+        for _ in 0..2 {
+            seen.push(recv(&mut alice).await.sequence);
+            seen.push(recv(&mut bob).await.sequence);
+        }
+        seen.sort();
+        assert_eq!(seen, [1, 2, 3, 4]);
+        for n in 1..=4u64 {
+            assert_eq!(recv(&mut audit).await.sequence, n);
+        }
+
+        let pending = b
+            .clone()
+            .pending("orders".into(), "billing".into(), None)
+            .unwrap();
+        assert_eq!(pending.len(), 4);
+        let alices = b
+            .clone()
+            .pending("orders".into(), "billing".into(), Some("alice".into()));
+        assert_eq!(alices.unwrap().len(), 2);
+
+        let acked = b
+            .clone()
+            .ack("orders".into(), "billing".into(), vec![1, 2, 9]);
+        assert_eq!(acked.unwrap(), 2);
+        let pending = b
+            .clone()
+            .pending("orders".into(), "billing".into(), None)
+            .unwrap();
+        assert_eq!(
+            pending.iter().map(|p| p.sequence).collect::<Vec<_>>(),
+            [3, 4]
+        );
+
+        let res = b.clone().pending("orders".into(), "nobody".into(), None);
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn group_should_redeliver_then_dead_letter() {
+        let config = GroupConfig::new()
+            .visibility_timeout(Duration::from_millis(50))
+            .max_attempts(2);
+        let b = Arc::new(
+            PubSub::new()
+                .durable("jobs", Retention::new())
+                .consumer_groups(config),
+        );
+        let mut dead = b.clone().subscribe(format!("jobs{}", DEAD_LETTER_SUFFIX));
+        recv(&mut dead).await;
+
+        let mut worker =
+            b.clone()
+                .join_group("jobs".into(), "workers".into(), "w1".into(), Start::Latest);
+        recv(&mut worker).await;
+        publish(&b, "jobs", 1).await;
+        publish(&b, "jobs", 2).await;
+
+        assert_eq!(recv(&mut worker).await.sequence, 1);
+        assert_eq!(recv(&mut worker).await.sequence, 2);
+        b.clone()
+            .ack("jobs".into(), "workers".into(), vec![2])
+            .unwrap();
+
+        // never acknowledged, delivered again and then given up on
+        assert_eq!(recv(&mut worker).await.sequence, 1);
+        let pending = b
+            .clone()
+            .pending("jobs".into(), "workers".into(), None)
+            .unwrap();
+        assert_eq!(pending[0].attempts, 2);
+
+        let res = recv(&mut dead).await;
+        assert_res_ok(res.as_ref().clone(), &[1i64.into()], &[]);
+        assert_eq!(res.topic, "jobs");
+        let pending = b
+            .clone()
+            .pending("jobs".into(), "workers".into(), None)
+            .unwrap();
+        assert!(pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_the_holding_connection_should_ack() {
+        let b = Arc::new(PubSub::new().durable("jobs", Retention::new()));
+        let (worker, other) = (Connection::new(b.clone(), 1), Connection::new(b.clone(), 2));
+        let mut rx =
+            worker
+                .clone()
+                .join_group("jobs".into(), "workers".into(), "w1".into(), Start::Latest);
+        recv(&mut rx).await;
+        publish(&b, "jobs", 1).await;
+        assert_eq!(recv(&mut rx).await.sequence, 1);
+
+        let acked = other.ack("jobs".into(), "workers".into(), vec![1]);
+        assert_eq!(acked.unwrap(), 0);
+        let acked = worker.ack("jobs".into(), "workers".into(), vec![1]);
+        assert_eq!(acked.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn dead_letters_should_be_kept_without_subscribers() {
+        let config = GroupConfig::new()
+            .visibility_timeout(Duration::from_millis(20))
+            .max_attempts(1);
+        let b = Arc::new(
+            PubSub::new()
+                .durable("jobs", Retention::new())
+                .consumer_groups(config),
+        );
+        let mut worker =
+            b.clone()
+                .join_group("jobs".into(), "workers".into(), "w1".into(), Start::Latest);
+        recv(&mut worker).await;
+        publish(&b, "jobs", 1).await;
+        recv(&mut worker).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let name = format!("jobs{}", DEAD_LETTER_SUFFIX);
+        let mut dead = b.clone().subscribe_from(name, Start::Earliest);
+        recv(&mut dead).await;
+        let res = recv(&mut dead).await;
+        assert_res_ok(res.as_ref().clone(), &[1i64.into()], &[]);
+        assert_eq!(res.topic, "jobs");
+    }
+
+    #[tokio::test]
+    async fn group_should_be_dropped_with_its_last_member() {
+        let b = Arc::new(PubSub::new().durable("jobs", Retention::new()));
+        let mut rx =
+            b.clone()
+                .join_group("jobs".into(), "workers".into(), "w1".into(), Start::Latest);
+        let id: i64 = recv(&mut rx).await.as_ref().try_into().unwrap();
+        assert!(b
+            .clone()
+            .pending("jobs".into(), "workers".into(), None)
+            .is_ok());
+
+        b.clone().unsubscribe("jobs".into(), id as u32).unwrap();
+        let res = b.clone().pending("jobs".into(), "workers".into(), None);
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn pending_messages_should_wait_for_members_to_come_back() {
+        let b = Arc::new(PubSub::new().durable("jobs", Retention::new()));
+        let worker = Connection::new(b.clone(), 1);
+        let mut rx =
+            worker
+                .clone()
+                .join_group("jobs".into(), "workers".into(), "w1".into(), Start::Latest);
+        recv(&mut rx).await;
+        publish(&b, "jobs", 1).await;
+        assert_eq!(recv(&mut rx).await.sequence, 1);
+        // the only member disconnects without acknowledging
+        drop(rx);
+        publish(&b, "jobs", 2).await;
+
+        let worker = Connection::new(b.clone(), 2);
+        let mut rx =
+            worker
+                .clone()
+                .join_group("jobs".into(), "workers".into(), "w1".into(), Start::Latest);
+        recv(&mut rx).await;
+        let res = recv(&mut rx).await;
+        assert_eq!(
+            (res.sequence, res.values.as_slice()),
+            (1, &[1i64.into()][..])
+        );
+        assert_eq!(recv(&mut rx).await.sequence, 2);
+        let acked = worker.ack("jobs".into(), "workers".into(), vec![1, 2]);
+        assert_eq!(acked.unwrap(), 2);
+    }
+}
@@ -0,0 +1,375 @@
+use crate::{
+    CommandRequest, CommandResponse, KvError, Middleware, Next, Session, StreamingResponse,
+};
+use dashmap::{mapref::entry::Entry as MapEntry, DashMap};
+use futures::{future::BoxFuture, stream, FutureExt, StreamExt};
+use prost::Message;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::watch;
+
+/// Keys remembered by default before the oldest get evicted
+const MAX_KEYS: usize = 65536;
+
+/// Commands that change state, the only ones a key is honored for
+const MUTATING: [&str; 6] = ["hset", "hmset", "hdel", "hmdel", "publish", "custom"];
+
+type Outcome = watch::Receiver<Option<Arc<CommandResponse>>>;
+
+#[derive(Debug)]
+struct Entry {
+    id: u64,
+    fingerprint: [u8; 32],
+    at: Instant,
+    outcome: Outcome,
+}
+
+/// Middleware replaying the response of a write when a client retries it with
+/// the same `idempotency_key`, for `window` after the first attempt. Keys are
+/// scoped to the principal and namespace, so it belongs after the
+/// `Authenticator`. Without a principal they are scoped to the peer address,
+/// or to the connection if that is unknown.
+///
+/// Expired keys are dropped as newer ones come in, and the oldest are
+/// evicted early once `max_keys` are remembered.
+///
+/// A retry arriving while the first attempt still runs waits for its
+/// response. Reusing a key for a different request is rejected, and responses
+/// asking the client to retry later (a 429 or 503) are not remembered.
+#[derive(Debug)]
+pub struct Idempotency {
+    window: Duration,
+    max_keys: usize,
+    entries: DashMap<String, Entry>,
+    /// Keys and entry ids in the order they were remembered
+    order: Mutex<VecDeque<(Instant, String, u64)>>,
+    next_id: AtomicU64,
+}
+
+impl Idempotency {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            max_keys: MAX_KEYS,
+            entries: DashMap::new(),
+            order: Mutex::new(VecDeque::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Remember at most `max_keys` keys.
+    pub fn max_keys(mut self, max_keys: usize) -> Self {
+        self.max_keys = max_keys.max(1);
+        self
+    }
+
+    /// Keys still remembered, expired ones not yet dropped included.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Forget expired keys, and the oldest ones until there is room for
+    /// another if `room` is asked for.
+    fn evict(&self, now: Instant, room: bool) {
+        let mut order = self.order.lock().unwrap();
+        while let Some((at, key, id)) = order.front() {
+            let full = room && self.entries.len() >= self.max_keys;
+            if now.duration_since(*at) < self.window && !full {
+                break;
+            }
+            // the key may have been forgotten or reused since
+            self.entries.remove_if(key, |_, e| e.id == *id);
+            order.pop_front();
+        }
+    }
+}
+
+/// Who a key belongs to besides its namespace.
+fn owner(session: &Arc<Session>) -> String {
+    match (session.principal(), session.peer_addr()) {
+        (Some(principal), _) => format!("principal {}", principal),
+        (None, Some(addr)) => format!("peer {}", addr.ip()),
+        (None, None) => format!("connection {:p}", Arc::as_ptr(session)),
+    }
+}
+
+/// What the request does, leaving out how it is identified.
+fn fingerprint(req: &CommandRequest) -> [u8; 32] {
+    let mut req = req.clone();
+    req.request_id = 0;
+    req.idempotency_key.clear();
+    Sha256::digest(req.encode_to_vec()).into()
+}
+
+fn replay(res: &CommandResponse) -> StreamingResponse {
+    let mut res = res.clone();
+    res.replayed = true;
+    res.into()
+}
+
+fn reject(e: KvError) -> BoxFuture<'static, StreamingResponse> {
+    let res: StreamingResponse = CommandResponse::from(e).into();
+    async move { res }.boxed()
+}
+
+/// Forgets a key whose first attempt ended without a response to remember.
+struct Pending<'a> {
+    entries: &'a DashMap<String, Entry>,
+    key: String,
+    id: u64,
+    remembered: bool,
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        if !self.remembered {
+            self.entries.remove_if(&self.key, |_, e| e.id == self.id);
+        }
+    }
+}
+
+impl Middleware for Idempotency {
+    fn call<'a>(&'a self, req: CommandRequest, next: Next<'a>) -> BoxFuture<'a, StreamingResponse> {
+        let name = req.request_data.as_ref().map(|d| d.name());
+        if req.idempotency_key.is_empty() || !name.is_some_and(|n| MUTATING.contains(&n)) {
+            return next.run(req);
+        }
+
+        let session = next.session();
+        let key = format!(
+            "{}\n{}\n{}",
+            owner(session),
+            session.namespace().unwrap_or_default(),
+            req.idempotency_key
+        );
+        let fingerprint = fingerprint(&req);
+        let now = Instant::now();
+        self.evict(now, !self.entries.contains_key(&key));
+
+        let (tx, id) = match self.entries.entry(key.clone()) {
+            MapEntry::Occupied(e) if now.duration_since(e.get().at) < self.window => {
+                if e.get().fingerprint != fingerprint {
+                    return reject(KvError::InvalidCommand(format!(
+                        "idempotency key `{}` was used for a different request",
+                        req.idempotency_key
+                    )));
+                }
+                let mut outcome = e.get().outcome.clone();
+                drop(e);
+                return async move {
+                    let done = match outcome.wait_for(Option::is_some).await {
+                        Ok(res) => res.clone(),
+                        Err(_) => None,
+                    };
+                    match done {
+                        Some(res) => replay(&res),
+                        // the first attempt was dropped or not remembered
+                        None => self.call(req, next).await,
+                    }
+                }
+                .boxed();
+            }
+            e => {
+                let (tx, outcome) = watch::channel(None);
+                let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+                let entry = Entry {
+                    id,
+                    fingerprint,
+                    at: now,
+                    outcome,
+                };
+                match e {
+                    MapEntry::Occupied(mut e) => {
+                        e.insert(entry);
+                    }
+                    MapEntry::Vacant(e) => {
+                        e.insert(entry);
+                    }
+                }
+                (tx, id)
+            }
+        };
+        let order = (now, key.clone(), id);
+        self.order.lock().unwrap().push_back(order);
+
+        let mut pending = Pending {
+            entries: &self.entries,
+            key,
+            id,
+            remembered: false,
+        };
+        async move {
+            let mut res = next.run(req).await;
+            let Some(first) = res.next().await else {
+                return Box::pin(stream::empty()) as StreamingResponse;
+            };
+            if first.status != 429 && first.status != 503 && first.retry_after_ms == 0 {
+                pending.remembered = true;
+                let _ = tx.send(Some(Arc::clone(&first)));
+            }
+            drop(pending);
+            Box::pin(stream::once(async move { first }).chain(res)) as StreamingResponse
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assert_res_ok, assert_res_ref_error, Context, MemTable, Service, ServiceInner, Session,
+        Value,
+    };
+    use std::sync::atomic::AtomicI64;
+
+    async fn run(
+        service: &Service,
+        cmd: CommandRequest,
+        session: &Arc<Session>,
+    ) -> CommandResponse {
+        let mut res = service.execute(cmd, session);
+        res.next().await.unwrap().as_ref().clone()
+    }
+
+    fn counter_service(window: Duration) -> (Service, Arc<AtomicI64>) {
+        let counter = Arc::new(AtomicI64::new(0));
+        let calls = Arc::clone(&counter);
+        let service = ServiceInner::new(MemTable::new())
+            .layer(Idempotency::new(window))
+            .command("incr", move |_args: Vec<Value>, _ctx: Context<MemTable>| {
+                let calls = Arc::clone(&calls);
+                async move {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    Ok::<_, KvError>(Value::from(calls.fetch_add(1, Ordering::SeqCst) + 1))
+                }
+            })
+            .into();
+        (service, counter)
+    }
+
+    #[tokio::test]
+    async fn duplicates_should_replay_the_first_response() {
+        let (service, counter) = counter_service(Duration::from_secs(60));
+        let session = Arc::new(Session::new());
+
+        let cmd = CommandRequest::new_custom("incr", vec![]).with_idempotency_key("k1");
+        let res = run(&service, cmd.clone(), &session).await;
+        assert!(!res.replayed);
+        // concurrent and later retries alike
+        let (a, b) = tokio::join!(
+            run(&service, cmd.clone(), &session),
+            run(&service, cmd.clone(), &session)
+        );
+        assert!(a.replayed && b.replayed);
+        assert_res_ok(a, &[1i64.into()], &[]);
+        assert_res_ok(b, &[1i64.into()], &[]);
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+
+        // other keys, and requests without one, run again
+        let other = CommandRequest::new_custom("incr", vec![]).with_idempotency_key("k2");
+        assert_res_ok(run(&service, other, &session).await, &[2i64.into()], &[]);
+        let plain = CommandRequest::new_custom("incr", vec![]);
+        assert_res_ok(run(&service, plain, &session).await, &[3i64.into()], &[]);
+
+        // keys are per principal
+        let alice = Arc::new(Session::new());
+        alice.set_principal("alice");
+        assert_res_ok(run(&service, cmd, &alice).await, &[4i64.into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn concurrent_duplicates_should_run_once() {
+        let (service, counter) = counter_service(Duration::from_secs(60));
+        let session = Arc::new(Session::new());
+
+        let cmd = CommandRequest::new_custom("incr", vec![]).with_idempotency_key("k1");
+        let (a, b) = tokio::join!(
+            run(&service, cmd.clone(), &session),
+            run(&service, cmd, &session)
+        );
+        assert_eq!(a.values, b.values);
+        assert!(a.replayed != b.replayed);
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn keys_should_expire_and_not_be_reused() {
+        let (service, counter) = counter_service(Duration::from_millis(50));
+        let session = Arc::new(Session::new());
+
+        let cmd = CommandRequest::new_custom("incr", vec![]).with_idempotency_key("k1");
+        run(&service, cmd.clone(), &session).await;
+        let other =
+            CommandRequest::new_custom("incr", vec![1i64.into()]).with_idempotency_key("k1");
+        let res = run(&service, other, &session).await;
+        assert_res_ref_error(
+            &res,
+            400,
+            "idempotency key `k1` was used for a different request",
+        );
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        let res = run(&service, cmd, &session).await;
+        assert!(!res.replayed);
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn dropped_attempts_should_not_be_remembered() {
+        let (service, counter) = counter_service(Duration::from_secs(60));
+        let session = Arc::new(Session::new());
+
+        let cmd = CommandRequest::new_custom("incr", vec![]).with_idempotency_key("k1");
+        let res = service.execute(cmd.clone(), &session);
+        drop(res);
+        assert_res_ok(run(&service, cmd, &session).await, &[1i64.into()], &[]);
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn anonymous_keys_should_be_scoped_to_the_peer() {
+        let (service, counter) = counter_service(Duration::from_secs(60));
+        let cmd = CommandRequest::new_custom("incr", vec![]).with_idempotency_key("k1");
+
+        let a = Arc::new(Session::with_peer_addr("10.0.0.1:1000".parse().unwrap()));
+        assert_res_ok(run(&service, cmd.clone(), &a).await, &[1i64.into()], &[]);
+        // a retry over a new connection from the same host
+        let a = Arc::new(Session::with_peer_addr("10.0.0.1:1001".parse().unwrap()));
+        assert!(run(&service, cmd.clone(), &a).await.replayed);
+
+        let b = Arc::new(Session::with_peer_addr("10.0.0.2:1000".parse().unwrap()));
+        assert_res_ok(run(&service, cmd.clone(), &b).await, &[2i64.into()], &[]);
+        let (c, d) = (Arc::new(Session::new()), Arc::new(Session::new()));
+        assert_res_ok(run(&service, cmd.clone(), &c).await, &[3i64.into()], &[]);
+        assert_res_ok(run(&service, cmd, &d).await, &[4i64.into()], &[]);
+        assert_eq!(counter.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn oldest_keys_should_be_evicted_first() {
+        let idempotency = Idempotency::new(Duration::from_secs(60)).max_keys(2);
+        let service: Service = ServiceInner::new(MemTable::new()).layer(idempotency).into();
+        let session = Arc::new(Session::new());
+        let hset =
+            |key: &str| CommandRequest::new_hset("t1", key, "v".into()).with_idempotency_key(key);
+
+        for key in ["k1", "k2", "k3"] {
+            assert!(!run(&service, hset(key), &session).await.replayed);
+        }
+        assert!(run(&service, hset("k3"), &session).await.replayed);
+        assert!(run(&service, hset("k2"), &session).await.replayed);
+        assert!(!run(&service, hset("k1"), &session).await.replayed);
+    }
+}
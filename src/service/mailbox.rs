@@ -0,0 +1,386 @@
+use crate::{CommandResponse, KvError};
+use futures::{stream, Stream};
+use std::{
+    collections::VecDeque,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{sync::Notify, time::Instant};
+
+/// What a subscription does with a message published while its buffer is
+/// full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Overflow {
+    /// Make the publisher wait for room, for a while only: a subscriber still
+    /// full by then is disconnected
+    Block,
+    /// Drop the oldest buffered message to make room
+    #[default]
+    DropOldest,
+    /// Drop the message
+    DropNewest,
+    /// Drop the message and end the subscription
+    Disconnect,
+}
+
+impl FromStr for Overflow {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(Self::Block),
+            "drop-oldest" => Ok(Self::DropOldest),
+            "drop-newest" => Ok(Self::DropNewest),
+            "disconnect" => Ok(Self::Disconnect),
+            _ => Err(KvError::ConfigError(format!(
+                "unknown overflow policy `{}`, expect block, drop-oldest, drop-newest or disconnect",
+                s
+            ))),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Item {
+    Message(Arc<CommandResponse>),
+    /// Messages dropped at this point of the queue
+    Gap(u64),
+}
+
+#[derive(Debug, Default)]
+struct State {
+    queue: VecDeque<Item>,
+    /// Messages in the queue, gaps left out
+    len: usize,
+    sender_closed: bool,
+    receiver_closed: bool,
+    /// A blocked sender gave up waiting for room
+    stalled: bool,
+}
+
+impl State {
+    fn push_gap(&mut self) {
+        match self.queue.back_mut() {
+            Some(Item::Gap(n)) => *n += 1,
+            _ => self.queue.push_back(Item::Gap(1)),
+        }
+    }
+
+    /// Drop the oldest message, folding it and any gap before it into one
+    /// gap at the front.
+    fn drop_oldest(&mut self) {
+        let mut dropped = 0;
+        while let Some(item) = self.queue.pop_front() {
+            match item {
+                Item::Gap(n) => dropped += n,
+                Item::Message(_) => {
+                    self.len -= 1;
+                    dropped += 1;
+                    break;
+                }
+            }
+        }
+        if let Some(Item::Gap(n)) = self.queue.front_mut() {
+            *n += dropped;
+        } else {
+            self.queue.push_front(Item::Gap(dropped));
+        }
+    }
+}
+
+/// What sending to a mailbox came to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Sent {
+    Delivered,
+    /// The buffer was full and a message was dropped for it
+    Dropped,
+    /// The buffer was full and the subscription is over
+    Disconnected,
+    /// The subscriber is gone
+    Closed,
+}
+
+/// The buffer of one subscription, bounded to `capacity` messages. Drops are
+/// reported to the subscriber as a response with `gap` set, where they
+/// happened in the stream.
+#[derive(Debug)]
+pub(crate) struct Mailbox {
+    state: Mutex<State>,
+    capacity: usize,
+    overflow: Overflow,
+    /// How long a blocked sender waits for room
+    patience: Duration,
+    readable: Notify,
+    writable: Notify,
+}
+
+impl Mailbox {
+    pub(crate) fn channel(
+        capacity: usize,
+        overflow: Overflow,
+        patience: Duration,
+    ) -> (Sender, Subscriber) {
+        let mailbox = Arc::new(Mailbox {
+            state: Default::default(),
+            capacity: capacity.max(1),
+            overflow,
+            patience,
+            readable: Notify::new(),
+            writable: Notify::new(),
+        });
+        (Sender(Arc::clone(&mailbox)), Subscriber(mailbox))
+    }
+
+    /// Buffer `message`, applying the overflow policy if there is no room.
+    pub(crate) async fn send(&self, message: Arc<CommandResponse>) -> Sent {
+        if self.overflow == Overflow::Block {
+            self.reserve().await;
+        }
+        self.push(message)
+    }
+
+    /// Wait until the buffer has room, or the subscription is over. Never
+    /// waits unless the policy is to block, and then no longer than the
+    /// patience of the mailbox, the next message disconnecting a subscriber
+    /// still full by then.
+    pub(crate) async fn reserve(&self) {
+        if self.overflow != Overflow::Block {
+            return;
+        }
+        let deadline = Instant::now() + self.patience;
+        loop {
+            let writable = self.writable.notified();
+            tokio::pin!(writable);
+            writable.as_mut().enable();
+            {
+                let state = self.state.lock().unwrap();
+                if state.receiver_closed || state.sender_closed || state.len < self.capacity {
+                    return;
+                }
+            }
+            if tokio::time::timeout_at(deadline, writable).await.is_err() {
+                self.state.lock().unwrap().stalled = true;
+                return;
+            }
+        }
+    }
+
+    /// Buffer `message` without waiting, applying the overflow policy if
+    /// there is no room. A blocking mailbox takes it past the capacity, its
+    /// sender having waited for room with `reserve`, unless it gave up.
+    pub(crate) fn push(&self, message: Arc<CommandResponse>) -> Sent {
+        let mut state = self.state.lock().unwrap();
+        if state.receiver_closed || state.sender_closed {
+            return Sent::Closed;
+        }
+        let sent = match self.overflow {
+            _ if state.len < self.capacity => Sent::Delivered,
+            // past the capacity, the sender having waited for room already
+            Overflow::Block if !state.stalled => Sent::Delivered,
+            Overflow::DropOldest => {
+                state.drop_oldest();
+                Sent::Dropped
+            }
+            Overflow::DropNewest => Sent::Dropped,
+            Overflow::Disconnect | Overflow::Block => {
+                state.sender_closed = true;
+                Sent::Disconnected
+            }
+        };
+        if sent == Sent::Delivered || self.overflow == Overflow::DropOldest {
+            state.queue.push_back(Item::Message(message));
+            state.len += 1;
+        } else {
+            state.push_gap();
+        }
+        drop(state);
+        self.readable.notify_one();
+        sent
+    }
+
+    /// Buffer `message` if there is room, whatever the overflow policy.
+    pub(crate) fn try_send(&self, message: Arc<CommandResponse>) -> Result<(), Sent> {
+        let mut state = self.state.lock().unwrap();
+        if state.receiver_closed || state.sender_closed {
+            return Err(Sent::Closed);
+        }
+        if state.len >= self.capacity {
+            return Err(Sent::Dropped);
+        }
+        state.queue.push_back(Item::Message(message));
+        state.len += 1;
+        drop(state);
+        self.readable.notify_one();
+        Ok(())
+    }
+
+    /// Buffer a control message, such as the subscription id, past the
+    /// capacity.
+    pub(crate) fn force(&self, message: Arc<CommandResponse>) {
+        let mut state = self.state.lock().unwrap();
+        state.queue.push_back(Item::Message(message));
+        state.len += 1;
+        drop(state);
+        self.readable.notify_one();
+    }
+
+    /// Whether the subscription is over, from either end.
+    pub(crate) fn is_closed(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.receiver_closed || state.sender_closed
+    }
+
+    /// Messages buffered but not yet taken by the subscriber.
+    pub(crate) fn lag(&self) -> usize {
+        self.state.lock().unwrap().len
+    }
+}
+
+/// The sending end of a subscription, ending it when dropped.
+#[derive(Debug)]
+pub(crate) struct Sender(Arc<Mailbox>);
+
+impl Sender {
+    pub(crate) fn mailbox(&self) -> Arc<Mailbox> {
+        Arc::clone(&self.0)
+    }
+}
+
+impl std::ops::Deref for Sender {
+    type Target = Mailbox;
+
+    fn deref(&self) -> &Mailbox {
+        &self.0
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().sender_closed = true;
+        self.0.readable.notify_one();
+        // a publisher waiting for room has none to wait for any more
+        self.0.writable.notify_waiters();
+    }
+}
+
+/// The receiving end of a subscription. The messages buffered before the
+/// subscription ended are still received.
+#[derive(Debug)]
+pub struct Subscriber(Arc<Mailbox>);
+
+impl Subscriber {
+    pub async fn recv(&mut self) -> Option<Arc<CommandResponse>> {
+        loop {
+            let readable = self.0.readable.notified();
+            tokio::pin!(readable);
+            readable.as_mut().enable();
+            {
+                let mut state = self.0.state.lock().unwrap();
+                match state.queue.pop_front() {
+                    Some(Item::Message(message)) => {
+                        state.len -= 1;
+                        drop(state);
+                        self.0.writable.notify_one();
+                        return Some(message);
+                    }
+                    Some(Item::Gap(n)) => return Some(Arc::new(CommandResponse::gap(n))),
+                    None if state.sender_closed => return None,
+                    None => {}
+                }
+            }
+            readable.await;
+        }
+    }
+
+    pub fn into_stream(self) -> impl Stream<Item = Arc<CommandResponse>> + Send + 'static {
+        stream::unfold(self, |mut rx| async move {
+            rx.recv().await.map(|res| (res, rx))
+        })
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().receiver_closed = true;
+        self.0.writable.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Value;
+
+    const PATIENCE: Duration = Duration::from_secs(5);
+
+    fn message(n: i64) -> Arc<CommandResponse> {
+        Arc::new(Value::from(n).into())
+    }
+
+    /// The values received until the buffer is empty, gaps as negative
+    /// numbers.
+    async fn drain(rx: &mut Subscriber) -> Vec<i64> {
+        let mut received = vec![];
+        while rx.0.state.lock().unwrap().queue.front().is_some() {
+            let res = rx.recv().await.unwrap();
+            received.push(match res.gap {
+                0 => res.as_ref().try_into().unwrap(),
+                n => -(n as i64),
+            });
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn overflow_should_follow_policy() {
+        let (tx, mut rx) = Mailbox::channel(2, Overflow::DropNewest, PATIENCE);
+        for n in 1..=4 {
+            tx.send(message(n)).await;
+        }
+        assert_eq!(tx.lag(), 2);
+        assert_eq!(drain(&mut rx).await, [1, 2, -2]);
+
+        let (tx, mut rx) = Mailbox::channel(2, Overflow::DropOldest, PATIENCE);
+        for n in 1..=4 {
+            tx.send(message(n)).await;
+        }
+        assert_eq!(drain(&mut rx).await, [-2, 3, 4]);
+
+        let (tx, mut rx) = Mailbox::channel(2, Overflow::Disconnect, PATIENCE);
+        for n in 1..=3 {
+            tx.send(message(n)).await;
+        }
+        assert_eq!(tx.send(message(4)).await, Sent::Closed);
+        assert_eq!(drain(&mut rx).await, [1, 2, -1]);
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn block_should_wait_for_room() {
+        let (tx, mut rx) = Mailbox::channel(1, Overflow::Block, PATIENCE);
+        tx.send(message(1)).await;
+        let blocked = tokio::spawn(async move {
+            tx.send(message(2)).await;
+            tx
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!blocked.is_finished());
+
+        assert_eq!(drain(&mut rx).await, [1]);
+        let tx = blocked.await.unwrap();
+        assert_eq!(drain(&mut rx).await, [2]);
+        drop(tx);
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn block_should_disconnect_once_out_of_patience() {
+        let (tx, mut rx) = Mailbox::channel(1, Overflow::Block, Duration::from_millis(20));
+        tx.send(message(1)).await;
+        let sent = tokio::time::timeout(Duration::from_secs(1), tx.send(message(2)));
+        assert_eq!(sent.await.unwrap(), Sent::Disconnected);
+        assert_eq!(drain(&mut rx).await, [1, -1]);
+        assert!(rx.recv().await.is_none());
+    }
+}
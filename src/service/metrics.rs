@@ -0,0 +1,241 @@
+use crate::{service::stats::LATENCY_BUCKETS, AsyncStorage, Service, FRAME_STATS};
+use std::fmt::{Display, Write};
+use tracing::warn;
+
+impl<Store: AsyncStorage> Service<Store> {
+    /// Render the server metrics in the Prometheus text format.
+    pub async fn metrics(&self) -> String {
+        let stats = &self.inner.stats;
+        let mut out = Metrics::default();
+
+        out.metric(
+            "kv_uptime_seconds",
+            "gauge",
+            "Seconds since the service started",
+        );
+        out.sample("kv_uptime_seconds", &[], stats.uptime().as_secs());
+
+        out.metric("kv_connections", "gauge", "Open connections by transport");
+        for e in stats.clients.iter() {
+            out.sample("kv_connections", &[("transport", e.key())], e.value());
+        }
+
+        out.metric("kv_commands_total", "counter", "Commands received");
+        for e in stats.commands.iter() {
+            out.sample("kv_commands_total", &[("command", e.key())], e.value());
+        }
+
+        out.metric("kv_errors_total", "counter", "Error responses by status");
+        for e in stats.errors.iter() {
+            out.sample(
+                "kv_errors_total",
+                &[("status", &e.key().to_string())],
+                e.value(),
+            );
+        }
+
+        let name = "kv_command_duration_seconds";
+        out.metric(
+            name,
+            "histogram",
+            "Time until the first response of a command",
+        );
+        for e in stats.latency.iter() {
+            let (command, h) = (*e.key(), e.value());
+            let mut cumulative = 0;
+            for (bound, n) in LATENCY_BUCKETS.iter().zip(h.buckets) {
+                cumulative += n;
+                let le = bound.to_string();
+                out.sample(
+                    "kv_command_duration_seconds_bucket",
+                    &[("command", command), ("le", &le)],
+                    cumulative,
+                );
+            }
+            out.sample(
+                "kv_command_duration_seconds_bucket",
+                &[("command", command), ("le", "+Inf")],
+                h.count,
+            );
+            out.sample(
+                "kv_command_duration_seconds_sum",
+                &[("command", command)],
+                h.sum,
+            );
+            out.sample(
+                "kv_command_duration_seconds_count",
+                &[("command", command)],
+                h.count,
+            );
+        }
+
+        let frames = &FRAME_STATS;
+        let directions = [
+            ("in", frames.message_bytes_in(), frames.wire_bytes_in()),
+            ("out", frames.message_bytes_out(), frames.wire_bytes_out()),
+        ];
+        out.metric(
+            "kv_frame_message_bytes_total",
+            "counter",
+            "Encoded message bytes",
+        );
+        for (direction, message, _) in directions {
+            out.sample(
+                "kv_frame_message_bytes_total",
+                &[("direction", direction)],
+                message,
+            );
+        }
+        out.metric(
+            "kv_frame_wire_bytes_total",
+            "counter",
+            "Frame bytes after compression",
+        );
+        for (direction, _, wire) in directions {
+            out.sample(
+                "kv_frame_wire_bytes_total",
+                &[("direction", direction)],
+                wire,
+            );
+        }
+        out.metric(
+            "kv_frame_compression_ratio",
+            "gauge",
+            "Message bytes per wire byte",
+        );
+        for (direction, message, wire) in directions {
+            let ratio = if wire == 0 {
+                1.0
+            } else {
+                message as f64 / wire as f64
+            };
+            out.sample(
+                "kv_frame_compression_ratio",
+                &[("direction", direction)],
+                ratio,
+            );
+        }
+
+        let pubsub = &self.broadcaster;
+        out.metric(
+            "kv_pubsub_topics",
+            "gauge",
+            "Topics with at least one subscriber",
+        );
+        out.sample("kv_pubsub_topics", &[], pubsub.topics());
+        out.metric(
+            "kv_pubsub_patterns",
+            "gauge",
+            "Topic patterns with at least one subscriber",
+        );
+        out.sample("kv_pubsub_patterns", &[], pubsub.patterns());
+        out.metric("kv_pubsub_subscriptions", "gauge", "Open subscriptions");
+        out.sample("kv_pubsub_subscriptions", &[], pubsub.subscriptions());
+        out.metric("kv_pubsub_published_total", "counter", "Messages published");
+        out.sample("kv_pubsub_published_total", &[], pubsub.published());
+        out.metric(
+            "kv_pubsub_delivered_total",
+            "counter",
+            "Messages handed to a subscriber",
+        );
+        out.sample("kv_pubsub_delivered_total", &[], pubsub.delivered());
+        out.metric(
+            "kv_pubsub_dropped_total",
+            "counter",
+            "Messages lost to a gone subscriber",
+        );
+        out.sample("kv_pubsub_dropped_total", &[], pubsub.dropped());
+        out.metric(
+            "kv_pubsub_overflowed_total",
+            "counter",
+            "Messages lost to a full subscriber buffer",
+        );
+        out.sample("kv_pubsub_overflowed_total", &[], pubsub.overflowed());
+        let max_lag = pubsub.lags().into_iter().map(|(_, lag)| lag).max();
+        out.metric(
+            "kv_pubsub_max_lag",
+            "gauge",
+            "Most messages buffered for one subscription",
+        );
+        out.sample("kv_pubsub_max_lag", &[], max_lag.unwrap_or_default());
+
+        match self.inner.store.key_counts().await {
+            Ok(counts) => {
+                out.metric("kv_table_keys", "gauge", "Keys held by each table");
+                for (table, n) in counts {
+                    out.sample("kv_table_keys", &[("table", &table)], n);
+                }
+            }
+            Err(e) => warn!("failed to count keys for metrics: {}", e),
+        }
+
+        out.0
+    }
+}
+
+#[derive(Debug, Default)]
+struct Metrics(String);
+
+impl Metrics {
+    fn metric(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP {} {}", name, help);
+        let _ = writeln!(self.0, "# TYPE {} {}", name, kind);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        self.0.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<_> = labels
+                .iter()
+                .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+                .collect();
+            let _ = write!(self.0, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.0, " {}", value);
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CommandRequest, MemTable, Session};
+    use futures::StreamExt;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn metrics_should_render_prometheus_text() {
+        let service = Service::new(MemTable::new());
+        let session = Arc::new(Session::new().with_transport("quic"));
+        let _client = service.connect(&session);
+        for cmd in [
+            CommandRequest::new_hset("t\"1", "k1", "v1".into()),
+            CommandRequest::new_hget("t\"1", "k1"),
+            CommandRequest::new_hget("t\"1", "k2"),
+        ] {
+            service.execute(cmd, &session).next().await.unwrap();
+        }
+
+        let text = service.metrics().await;
+        assert!(text.contains("# TYPE kv_command_duration_seconds histogram\n"));
+        assert!(text.contains("kv_connections{transport=\"quic\"} 1\n"));
+        assert!(text.contains("kv_commands_total{command=\"hget\"} 2\n"));
+        assert!(text.contains("kv_errors_total{status=\"404\"} 1\n"));
+        assert!(
+            text.contains("kv_command_duration_seconds_bucket{command=\"hget\",le=\"+Inf\"} 2\n")
+        );
+        assert!(text.contains("kv_command_duration_seconds_count{command=\"hget\"} 2\n"));
+        assert!(text.contains("kv_table_keys{table=\"t\\\"1\"} 1\n"));
+    }
+
+    #[test]
+    fn escape_should_quote_label_values() {
+        assert_eq!(escape("a\\b\"c\nd"), "a\\\\b\\\"c\\nd");
+    }
+}
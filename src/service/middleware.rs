@@ -0,0 +1,209 @@
+use crate::{CommandRequest, CommandResponse, Hook, HookMut, Session, StreamingResponse};
+use futures::{future::BoxFuture, FutureExt, StreamExt};
+use std::sync::Arc;
+
+/// A layer around `Service::execute`.
+///
+/// A middleware gets the request together with the rest of the pipeline as
+/// `next`. It may inspect or modify the request before passing it on, reject
+/// or answer it without calling `next` at all, await anything along the way,
+/// and wrap the returned `StreamingResponse` to see (or rewrite) every
+/// response, including messages of a subscription.
+pub trait Middleware: Send + Sync + 'static {
+    fn call<'a>(&'a self, req: CommandRequest, next: Next<'a>) -> BoxFuture<'a, StreamingResponse>;
+}
+
+type Endpoint<'a> = &'a (dyn Fn(CommandRequest) -> StreamingResponse + Send + Sync);
+
+/// The remaining layers of the pipeline, ending with the command dispatch.
+pub struct Next<'a> {
+    layers: &'a [Box<dyn Middleware>],
+    endpoint: Endpoint<'a>,
+    session: &'a Arc<Session>,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(
+        layers: &'a [Box<dyn Middleware>],
+        endpoint: Endpoint<'a>,
+        session: &'a Arc<Session>,
+    ) -> Self {
+        Self {
+            layers,
+            endpoint,
+            session,
+        }
+    }
+
+    /// The connection the request came from.
+    pub fn session(&self) -> &'a Arc<Session> {
+        self.session
+    }
+
+    /// Pass the request to the next layer.
+    pub fn run(self, req: CommandRequest) -> BoxFuture<'a, StreamingResponse> {
+        match self.layers.split_first() {
+            Some((layer, layers)) => layer.call(
+                req,
+                Next {
+                    layers,
+                    endpoint: self.endpoint,
+                    session: self.session,
+                },
+            ),
+            None => {
+                let res = (self.endpoint)(req);
+                async move { res }.boxed()
+            }
+        }
+    }
+}
+
+/// Run a `Hook` on every request before passing it on.
+pub struct OnRequest<H>(pub H);
+
+impl<H> Middleware for OnRequest<H>
+where
+    H: Hook<CommandRequest> + Send + Sync + 'static,
+{
+    fn call<'a>(&'a self, req: CommandRequest, next: Next<'a>) -> BoxFuture<'a, StreamingResponse> {
+        self.0.hook(&req);
+        next.run(req)
+    }
+}
+
+/// Run a `HookMut` on every response, streamed ones included.
+pub struct OnResponse<H>(pub Arc<H>);
+
+impl<H> Middleware for OnResponse<H>
+where
+    H: HookMut<CommandResponse> + Send + Sync + 'static,
+{
+    fn call<'a>(&'a self, req: CommandRequest, next: Next<'a>) -> BoxFuture<'a, StreamingResponse> {
+        let hook = Arc::clone(&self.0);
+        async move {
+            let res = next.run(req).await;
+            let res: StreamingResponse = Box::pin(res.map(move |mut res| {
+                hook.hook(Arc::make_mut(&mut res));
+                res
+            }));
+            res
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assert_res_ref_error, assert_res_ref_ok, KvError, MemTable, Service, ServiceInner,
+    };
+    use http::StatusCode;
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+    use tokio::time;
+
+    struct Deny;
+
+    impl Middleware for Deny {
+        fn call<'a>(
+            &'a self,
+            req: CommandRequest,
+            next: Next<'a>,
+        ) -> BoxFuture<'a, StreamingResponse> {
+            async move {
+                if let Some(crate::command_request::RequestData::Hdel(_)) = req.request_data {
+                    let res: CommandResponse = KvError::InvalidCommand("hdel denied".into()).into();
+                    return res.into();
+                }
+                next.run(req).await
+            }
+            .boxed()
+        }
+    }
+
+    struct Slow(AtomicUsize);
+
+    impl Middleware for Slow {
+        fn call<'a>(
+            &'a self,
+            req: CommandRequest,
+            next: Next<'a>,
+        ) -> BoxFuture<'a, StreamingResponse> {
+            async move {
+                time::sleep(Duration::from_millis(10)).await;
+                self.0.fetch_add(1, Ordering::SeqCst);
+                next.run(req).await
+            }
+            .boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn middleware_should_short_circuit() {
+        let service: Service = ServiceInner::new(MemTable::new()).layer(Deny).into();
+        let session = Arc::default();
+
+        let mut res = service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()), &session);
+        assert_res_ref_ok(&res.next().await.unwrap(), &[Default::default()], &[]);
+
+        let mut res = service.execute(CommandRequest::new_hdel("t1", "k1"), &session);
+        assert_res_ref_error(&res.next().await.unwrap(), 400, "hdel denied");
+
+        // the rejected request never reached the storage
+        let mut res = service.execute(CommandRequest::new_hget("t1", "k1"), &session);
+        assert_res_ref_ok(&res.next().await.unwrap(), &["v1".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn middleware_should_await_in_order() {
+        let slow = Arc::new(Slow(AtomicUsize::new(0)));
+        let seen = Arc::clone(&slow);
+        struct Shared(Arc<Slow>);
+        impl Middleware for Shared {
+            fn call<'a>(
+                &'a self,
+                req: CommandRequest,
+                next: Next<'a>,
+            ) -> BoxFuture<'a, StreamingResponse> {
+                self.0.call(req, next)
+            }
+        }
+
+        let service: Service = ServiceInner::new(MemTable::new())
+            .layer(Shared(slow))
+            .layer(Deny)
+            .into();
+        let session = Arc::default();
+
+        let mut res = service.execute(CommandRequest::new_hdel("t1", "k1"), &session);
+        assert_eq!(res.next().await.unwrap().status, 400);
+        assert_eq!(seen.0.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn on_response_should_see_streaming_responses() {
+        let service: Service = ServiceInner::new(MemTable::new())
+            .before_send_callback(|res: &mut CommandResponse| {
+                res.status = StatusCode::CREATED.as_u16() as _;
+            })
+            .into();
+        let session = Arc::default();
+
+        let mut sub = service.execute(CommandRequest::new_subscribe("lobby"), &session);
+        assert_eq!(sub.next().await.unwrap().status, 201);
+
+        let mut res = service.execute(
+            CommandRequest::new_publish("lobby", vec!["hi".into()]),
+            &session,
+        );
+        assert_eq!(res.next().await.unwrap().status, 201);
+
+        let msg = sub.next().await.unwrap();
+        assert_eq!(msg.status, 201);
+        assert_eq!(msg.values, vec!["hi".into()]);
+    }
+}
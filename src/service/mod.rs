@@ -1,17 +1,55 @@
-use self::topic::PubSub;
-use crate::{CommandRequest, CommandResponse, MemTable, Storage};
+use self::{
+    chunk::{chunk_pairs, CHUNK_SIZE},
+    custom::Handler,
+    mode::Mode,
+    stats::{ClientGuard, Stats},
+    topic::PubSub,
+};
 #[cfg(test)]
-use crate::{Kvpair, Value};
-use futures::{stream, Stream};
-use std::{pin::Pin, sync::Arc};
-use tracing::{debug, instrument};
+use crate::Kvpair;
+use crate::{
+    command_request::RequestData, AsyncStorage, CommandRequest, CommandResponse, KvError, MemTable,
+    ServerInfo, ServerMode, Value,
+};
+use futures::{stream, Future, FutureExt, Stream, StreamExt};
+use std::{collections::HashMap, pin::Pin, sync::Arc, time::Instant};
+use tracing::{debug, instrument, warn};
 
+mod acl;
+mod audit;
+mod auth;
+mod chunk;
 mod command_service;
+mod custom;
+mod group;
+mod idempotency;
+mod mailbox;
+mod metrics;
+mod middleware;
+mod mode;
+mod namespace;
+mod ratelimit;
+mod session;
+mod slowlog;
+mod stats;
 pub mod topic;
 mod topic_service;
 
+pub use acl::{AccessControl, Acl, Effect, Rule};
+pub use audit::{verify as verify_audit_log, AuditLog, AuditSummary};
+pub use auth::{Authenticator, Credentials};
+pub use custom::{Context, SessionStore};
+pub use group::{GroupConfig, DEAD_LETTER_SUFFIX};
+pub use idempotency::Idempotency;
+pub use mailbox::Overflow;
+pub use middleware::{Middleware, Next, OnRequest, OnResponse};
+pub use namespace::{Namespaces, SEPARATOR as NAMESPACE_SEPARATOR};
+pub use ratelimit::{Quota, RateLimiter};
+pub use session::Session;
+pub use slowlog::{Slowlog, DEFAULT_CAPACITY, DEFAULT_THRESHOLD};
+
 pub trait CommandService {
-    fn execute(self, store: &impl Storage) -> CommandResponse;
+    fn execute(self, store: &impl AsyncStorage) -> impl Future<Output = CommandResponse> + Send;
 }
 
 pub type StreamingResponse = Pin<Box<dyn Stream<Item = Arc<CommandResponse>> + Send>>;
@@ -64,16 +102,36 @@ impl<T> HookMut<T> for Vec<fn(&mut T)> {
     }
 }
 
+impl<T, F: Fn(&T)> Hook<T> for F {
+    fn hook(&self, arg: &T) {
+        self(arg)
+    }
+}
+
+impl<T, F: Fn(&mut T)> HookMut<T> for F {
+    fn hook(&self, arg: &mut T) {
+        self(arg)
+    }
+}
+
 pub struct ServiceInner<Store> {
     store: Store,
-    process: Processor<CommandRequest, CommandResponse>,
+    layers: Vec<Box<dyn Middleware>>,
+    commands: HashMap<String, Handler<Store>>,
+    slowlog: Slowlog,
+    stats: Arc<Stats>,
+    namespaces: Namespaces,
+    mode: Mode,
+    /// Handed over to `Service`, which shares it with `Context`
+    pubsub: PubSub,
 }
 
-impl<Store: Storage> From<ServiceInner<Store>> for Service<Store> {
-    fn from(inner: ServiceInner<Store>) -> Self {
+impl<Store: AsyncStorage> From<ServiceInner<Store>> for Service<Store> {
+    fn from(mut inner: ServiceInner<Store>) -> Self {
+        let broadcaster = Arc::new(std::mem::take(&mut inner.pubsub));
         Self {
             inner: Arc::new(inner),
-            broadcaster: Default::default(),
+            broadcaster,
         }
     }
 }
@@ -82,23 +140,67 @@ impl<Store> ServiceInner<Store> {
     pub fn new(store: Store) -> Self {
         Self {
             store,
-            process: Processor::new(),
+            layers: Vec::new(),
+            commands: HashMap::new(),
+            slowlog: Slowlog::default(),
+            stats: Default::default(),
+            namespaces: Namespaces::default(),
+            mode: Mode::default(),
+            pubsub: PubSub::default(),
         }
     }
-    fn received_callback(mut self, c: impl Fn(&CommandRequest) + Send + Sync + 'static) -> Self {
-        self.process.set_callback(c);
+
+    /// Register a handler for `Custom` requests called `name`, replacing any
+    /// previous one. Custom commands go through the middlewares like any
+    /// other, with `custom` as the command and `name` as the resource.
+    pub fn command<F, Fut, R>(mut self, name: impl Into<String>, f: F) -> Self
+    where
+        F: Fn(Vec<Value>, Context<Store>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<R, KvError>> + Send + 'static,
+        R: Into<CommandResponse>,
+    {
+        self.commands.insert(name.into(), custom::handler(f));
         self
     }
-    fn before_send_callback(
-        mut self,
+
+    /// Add a middleware. Layers run in the order they are added, the first
+    /// one being the outermost.
+    pub fn layer(mut self, m: impl Middleware) -> Self {
+        self.layers.push(Box::new(m));
+        self
+    }
+
+    /// Replace the default slowlog, e.g. to change its threshold.
+    pub fn slowlog(mut self, slowlog: Slowlog) -> Self {
+        self.slowlog = slowlog;
+        self
+    }
+
+    /// Replace the default pubsub, e.g. to make some topics durable.
+    pub fn pubsub(mut self, pubsub: PubSub) -> Self {
+        self.pubsub = pubsub;
+        self
+    }
+
+    /// Set the key quotas of namespaces.
+    pub fn namespaces(mut self, namespaces: Namespaces) -> Self {
+        self.namespaces = namespaces;
+        self
+    }
+
+    pub fn received_callback(self, c: impl Fn(&CommandRequest) + Send + Sync + 'static) -> Self {
+        self.layer(OnRequest(c))
+    }
+
+    pub fn before_send_callback(
+        self,
         c: impl Fn(&mut CommandResponse) + Send + Sync + 'static,
     ) -> Self {
-        self.process.set_mut_callback(c);
-        self
+        self.layer(OnResponse(Arc::new(c)))
     }
 }
 
-impl<Store: Storage> Service<Store> {
+impl<Store: AsyncStorage> Service<Store> {
     pub fn new(store: Store) -> Self {
         Self {
             inner: Arc::new(ServiceInner::new(store)),
@@ -106,50 +208,271 @@ impl<Store: Storage> Service<Store> {
         }
     }
 
+    pub fn slowlog(&self) -> &Slowlog {
+        &self.inner.slowlog
+    }
+
+    /// The current mode and why it was set.
+    pub fn mode(&self) -> (ServerMode, String) {
+        self.inner.mode.get()
+    }
+
+    /// Switch modes, like a `SetMode` command would.
+    pub fn set_mode(&self, mode: ServerMode, reason: impl Into<String>) {
+        self.inner.mode.set(mode, reason.into());
+    }
+
+    /// Run a command on behalf of the connection owning `session`.
     #[instrument(name = "service_execute", skip_all)]
-    pub fn execute(&self, cmd: CommandRequest) -> StreamingResponse {
+    pub fn execute(&self, mut cmd: CommandRequest, session: &Arc<Session>) -> StreamingResponse {
         debug!("Got request: {:?}", cmd);
-        self.inner.process.process_events(&cmd);
+        let name = cmd.request_data.as_ref().map(|data| data.name());
+        if let Some(name) = name {
+            self.inner.stats.command(name);
+        }
+        let started = Instant::now();
+
+        // scope before the middlewares, so access control, idempotency and
+        // auditing see the names actually used
+        let ns = session.namespace();
+        let scoped = match (&ns, cmd.request_data.as_mut()) {
+            (Some(ns), Some(data)) => namespace::scope(data, ns),
+            _ => None,
+        };
 
-        if let Some(true) = cmd.request_data.as_ref().map(|x| x.is_streaming()) {
-            cmd.dispatch_streaming(Arc::clone(&self.broadcaster))
+        let res = if self.inner.layers.is_empty() {
+            dispatch(&self.inner, &self.broadcaster, session, cmd)
         } else {
-            let mut res = cmd.dispatch(&self.inner.store);
-            self.inner.process.process_events_mut(&mut res);
-            debug!("Executed response: {:?}", res);
+            // the pipeline runs once the response is polled, so the caller
+            // awaits it instead of blocking here
+            let inner = Arc::clone(&self.inner);
+            let broadcaster = Arc::clone(&self.broadcaster);
+            let session = Arc::clone(session);
+            Box::pin(
+                stream::once(async move {
+                    let endpoint = |cmd| dispatch(&inner, &broadcaster, &session, cmd);
+                    Next::new(&inner.layers, &endpoint, &session).run(cmd).await
+                })
+                .flatten(),
+            )
+        };
 
-            res.into()
-        }
+        let stats = Arc::clone(&self.inner.stats);
+        let mut timed = name;
+        Box::pin(res.map(move |mut res| {
+            if let Some(name) = timed.take() {
+                stats.latency(name, started.elapsed());
+            }
+            stats.response(&res);
+            if let Some(ns) = &ns {
+                if res.status >= 400 || !res.topic.is_empty() {
+                    namespace::unscope(Arc::make_mut(&mut res), ns, scoped.as_deref());
+                }
+            }
+            res
+        }))
+    }
+
+    /// Count a connection in `Info` until the guard is dropped.
+    pub(crate) fn connect(&self, session: &Session) -> ClientGuard {
+        self.inner.stats.connect(session.transport())
     }
 }
 
-struct Processor<T, U> {
-    callbacks: Vec<Box<dyn Fn(&T) + Send + Sync>>,
-    mut_callbacks: Vec<Box<dyn Fn(&mut U) + Send + Sync>>,
+/// Refuse a server-wide command to a connection bound to a namespace.
+fn server_wide(session: &Session) -> Result<(), KvError> {
+    match session.namespace().filter(|_| session.is_namespace_bound()) {
+        Some(ns) => Err(KvError::PermissionDenied(format!(
+            "connection is bound to namespace {}",
+            ns
+        ))),
+        None => Ok(()),
+    }
 }
 
-impl<T, U> Processor<T, U> {
-    fn new() -> Self {
-        Self {
-            callbacks: Vec::new(),
-            mut_callbacks: Vec::new(),
+/// The innermost layer: answer the server-wide commands, and run the others
+/// within the mode and namespace quotas. Commands arrive already scoped to
+/// the namespace of the session.
+fn dispatch<Store: AsyncStorage>(
+    inner: &Arc<ServiceInner<Store>>,
+    broadcaster: &Arc<PubSub>,
+    session: &Arc<Session>,
+    cmd: CommandRequest,
+) -> StreamingResponse {
+    match cmd.request_data {
+        Some(RequestData::SlowlogGet(param)) => {
+            // entries name the tables and clients of every namespace
+            if let Err(e) = server_wide(session) {
+                return CommandResponse::from(e).into();
+            }
+            return CommandResponse::from(inner.slowlog.get(param.count as usize)).into();
         }
-    }
-    fn set_callback(&mut self, c: impl Fn(&T) + Send + Sync + 'static) {
-        self.callbacks.push(Box::new(c));
-    }
-    fn set_mut_callback(&mut self, c: impl Fn(&mut U) + Send + Sync + 'static) {
-        self.mut_callbacks.push(Box::new(c));
-    }
-    fn process_events(&self, data: &T) {
-        for cb in self.callbacks.iter() {
-            cb(data)
+        Some(RequestData::SlowlogReset(_)) => {
+            if let Err(e) = server_wide(session) {
+                return CommandResponse::from(e).into();
+            }
+            inner.slowlog.reset();
+            return CommandResponse::ok().into();
         }
+        Some(RequestData::Info(_)) => {
+            let inner = Arc::clone(inner);
+            let (mode, mode_reason) = inner.mode.get();
+            // a bound connection only learns the mode and its own tables
+            let stats = match session.is_namespace_bound() {
+                true => ServerInfo::default(),
+                false => inner.stats.info(broadcaster),
+            };
+            let info = ServerInfo {
+                mode: mode as i32,
+                mode_reason,
+                ..stats
+            };
+            let namespace = session.namespace();
+            return Box::pin(stream::once(async move {
+                // a backend that does not count keys leaves them out
+                let table_keys = match inner.store.key_counts().await {
+                    Ok(counts) => match &namespace {
+                        Some(ns) => namespace::tables_of(counts, ns).collect(),
+                        None => counts.into_iter().collect(),
+                    },
+                    Err(e) => {
+                        warn!("failed to count keys for info: {}", e);
+                        Default::default()
+                    }
+                };
+                Arc::new(CommandResponse::from(ServerInfo { table_keys, ..info }))
+            }));
+        }
+        Some(RequestData::SelectNamespace(param)) => {
+            let name = (!param.name.is_empty()).then_some(param.name);
+            let res = match name.as_deref().map(namespace::validate) {
+                Some(Err(e)) => e,
+                _ => match session.set_namespace(name) {
+                    Ok(()) => return CommandResponse::ok().into(),
+                    Err(e) => e,
+                },
+            };
+            return CommandResponse::from(res).into();
+        }
+        Some(RequestData::ListNamespaces(_)) => {
+            if session.is_namespace_bound() {
+                let names: Vec<Value> = session.namespace().into_iter().map(Value::from).collect();
+                return CommandResponse::from(names).into();
+            }
+            let inner = Arc::clone(inner);
+            return Box::pin(stream::once(async move {
+                let res = match inner.namespaces.list(&inner.store).await {
+                    Ok(names) => {
+                        let names: Vec<Value> = names.into_iter().map(Value::from).collect();
+                        names.into()
+                    }
+                    Err(e) => e.into(),
+                };
+                Arc::new(res)
+            }));
+        }
+        Some(RequestData::SetMode(param)) => {
+            // the mode is server-wide, beyond what one namespace may change
+            if let Err(e) = server_wide(session) {
+                return CommandResponse::from(e).into();
+            }
+            inner.mode.set(param.mode(), param.reason);
+            return CommandResponse::ok().into();
+        }
+        Some(ref data) => {
+            if let Err(e) = inner.mode.check(data) {
+                return CommandResponse::from(e).into();
+            }
+        }
+        None => {}
     }
-    fn process_events_mut(&self, data: &mut U) {
-        for cb in self.mut_callbacks.iter() {
-            cb(data)
+
+    let limit = cmd.request_data.as_ref().and_then(|data| {
+        let (ns, quota) = inner.namespaces.limit(data)?;
+        Some((ns.to_string(), quota))
+    });
+    let Some((ns, quota)) = limit else {
+        return timed(inner, broadcaster, session, cmd);
+    };
+
+    let inner = Arc::clone(inner);
+    let broadcaster = Arc::clone(broadcaster);
+    let session = Arc::clone(session);
+    Box::pin(
+        stream::once(async move {
+            let data = cmd.request_data.as_ref().unwrap();
+            if let Err(e) = Namespaces::check(&inner.store, &ns, quota, data).await {
+                return CommandResponse::from(e).into();
+            }
+            timed(&inner, &broadcaster, &session, cmd)
+        })
+        .flatten(),
+    )
+}
+
+/// Run a command, timing it into the slowlog.
+fn timed<Store: AsyncStorage>(
+    inner: &Arc<ServiceInner<Store>>,
+    broadcaster: &Arc<PubSub>,
+    session: &Arc<Session>,
+    cmd: CommandRequest,
+) -> StreamingResponse {
+    let timer = cmd
+        .request_data
+        .as_ref()
+        .and_then(|data| inner.slowlog.start(data, session));
+    let res = execute(inner, broadcaster, session, cmd);
+    match timer {
+        // done once the first response is ready
+        Some(timer) => {
+            let inner = Arc::clone(inner);
+            let mut timer = Some(timer);
+            Box::pin(res.inspect(move |_| {
+                if let Some(timer) = timer.take() {
+                    inner.slowlog.finish(timer);
+                }
+            }))
         }
+        None => res,
+    }
+}
+
+/// Run the command against the store or the broadcaster, or hand it to a
+/// registered custom command.
+fn execute<Store: AsyncStorage>(
+    inner: &Arc<ServiceInner<Store>>,
+    broadcaster: &Arc<PubSub>,
+    session: &Arc<Session>,
+    cmd: CommandRequest,
+) -> StreamingResponse {
+    if let Some(RequestData::Custom(param)) = cmd.request_data {
+        let Some(handler) = inner.commands.get(&param.name) else {
+            let e = KvError::InvalidCommand(format!("unknown command `{}`", param.name));
+            return CommandResponse::from(e).into();
+        };
+        let ctx = Context::new(
+            Arc::clone(inner),
+            Arc::clone(broadcaster),
+            Arc::clone(session),
+        );
+        Box::pin(stream::once(handler(param.args, ctx).map(Arc::new)))
+    } else if let Some(true) = cmd.request_data.as_ref().map(|x| x.is_streaming()) {
+        let topic = topic::Connection::new(Arc::clone(broadcaster), session.id());
+        cmd.dispatch_streaming(topic)
+    } else if let Some(RequestData::Hgetall(param)) = cmd.request_data {
+        // stream the table back in chunks of at most `CHUNK_SIZE` bytes,
+        // pulled from `get_iter` only as fast as the client consumes them
+        let pairs = inner.store.get_iter(&param.table);
+        Box::pin(chunk_pairs(pairs, CHUNK_SIZE).map(Arc::new))
+    } else {
+        // storage is only touched once the response is polled
+        let inner = Arc::clone(inner);
+        Box::pin(stream::once(async move {
+            let res = cmd.dispatch(&inner.store).await;
+            debug!("Executed response: {:?}", res);
+
+            Arc::new(res)
+        }))
     }
 }
 
@@ -157,6 +480,7 @@ impl<T, U> Processor<T, U> {
 mod test {
     use super::*;
     use crate::*;
+    use bytes::Bytes;
     use futures::StreamExt;
     use http::StatusCode;
     use tracing::info;
@@ -166,17 +490,143 @@ mod test {
         let service = Service::new(MemTable::default());
         let service_c = service.clone();
         let handle = tokio::spawn(async move {
-            let mut res = service_c.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+            let mut res = service_c.execute(
+                CommandRequest::new_hset("t1", "k1", "v1".into()),
+                &Arc::default(),
+            );
             let res = res.next().await.unwrap();
             assert_res_ref_ok(&res, &[Value::default()], &[]);
         })
         .await
         .unwrap();
 
-        let mut res = service.execute(CommandRequest::new_hget("t1", "k1"));
+        let mut res = service.execute(CommandRequest::new_hget("t1", "k1"), &Arc::default());
         let res = res.next().await.unwrap();
         assert_res_ref_ok(&res, &["v1".into()], &[]);
     }
+    #[tokio::test]
+    async fn service_with_blocking_storage_should_work() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = Service::new(BlockingStorage::new(SledDb::new(dir.path())));
+
+        let mut res = service.execute(
+            CommandRequest::new_hset("t1", "k1", "v1".into()),
+            &Arc::default(),
+        );
+        let res = res.next().await.unwrap();
+        assert_res_ref_ok(&res, &[Value::default()], &[]);
+
+        let mut res = service.execute(CommandRequest::new_hgetall("t1"), &Arc::default());
+        let res = res.next().await.unwrap();
+        assert_res_ref_ok(&res, &[], &[Kvpair::new("k1", "v1".into())]);
+    }
+
+    #[tokio::test]
+    async fn slowlog_commands_should_work() {
+        let service: Service = ServiceInner::new(MemTable::default())
+            .slowlog(Slowlog::new(std::time::Duration::ZERO, 16))
+            .into();
+        let session = Arc::new(Session::with_peer_addr("127.0.0.1:4000".parse().unwrap()));
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        service.execute(cmd, &session).next().await.unwrap();
+        // the slowlog commands themselves are not recorded
+        let mut res = service.execute(CommandRequest::new_slowlog_get(0), &session);
+        let res = res.next().await.unwrap();
+        assert_eq!(res.status, 200);
+        assert_eq!(res.slowlog.len(), 1);
+        assert_eq!(res.slowlog[0].command, "hset");
+        assert_eq!(res.slowlog[0].client, "127.0.0.1:4000");
+
+        let mut res = service.execute(CommandRequest::new_slowlog_reset(), &session);
+        assert_res_ref_ok(&res.next().await.unwrap(), &[], &[]);
+        assert!(service.slowlog().is_empty());
+    }
+
+    #[tokio::test]
+    async fn info_should_report_stats() {
+        let service = Service::new(MemTable::default());
+        let session = Arc::new(Session::new().with_transport("tcp"));
+        let client = service.connect(&session);
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        service.execute(cmd, &session).next().await.unwrap();
+        let cmd = CommandRequest::new_hget("t1", "k2");
+        service.execute(cmd, &session).next().await.unwrap();
+        let mut sub = service.execute(CommandRequest::new_subscribe("lobby"), &session);
+        sub.next().await.unwrap();
+
+        let mut res = service.execute(CommandRequest::new_info(), &session);
+        let info = res.next().await.unwrap().info.clone().unwrap();
+        assert_eq!(info.clients["tcp"], 1);
+        assert_eq!(info.commands["hset"], 1);
+        assert_eq!(info.commands["info"], 1);
+        assert_eq!(info.errors[&404], 1);
+        assert_eq!((info.topics, info.subscriptions), (1, 1));
+        assert_eq!(info.table_keys["t1"], 1);
+
+        drop(client);
+        let mut res = service.execute(CommandRequest::new_info(), &session);
+        let info = res.next().await.unwrap().info.clone().unwrap();
+        assert_eq!(info.clients["tcp"], 0);
+    }
+
+    /// A backend that does not count its keys.
+    struct Uncounted(MemTable);
+
+    impl Storage for Uncounted {
+        fn get(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+            Storage::get(&self.0, table, key)
+        }
+        fn set(&self, table: &str, key: Bytes, value: Value) -> Result<Option<Value>, KvError> {
+            Storage::set(&self.0, table, key, value)
+        }
+        fn contains(&self, table: &str, key: &[u8]) -> Result<bool, KvError> {
+            Storage::contains(&self.0, table, key)
+        }
+        fn del(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+            Storage::del(&self.0, table, key)
+        }
+        fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+            Storage::get_all(&self.0, table)
+        }
+        fn get_iter(&self, table: &str) -> Result<impl Iterator<Item = Kvpair>, KvError> {
+            Storage::get_iter(&self.0, table)
+        }
+    }
+
+    #[tokio::test]
+    async fn info_should_work_without_key_counts() {
+        let service = Service::new(BlockingStorage::new(Uncounted(MemTable::new())));
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        service.execute(cmd, &Arc::default()).next().await.unwrap();
+
+        let mut res = service.execute(CommandRequest::new_info(), &Arc::default());
+        let res = res.next().await.unwrap();
+        assert_eq!(res.status, 200);
+        let info = res.info.clone().unwrap();
+        assert_eq!(info.commands["hset"], 1);
+        assert!(info.table_keys.is_empty());
+    }
+
+    #[tokio::test]
+    async fn hgetall_should_stream_chunks() {
+        let service = Service::new(MemTable::default());
+        let v: Value = Bytes::from(vec![0u8; 1024]).into();
+        let pairs = (0..200)
+            .map(|i| Kvpair::new(format!("k{}", i), v.clone()))
+            .collect();
+        let mut res = service.execute(CommandRequest::new_hmset("t1", pairs), &Arc::default());
+        res.next().await.unwrap();
+
+        let res = service.execute(CommandRequest::new_hgetall("t1"), &Arc::default());
+        let chunks: Vec<_> = res.collect().await;
+        assert!(chunks.len() > 1);
+        assert!(chunks[..chunks.len() - 1].iter().all(|c| c.has_more));
+        assert!(!chunks.last().unwrap().has_more);
+        assert_eq!(chunks.iter().map(|c| c.pairs.len()).sum::<usize>(), 200);
+    }
+
     #[tokio::test]
     async fn hook_should_work() {
         fn on_received(cmd: &CommandRequest) {
@@ -193,7 +643,10 @@ mod test {
             .before_send_callback(before_send)
             .received_callback(move |_| info!("HOLA {}", name))
             .into();
-        let mut res = service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        let mut res = service.execute(
+            CommandRequest::new_hset("t1", "k1", "v1".into()),
+            &Arc::default(),
+        );
         let res = res.next().await.unwrap();
         assert_eq!(res.status, StatusCode::CREATED.as_u16() as u32);
         assert_eq!(res.message, "");
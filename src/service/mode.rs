@@ -0,0 +1,172 @@
+use crate::{command_request::RequestData, KvError, ServerMode};
+use std::sync::RwLock;
+use tracing::info;
+
+/// The mode the server is in and why, switched at runtime by `SetMode` or
+/// `Service::set_mode`.
+#[derive(Debug, Default)]
+pub(crate) struct Mode {
+    state: RwLock<(ServerMode, String)>,
+}
+
+impl Mode {
+    pub(crate) fn get(&self) -> (ServerMode, String) {
+        self.state.read().unwrap().clone()
+    }
+
+    pub(crate) fn set(&self, mode: ServerMode, reason: String) {
+        info!("server mode set to {} ({})", describe(mode), reason);
+        *self.state.write().unwrap() = (mode, reason);
+    }
+
+    /// Reject the commands the current mode does not allow. Custom commands
+    /// may write, so they are rejected along with storage writes. Pubsub and
+    /// server-wide commands are never rejected.
+    pub(crate) fn check(&self, data: &RequestData) -> Result<(), KvError> {
+        let state = self.state.read().unwrap();
+        let rejected = match state.0 {
+            ServerMode::ReadWrite => false,
+            ServerMode::ReadOnly => matches!(
+                data,
+                RequestData::Hset(_)
+                    | RequestData::Hmset(_)
+                    | RequestData::Hdel(_)
+                    | RequestData::Hmdel(_)
+                    | RequestData::Custom(_)
+            ),
+            ServerMode::MaintenanceDrain => matches!(
+                data,
+                RequestData::Hget(_)
+                    | RequestData::Hgetall(_)
+                    | RequestData::Hmget(_)
+                    | RequestData::Hset(_)
+                    | RequestData::Hmset(_)
+                    | RequestData::Hdel(_)
+                    | RequestData::Hmdel(_)
+                    | RequestData::Hexist(_)
+                    | RequestData::Hmexist(_)
+                    | RequestData::Custom(_)
+            ),
+        };
+        if !rejected {
+            return Ok(());
+        }
+        let name = describe(state.0);
+        Err(KvError::Unavailable(match state.1.as_str() {
+            "" => format!("server is {}", name),
+            reason => format!("server is {}: {}", name, reason),
+        }))
+    }
+}
+
+/// How a mode is spelled in messages and logs.
+pub(crate) fn describe(mode: ServerMode) -> &'static str {
+    match mode {
+        ServerMode::ReadWrite => "read-write",
+        ServerMode::ReadOnly => "read-only",
+        ServerMode::MaintenanceDrain => "maintenance-drain",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assert_res_ok, assert_res_ref_error, AsyncStorage, CommandRequest, CommandResponse,
+        MemTable, Service, ServiceInner, Session,
+    };
+    use futures::StreamExt;
+    use std::sync::Arc;
+
+    async fn run(
+        service: &Service,
+        cmd: CommandRequest,
+        session: &Arc<Session>,
+    ) -> CommandResponse {
+        let mut res = service.execute(cmd, session);
+        res.next().await.unwrap().as_ref().clone()
+    }
+
+    #[tokio::test]
+    async fn read_only_should_reject_writes_only() {
+        let service = Service::new(MemTable::new());
+        let session = Arc::new(Session::new());
+        run(
+            &service,
+            CommandRequest::new_hset("t1", "k1", "v1".into()),
+            &session,
+        )
+        .await;
+        let mut sub = service.execute(CommandRequest::new_subscribe("lobby"), &session);
+        sub.next().await.unwrap();
+
+        let cmd = CommandRequest::new_set_mode(ServerMode::ReadOnly, "migrating");
+        assert_eq!(run(&service, cmd, &session).await.status, 200);
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v2".into());
+        let res = run(&service, cmd, &session).await;
+        assert_res_ref_error(&res, 503, "server is read-only: migrating");
+        let res = run(&service, CommandRequest::new_hget("t1", "k1"), &session).await;
+        assert_res_ok(res, &["v1".into()], &[]);
+
+        // subscriptions keep working
+        let cmd = CommandRequest::new_publish("lobby", vec!["hello".into()]);
+        run(&service, cmd, &session).await;
+        assert_eq!(sub.next().await.unwrap().values, vec!["hello".into()]);
+
+        let res = run(&service, CommandRequest::new_info(), &session).await;
+        let info = res.info.unwrap();
+        assert_eq!(info.mode(), ServerMode::ReadOnly);
+        assert_eq!(info.mode_reason, "migrating");
+    }
+
+    #[tokio::test]
+    async fn maintenance_drain_should_reject_storage_commands() {
+        let service = Service::new(MemTable::new());
+        let session = Arc::new(Session::new());
+        service.set_mode(ServerMode::MaintenanceDrain, "");
+
+        let res = run(&service, CommandRequest::new_hget("t1", "k1"), &session).await;
+        assert_res_ref_error(&res, 503, "server is maintenance-drain");
+        let cmd = CommandRequest::new_publish("lobby", vec!["hello".into()]);
+        assert_eq!(run(&service, cmd, &session).await.status, 200);
+
+        service.set_mode(ServerMode::ReadWrite, "");
+        let res = run(&service, CommandRequest::new_hget("t1", "k1"), &session).await;
+        assert_eq!(res.status, 404);
+    }
+
+    #[tokio::test]
+    async fn read_only_should_reject_custom_commands() {
+        let service: Service = ServiceInner::new(MemTable::new())
+            .command("touch", |_, ctx| async move {
+                ctx.store().set("t1", "k1".into(), "v1".into()).await?;
+                Ok(CommandResponse::ok())
+            })
+            .into();
+        let session = Arc::new(Session::new());
+        service.set_mode(ServerMode::ReadOnly, "");
+
+        let res = run(
+            &service,
+            CommandRequest::new_custom("touch", vec![]),
+            &session,
+        )
+        .await;
+        assert_res_ref_error(&res, 503, "server is read-only");
+        let res = run(&service, CommandRequest::new_hget("t1", "k1"), &session).await;
+        assert_eq!(res.status, 404);
+    }
+
+    #[tokio::test]
+    async fn namespace_bound_session_should_not_set_mode() {
+        let service = Service::new(MemTable::new());
+        let session = Arc::new(Session::new());
+        session.bind_namespace("acme");
+
+        let cmd = CommandRequest::new_set_mode(ServerMode::ReadOnly, "mine now");
+        let res = run(&service, cmd, &session).await;
+        assert_res_ref_error(&res, 403, "bound to namespace acme");
+        assert_eq!(service.mode().0, ServerMode::ReadWrite);
+    }
+}
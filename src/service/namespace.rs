@@ -0,0 +1,351 @@
+use crate::{command_request::RequestData, AsyncStorage, CommandResponse, KvError};
+use std::collections::{BTreeSet, HashMap};
+
+/// Separates a namespace from the table and topic names scoped to it
+pub const SEPARATOR: char = '/';
+
+/// Per-namespace key quotas, checked against the key counts of the store.
+/// A quota covers every write to the tables of its namespace, whether the
+/// connection selected the namespace or names `<namespace>/<table>` itself.
+///
+/// Concurrent writes to a namespace near its quota may overshoot it a little,
+/// as keys are counted before the writes land. Quotas need a backend that
+/// counts keys (see `Storage::key_counts`): with one that does not, writes to
+/// a namespace with a quota fail.
+#[derive(Debug, Default)]
+pub struct Namespaces {
+    quotas: HashMap<String, u64>,
+}
+
+impl Namespaces {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow at most `max_keys` keys across the tables of `namespace`.
+    pub fn quota(mut self, namespace: impl Into<String>, max_keys: u64) -> Self {
+        self.quotas.insert(namespace.into(), max_keys);
+        self
+    }
+
+    /// The namespace and quota a write is limited by, if any. `data` is
+    /// already scoped.
+    pub(crate) fn limit<'a>(&self, data: &'a RequestData) -> Option<(&'a str, u64)> {
+        let table = match data {
+            RequestData::Hset(v) => &v.table,
+            RequestData::Hmset(v) => &v.table,
+            _ => return None,
+        };
+        let (namespace, _) = table.split_once(SEPARATOR)?;
+        Some((namespace, *self.quotas.get(namespace)?))
+    }
+
+    /// Reject a write that would take `namespace` over `quota`.
+    pub(crate) async fn check(
+        store: &impl AsyncStorage,
+        namespace: &str,
+        quota: u64,
+        data: &RequestData,
+    ) -> Result<(), KvError> {
+        let (table, keys) = match data {
+            RequestData::Hset(v) => (&v.table, vec![v.pair.as_ref().map(|p| &p.key)]),
+            RequestData::Hmset(v) => (&v.table, v.pairs.iter().map(|p| Some(&p.key)).collect()),
+            _ => return Ok(()),
+        };
+
+        let prefix = prefix(namespace);
+        let count: u64 = store
+            .key_counts()
+            .await?
+            .into_iter()
+            .filter(|(table, _)| table.starts_with(&prefix))
+            .map(|(_, n)| n)
+            .sum();
+        let mut added = 0;
+        for key in keys.into_iter().flatten() {
+            if !store.contains(table, key).await? {
+                added += 1;
+            }
+        }
+        if count + added > quota {
+            return Err(KvError::QuotaExceeded(format!(
+                "namespace {} holds {} of {} keys, {} more requested",
+                namespace, count, quota, added
+            )));
+        }
+        Ok(())
+    }
+
+    /// Namespaces that hold keys or have a quota.
+    pub(crate) async fn list(&self, store: &impl AsyncStorage) -> Result<Vec<String>, KvError> {
+        let mut names: BTreeSet<String> = self.quotas.keys().cloned().collect();
+        for (table, n) in store.key_counts().await? {
+            if let (Some((namespace, _)), true) = (table.split_once(SEPARATOR), n > 0) {
+                names.insert(namespace.into());
+            }
+        }
+        Ok(names.into_iter().collect())
+    }
+}
+
+/// Check a name a connection wants to select.
+pub(crate) fn validate(name: &str) -> Result<(), KvError> {
+    if name.is_empty() || name.contains(SEPARATOR) || name.contains(char::is_whitespace) {
+        return Err(KvError::InvalidCommand(format!(
+            "invalid namespace `{}`, it must be non-empty without `{}` or whitespace",
+            name, SEPARATOR
+        )));
+    }
+    Ok(())
+}
+
+fn prefix(namespace: &str) -> String {
+    format!("{}{}", namespace, SEPARATOR)
+}
+
+/// Prefix the table or topic of a command with `namespace`, returning the
+/// scoped name.
+pub(crate) fn scope(data: &mut RequestData, namespace: &str) -> Option<String> {
+    let name = match data {
+        RequestData::Hget(v) => &mut v.table,
+        RequestData::Hgetall(v) => &mut v.table,
+        RequestData::Hmget(v) => &mut v.table,
+        RequestData::Hset(v) => &mut v.table,
+        RequestData::Hmset(v) => &mut v.table,
+        RequestData::Hdel(v) => &mut v.table,
+        RequestData::Hmdel(v) => &mut v.table,
+        RequestData::Hexist(v) => &mut v.table,
+        RequestData::Hmexist(v) => &mut v.table,
+        RequestData::Subscribe(v) => &mut v.topic,
+        RequestData::Unsubscribe(v) => &mut v.topic,
+        RequestData::Publish(v) => &mut v.topic,
+        RequestData::Psubscribe(v) => &mut v.pattern,
+        RequestData::Punsubscribe(v) => &mut v.pattern,
+        RequestData::Ack(v) => &mut v.topic,
+        RequestData::Pending(v) => &mut v.topic,
+        _ => return None,
+    };
+    name.insert_str(0, &prefix(namespace));
+    Some(name.clone())
+}
+
+/// Take `namespace` back out of the table and topic names a response
+/// mentions. In the message only `scoped`, the name the command was run
+/// with, is rewritten, so keys and values keep their text.
+pub(crate) fn unscope(res: &mut CommandResponse, namespace: &str, scoped: Option<&str>) {
+    let prefix = prefix(namespace);
+    if let Some(topic) = res.topic.strip_prefix(&prefix) {
+        res.topic = topic.into();
+    }
+    if let Some(detail) = res.error_detail.as_mut() {
+        if let Some(table) = detail.table.strip_prefix(&prefix) {
+            detail.table = table.into();
+        }
+    }
+    if let Some(at) = scoped.and_then(|name| name_at(&res.message, name)) {
+        res.message.replace_range(at..at + prefix.len(), "");
+    }
+}
+
+/// Where `name` first shows up in `message` as a word of its own, after a
+/// space and before a space, comma or the end. Messages name the table or
+/// topic ahead of any key, so the first such match is the name itself.
+fn name_at(message: &str, name: &str) -> Option<usize> {
+    message.match_indices(name).map(|(at, _)| at).find(|&at| {
+        let end = at + name.len();
+        message[..at].ends_with(' ')
+            && (end == message.len() || message[end..].starts_with([' ', ',']))
+    })
+}
+
+/// The tables of `namespace` among `tables`, without the prefix.
+pub(crate) fn tables_of<'a>(
+    tables: impl IntoIterator<Item = (String, u64)> + 'a,
+    namespace: &'a str,
+) -> impl Iterator<Item = (String, u64)> + 'a {
+    let prefix = prefix(namespace);
+    tables
+        .into_iter()
+        .filter_map(move |(table, n)| Some((table.strip_prefix(&prefix)?.to_string(), n)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assert_res_ok, assert_res_ref_error, AccessControl, CommandRequest, MemTable, Service,
+        ServiceInner, Session,
+    };
+    use futures::StreamExt;
+    use std::sync::Arc;
+
+    async fn run(
+        service: &Service,
+        cmd: CommandRequest,
+        session: &Arc<Session>,
+    ) -> CommandResponse {
+        let mut res = service.execute(cmd, session);
+        res.next().await.unwrap().as_ref().clone()
+    }
+
+    #[tokio::test]
+    async fn namespaces_should_isolate_tables_and_topics() {
+        let service = Service::new(MemTable::new());
+        let (acme, globex) = (Arc::new(Session::new()), Arc::new(Session::new()));
+        run(
+            &service,
+            CommandRequest::new_select_namespace("acme"),
+            &acme,
+        )
+        .await;
+        run(
+            &service,
+            CommandRequest::new_select_namespace("globex"),
+            &globex,
+        )
+        .await;
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "acme".into());
+        run(&service, cmd, &acme).await;
+        let res = run(&service, CommandRequest::new_hget("t1", "k1"), &globex).await;
+        assert_res_ref_error(&res, 404, "table: t1, key: k1");
+        assert_eq!(res.error_detail.unwrap().table, "t1");
+
+        // keys naming the namespace keep their text
+        let res = run(&service, CommandRequest::new_hget("t1", "acme/t1"), &acme).await;
+        assert_res_ref_error(&res, 404, "table: t1, key: acme/t1");
+
+        // unscoped connections see the full names
+        let global = Arc::new(Session::new());
+        let res = run(&service, CommandRequest::new_hget("acme/t1", "k1"), &global).await;
+        assert_res_ok(res, &["acme".into()], &[]);
+
+        let mut sub = service.execute(CommandRequest::new_subscribe("lobby"), &acme);
+        sub.next().await.unwrap();
+        let mut psub = service.execute(CommandRequest::new_psubscribe("*"), &acme);
+        psub.next().await.unwrap();
+        let cmd = CommandRequest::new_publish("lobby", vec!["globex".into()]);
+        run(&service, cmd, &globex).await;
+        let cmd = CommandRequest::new_publish("lobby", vec!["acme".into()]);
+        run(&service, cmd, &acme).await;
+        assert_eq!(sub.next().await.unwrap().values, vec!["acme".into()]);
+        let res = psub.next().await.unwrap();
+        assert_eq!(
+            (res.values.as_slice(), res.topic.as_str()),
+            (&["acme".into()][..], "lobby")
+        );
+
+        let res = run(&service, CommandRequest::new_list_namespaces(), &global).await;
+        assert_res_ok(res, &["acme".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn bound_namespace_should_not_change() {
+        let service = Service::new(MemTable::new());
+        let session = Arc::new(Session::new());
+        session.bind_namespace("acme");
+
+        let res = run(
+            &service,
+            CommandRequest::new_select_namespace("globex"),
+            &session,
+        )
+        .await;
+        assert_res_ref_error(&res, 403, "bound to namespace acme");
+        let res = run(
+            &service,
+            CommandRequest::new_select_namespace("a/b"),
+            &session,
+        )
+        .await;
+        assert_res_ref_error(&res, 400, "invalid namespace");
+
+        let global = Arc::new(Session::new());
+        let cmd = CommandRequest::new_hset("globex/t1", "k1", "v1".into());
+        run(&service, cmd, &global).await;
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        run(&service, cmd, &session).await;
+        // only its own namespace
+        let res = run(&service, CommandRequest::new_list_namespaces(), &session).await;
+        assert_res_ok(res, &["acme".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn bound_namespace_should_not_see_server_wide_state() {
+        let service = Service::new(MemTable::new());
+        let global = Arc::new(Session::new());
+        let cmd = CommandRequest::new_hset("globex/t1", "k1", "v1".into());
+        run(&service, cmd, &global).await;
+        let session = Arc::new(Session::new());
+        session.bind_namespace("acme");
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        run(&service, cmd, &session).await;
+
+        for cmd in [
+            CommandRequest::new_slowlog_get(0),
+            CommandRequest::new_slowlog_reset(),
+        ] {
+            let res = run(&service, cmd, &session).await;
+            assert_res_ref_error(&res, 403, "bound to namespace acme");
+        }
+
+        let res = run(&service, CommandRequest::new_info(), &session).await;
+        let info = res.info.unwrap();
+        assert!(info.commands.is_empty());
+        assert_eq!(
+            info.table_keys.into_iter().collect::<Vec<_>>(),
+            [("t1".into(), 1)]
+        );
+        let res = run(&service, CommandRequest::new_info(), &global).await;
+        assert_eq!(res.info.unwrap().commands["hset"], 2);
+    }
+
+    #[tokio::test]
+    async fn namespace_quota_should_limit_keys() {
+        let service: Service = ServiceInner::new(MemTable::new())
+            .namespaces(Namespaces::new().quota("acme", 2))
+            .into();
+        let session = Arc::new(Session::new());
+        session.bind_namespace("acme");
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        run(&service, cmd, &session).await;
+        let cmd = CommandRequest::new_hset("t2", "k1", "v1".into());
+        run(&service, cmd, &session).await;
+
+        let cmd = CommandRequest::new_hset("t1", "k2", "v1".into());
+        let res = run(&service, cmd, &session).await;
+        assert_res_ref_error(&res, 507, "namespace acme holds 2 of 2 keys");
+
+        // overwriting is fine, and deleting makes room
+        let cmd = CommandRequest::new_hset("t1", "k1", "v2".into());
+        assert_eq!(run(&service, cmd, &session).await.status, 200);
+        run(&service, CommandRequest::new_hdel("t2", "k1"), &session).await;
+        let cmd = CommandRequest::new_hset("t1", "k2", "v1".into());
+        assert_eq!(run(&service, cmd, &session).await.status, 200);
+
+        // writes naming the namespace from an unscoped connection count too
+        let global = Arc::new(Session::new());
+        let cmd = CommandRequest::new_hset("acme/t3", "k1", "v1".into());
+        let res = run(&service, cmd, &global).await;
+        assert_res_ref_error(&res, 507, "namespace acme holds 2 of 2 keys");
+        run(&service, CommandRequest::new_hdel("acme/t1", "k1"), &global).await;
+        let cmd = CommandRequest::new_hset("t3", "k1", "v1".into());
+        assert_eq!(run(&service, cmd, &session).await.status, 200);
+    }
+
+    #[tokio::test]
+    async fn middlewares_should_see_scoped_names() {
+        let acl = "deny * hget acme/secret\nallow * * *".parse().unwrap();
+        let service: Service = ServiceInner::new(MemTable::new())
+            .layer(AccessControl::new(acl))
+            .into();
+        let session = Arc::new(Session::new());
+        session.bind_namespace("acme");
+
+        let res = run(&service, CommandRequest::new_hget("secret", "k1"), &session).await;
+        assert_res_ref_error(&res, 403, "hget on secret");
+        let res = run(&service, CommandRequest::new_hget("public", "k1"), &session).await;
+        assert_eq!(res.status, 404);
+    }
+}
@@ -0,0 +1,329 @@
+use crate::{
+    command_request::RequestData, CommandRequest, CommandResponse, KvError, Middleware, Next,
+    Session, StreamingResponse,
+};
+use dashmap::DashMap;
+use futures::{future::BoxFuture, FutureExt, StreamExt};
+use prost::Message;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Clients tracked before idle buckets get dropped
+const MAX_CLIENTS: usize = 4096;
+
+/// A token bucket refilled at `rate` tokens per second, holding at most
+/// `burst` tokens.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quota {
+    rate: f64,
+    burst: f64,
+}
+
+impl Quota {
+    /// `rate` tokens per second, with a burst of one second worth.
+    pub fn per_second(rate: u32) -> Self {
+        Self {
+            rate: rate as f64,
+            burst: rate as f64,
+        }
+    }
+
+    pub fn with_burst(mut self, burst: u32) -> Self {
+        self.burst = burst as f64;
+        self
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(quota: &Quota, now: Instant) -> Self {
+        Self {
+            tokens: quota.burst,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, quota: &Quota, now: Instant) {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * quota.rate).min(quota.burst);
+        self.last = now;
+    }
+
+    /// How long until `cost` tokens are available, zero if they are now. A
+    /// cost above the burst only has to wait for a full bucket.
+    fn wait(&self, quota: &Quota, cost: f64) -> Duration {
+        let missing = cost.min(quota.burst) - self.tokens;
+        if missing <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(missing / quota.rate)
+        }
+    }
+
+    fn take(&mut self, quota: &Quota, cost: f64) {
+        self.tokens -= cost.min(quota.burst);
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    requests: Bucket,
+    bytes: Bucket,
+}
+
+/// Middleware limiting how fast each client may send requests, and how many
+/// subscriptions one connection may hold at once. Clients are told apart by
+/// principal, or by IP address before they authenticate. Rejected requests
+/// get a 429 with `retry_after_ms` set when waiting would help.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    requests: Option<Quota>,
+    bytes: Option<Quota>,
+    max_subscriptions: Option<usize>,
+    buckets: DashMap<String, Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limit the number of requests per second.
+    pub fn requests(mut self, quota: Quota) -> Self {
+        self.requests = Some(quota);
+        self
+    }
+
+    /// Limit the encoded request bytes per second.
+    pub fn bytes(mut self, quota: Quota) -> Self {
+        self.bytes = Some(quota);
+        self
+    }
+
+    /// Limit the concurrent subscriptions of a connection.
+    pub fn max_subscriptions(mut self, max: usize) -> Self {
+        self.max_subscriptions = Some(max);
+        self
+    }
+
+    fn check(&self, session: &Session, req: &CommandRequest) -> Result<(), KvError> {
+        let (requests, bytes) = match (self.requests, self.bytes) {
+            (None, None) => return Ok(()),
+            (requests, bytes) => (
+                requests.unwrap_or(Quota::per_second(u32::MAX)),
+                bytes.unwrap_or(Quota::per_second(u32::MAX)),
+            ),
+        };
+
+        let client = match (session.principal(), session.peer_addr()) {
+            (Some(principal), _) => principal,
+            (None, Some(addr)) => addr.ip().to_string(),
+            (None, None) => "anonymous".into(),
+        };
+        let now = Instant::now();
+        if self.buckets.len() >= MAX_CLIENTS && !self.buckets.contains_key(&client) {
+            self.evict_idle(&requests, &bytes, now);
+        }
+
+        let mut buckets = self
+            .buckets
+            .entry(client.clone())
+            .or_insert_with(|| Buckets {
+                requests: Bucket::new(&requests, now),
+                bytes: Bucket::new(&bytes, now),
+            });
+        buckets.requests.refill(&requests, now);
+        buckets.bytes.refill(&bytes, now);
+
+        let size = req.encoded_len() as f64;
+        let wait = buckets
+            .requests
+            .wait(&requests, 1.0)
+            .max(buckets.bytes.wait(&bytes, size));
+        if !wait.is_zero() {
+            // round up, waiting exactly this long must be enough
+            let ms = wait.as_nanos().div_ceil(1_000_000).min(u32::MAX as u128) as u32;
+            return Err(KvError::RateLimited(
+                format!("{} exceeded its quota", client),
+                ms,
+            ));
+        }
+
+        buckets.requests.take(&requests, 1.0);
+        buckets.bytes.take(&bytes, size);
+        Ok(())
+    }
+
+    /// Forget clients whose buckets have filled up again.
+    fn evict_idle(&self, requests: &Quota, bytes: &Quota, now: Instant) {
+        self.buckets.retain(|_, b| {
+            b.requests.refill(requests, now);
+            b.bytes.refill(bytes, now);
+            b.requests.tokens < requests.burst || b.bytes.tokens < bytes.burst
+        });
+    }
+}
+
+/// Gives a subscription slot back once the subscription stream is dropped.
+struct SubscriptionGuard(Arc<Session>);
+
+impl Drop for SubscriptionGuard {
+    fn drop(&mut self) {
+        self.0.remove_subscription();
+    }
+}
+
+impl Middleware for RateLimiter {
+    fn call<'a>(&'a self, req: CommandRequest, next: Next<'a>) -> BoxFuture<'a, StreamingResponse> {
+        let session = next.session();
+        if let Err(e) = self.check(session, &req) {
+            let res: StreamingResponse = CommandResponse::from(e).into();
+            return async move { res }.boxed();
+        }
+
+        match (&req.request_data, self.max_subscriptions) {
+            (Some(RequestData::Subscribe(_) | RequestData::Psubscribe(_)), Some(max)) => {
+                if !session.try_add_subscription(max) {
+                    let e = KvError::RateLimited(format!("more than {} subscriptions", max), 0);
+                    let res: StreamingResponse = CommandResponse::from(e).into();
+                    return async move { res }.boxed();
+                }
+
+                let guard = SubscriptionGuard(Arc::clone(session));
+                async move {
+                    let res = next.run(req).await;
+                    let res: StreamingResponse = Box::pin(res.map(move |res| {
+                        let _ = &guard;
+                        res
+                    }));
+                    res
+                }
+                .boxed()
+            }
+            _ => next.run(req),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Authenticator, Credentials, MemTable, Service, ServiceInner};
+    use tokio::time;
+
+    async fn status(service: &Service, cmd: CommandRequest, session: &Arc<Session>) -> u32 {
+        let mut res = service.execute(cmd, session);
+        res.next().await.unwrap().status
+    }
+
+    #[test]
+    fn bucket_should_refill_over_time() {
+        let quota = Quota::per_second(10).with_burst(2);
+        let start = Instant::now();
+        let mut bucket = Bucket::new(&quota, start);
+
+        bucket.take(&quota, 1.0);
+        bucket.take(&quota, 1.0);
+        assert_eq!(bucket.wait(&quota, 1.0), Duration::from_millis(100));
+
+        bucket.refill(&quota, start + Duration::from_millis(150));
+        assert_eq!(bucket.wait(&quota, 1.0), Duration::ZERO);
+
+        // never more than the burst
+        bucket.refill(&quota, start + Duration::from_secs(10));
+        assert_eq!(bucket.tokens, 2.0);
+        assert_eq!(bucket.wait(&quota, 100.0), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn rate_limiter_should_reject_with_retry_after() {
+        let limiter = RateLimiter::new().requests(Quota::per_second(10).with_burst(2));
+        let service: Service = ServiceInner::new(MemTable::new()).layer(limiter).into();
+        let alice = Arc::new(Session::new());
+        alice.set_principal("alice");
+        let bob = Arc::new(Session::new());
+        bob.set_principal("bob");
+
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        assert_eq!(status(&service, cmd.clone(), &alice).await, 404);
+        assert_eq!(status(&service, cmd.clone(), &alice).await, 404);
+
+        let mut res = service.execute(cmd.clone(), &alice);
+        let res = res.next().await.unwrap();
+        assert_eq!(res.status, 429);
+        assert!(res.retry_after_ms > 0 && res.retry_after_ms <= 100);
+
+        // quotas are per principal
+        assert_eq!(status(&service, cmd.clone(), &bob).await, 404);
+
+        time::sleep(Duration::from_millis(res.retry_after_ms as u64)).await;
+        assert_eq!(status(&service, cmd, &alice).await, 404);
+    }
+
+    #[tokio::test]
+    async fn rate_limiter_should_limit_bytes() {
+        let limiter = RateLimiter::new().bytes(Quota::per_second(1024));
+        let service: Service = ServiceInner::new(MemTable::new()).layer(limiter).into();
+        let session = Arc::new(Session::with_peer_addr("127.0.0.1:4000".parse().unwrap()));
+
+        let big: crate::Value = bytes::Bytes::from(vec![0u8; 800]).into();
+        let cmd = CommandRequest::new_hset("t1", "k1", big);
+        assert_eq!(status(&service, cmd.clone(), &session).await, 200);
+        assert_eq!(status(&service, cmd, &session).await, 429);
+
+        // small requests still fit in what is left
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        assert_eq!(status(&service, cmd, &session).await, 200);
+    }
+
+    #[tokio::test]
+    async fn rate_limiter_should_cap_subscriptions() {
+        let limiter = RateLimiter::new().max_subscriptions(1);
+        let service: Service = ServiceInner::new(MemTable::new()).layer(limiter).into();
+        let session = Arc::new(Session::new());
+
+        let mut sub = service.execute(CommandRequest::new_subscribe("lobby"), &session);
+        assert_eq!(sub.next().await.unwrap().status, 200);
+        assert_eq!(session.subscriptions(), 1);
+
+        let cmd = CommandRequest::new_subscribe("other");
+        assert_eq!(status(&service, cmd.clone(), &session).await, 429);
+
+        // the slot is given back once the subscription is gone
+        drop(sub);
+        assert_eq!(session.subscriptions(), 0);
+        assert_eq!(status(&service, cmd, &session).await, 200);
+    }
+
+    #[tokio::test]
+    async fn rate_limiter_after_authenticator_should_key_on_principal() {
+        let mut credentials = Credentials::new();
+        credentials.add_token("alice", "a");
+        credentials.add_token("bob", "b");
+        let service: Service = ServiceInner::new(MemTable::new())
+            .layer(Authenticator::new(credentials))
+            .layer(RateLimiter::new().requests(Quota::per_second(1)))
+            .into();
+
+        // both behind the same address
+        let addr = "127.0.0.1:4000".parse().unwrap();
+        let alice = Arc::new(Session::with_peer_addr(addr));
+        let bob = Arc::new(Session::with_peer_addr(addr));
+        for (session, token) in [(&alice, "a"), (&bob, "b")] {
+            let cmd = CommandRequest::new_auth_token(token);
+            assert_eq!(status(&service, cmd, session).await, 200);
+        }
+
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        assert_eq!(status(&service, cmd.clone(), &alice).await, 404);
+        assert_eq!(status(&service, cmd.clone(), &alice).await, 429);
+        assert_eq!(status(&service, cmd, &bob).await, 404);
+    }
+}
@@ -0,0 +1,143 @@
+use crate::KvError;
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        RwLock,
+    },
+};
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug)]
+struct Id(u64);
+
+impl Default for Id {
+    fn default() -> Self {
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// State of one client connection, shared by all of its requests.
+#[derive(Debug, Default)]
+pub struct Session {
+    id: Id,
+    principal: RwLock<Option<String>>,
+    principal_bound: AtomicBool,
+    namespace: RwLock<Option<String>>,
+    namespace_bound: AtomicBool,
+    peer_addr: Option<SocketAddr>,
+    transport: &'static str,
+    subscriptions: AtomicUsize,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_peer_addr(addr: SocketAddr) -> Self {
+        Self {
+            peer_addr: Some(addr),
+            ..Default::default()
+        }
+    }
+
+    /// Name the transport the connection came in by, e.g. `tcp`.
+    pub fn with_transport(mut self, transport: &'static str) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Tells the connection apart from every other one of the process.
+    pub fn id(&self) -> u64 {
+        self.id.0
+    }
+
+    /// The identity the connection authenticated as, if any.
+    pub fn principal(&self) -> Option<String> {
+        self.principal.read().unwrap().clone()
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        self.principal.read().unwrap().is_some()
+    }
+
+    pub fn set_principal(&self, principal: impl Into<String>) {
+        *self.principal.write().unwrap() = Some(principal.into());
+    }
+
+    /// Pin the connection to `principal` for good, e.g. the identity of its
+    /// client certificate, so that no `Auth` can change it.
+    pub fn bind_principal(&self, principal: impl Into<String>) {
+        self.set_principal(principal);
+        self.principal_bound.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_principal_bound(&self) -> bool {
+        self.principal_bound.load(Ordering::SeqCst)
+    }
+
+    /// The namespace table and topic names are scoped to, if any.
+    pub fn namespace(&self) -> Option<String> {
+        self.namespace.read().unwrap().clone()
+    }
+
+    /// Switch namespace, `None` going back to unscoped names. Fails if the
+    /// connection is bound to another namespace.
+    pub fn set_namespace(&self, namespace: Option<String>) -> Result<(), KvError> {
+        let mut current = self.namespace.write().unwrap();
+        if self.namespace_bound.load(Ordering::SeqCst) && *current != namespace {
+            return Err(KvError::PermissionDenied(format!(
+                "connection is bound to namespace {}",
+                current.as_deref().unwrap_or_default()
+            )));
+        }
+        *current = namespace;
+        Ok(())
+    }
+
+    /// Pin the connection to `namespace` for good, e.g. the one its
+    /// credentials belong to.
+    pub fn bind_namespace(&self, namespace: impl Into<String>) {
+        let mut current = self.namespace.write().unwrap();
+        *current = Some(namespace.into());
+        self.namespace_bound.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_namespace_bound(&self) -> bool {
+        self.namespace_bound.load(Ordering::SeqCst)
+    }
+
+    /// Remote address of the client, if the server knows it.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// The transport the connection came in by, `unknown` if not set.
+    pub fn transport(&self) -> &'static str {
+        match self.transport {
+            "" => "unknown",
+            transport => transport,
+        }
+    }
+
+    /// Number of subscriptions the connection currently holds.
+    pub fn subscriptions(&self) -> usize {
+        self.subscriptions.load(Ordering::SeqCst)
+    }
+
+    /// Count one more subscription unless the connection already holds
+    /// `max`. Returns whether it was counted.
+    pub(crate) fn try_add_subscription(&self, max: usize) -> bool {
+        self.subscriptions
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < max).then_some(n + 1)
+            })
+            .is_ok()
+    }
+
+    pub(crate) fn remove_subscription(&self) {
+        self.subscriptions.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,197 @@
+use crate::{command_request::RequestData, Session, SlowlogEntry};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Commands taking at least this long are recorded by default
+pub const DEFAULT_THRESHOLD: Duration = Duration::from_millis(10);
+/// Entries kept by default
+pub const DEFAULT_CAPACITY: usize = 128;
+
+/// The latest `capacity` commands that took at least `threshold` to execute,
+/// answering `SlowlogGet` and `SlowlogReset`. Subscriptions and publishes
+/// are not timed.
+#[derive(Debug)]
+pub struct Slowlog {
+    threshold: Duration,
+    capacity: usize,
+    next_id: AtomicU64,
+    entries: Mutex<VecDeque<SlowlogEntry>>,
+}
+
+impl Default for Slowlog {
+    fn default() -> Self {
+        Self::new(DEFAULT_THRESHOLD, DEFAULT_CAPACITY)
+    }
+}
+
+impl Slowlog {
+    /// A `capacity` of 0 turns the log off.
+    pub fn new(threshold: Duration, capacity: usize) -> Self {
+        Self {
+            threshold,
+            capacity,
+            next_id: AtomicU64::new(0),
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Up to `count` entries, newest first, all of them when `count` is 0.
+    pub fn get(&self, count: usize) -> Vec<SlowlogEntry> {
+        let entries = self.entries.lock().unwrap();
+        let count = if count == 0 { entries.len() } else { count };
+        entries.iter().rev().take(count).cloned().collect()
+    }
+
+    pub fn reset(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Start timing a command, `None` if it is not to be recorded.
+    pub(crate) fn start(&self, data: &RequestData, session: &Session) -> Option<Timer> {
+        if self.capacity == 0
+            || data.is_streaming()
+            || matches!(
+                data,
+                RequestData::SlowlogGet(_) | RequestData::SlowlogReset(_)
+            )
+        {
+            return None;
+        }
+
+        Some(Timer {
+            started: Instant::now(),
+            at: SystemTime::now(),
+            command: data.name(),
+            table: data.resource().unwrap_or_default().to_string(),
+            key_count: key_count(data),
+            client: session
+                .peer_addr()
+                .map(|a| a.to_string())
+                .unwrap_or_default(),
+        })
+    }
+
+    /// Record the command if it turned out slow.
+    pub(crate) fn finish(&self, timer: Timer) {
+        let elapsed = timer.started.elapsed();
+        if elapsed < self.threshold {
+            return;
+        }
+
+        let entry = SlowlogEntry {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            timestamp_ms: timer
+                .at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            command: timer.command.into(),
+            table: timer.table,
+            key_count: timer.key_count,
+            duration_us: elapsed.as_micros() as u64,
+            client: timer.client,
+        };
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+}
+
+/// A command being timed.
+#[derive(Debug)]
+pub(crate) struct Timer {
+    started: Instant,
+    at: SystemTime,
+    command: &'static str,
+    table: String,
+    key_count: u32,
+    client: String,
+}
+
+fn key_count(data: &RequestData) -> u32 {
+    let n = match data {
+        RequestData::Hget(_)
+        | RequestData::Hset(_)
+        | RequestData::Hdel(_)
+        | RequestData::Hexist(_) => 1,
+        RequestData::Hmget(v) => v.keys.len(),
+        RequestData::Hmdel(v) => v.keys.len(),
+        RequestData::Hmexist(v) => v.keys.len(),
+        RequestData::Hmset(v) => v.pairs.len(),
+        _ => 0,
+    };
+    n as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CommandRequest;
+
+    fn data(cmd: CommandRequest) -> RequestData {
+        cmd.request_data.unwrap()
+    }
+
+    #[test]
+    fn slowlog_should_only_keep_slow_commands() {
+        let log = Slowlog::new(Duration::from_millis(5), 8);
+        let session = Session::with_peer_addr("127.0.0.1:4000".parse().unwrap());
+
+        let timer = log.start(&data(CommandRequest::new_hget("t1", "k1")), &session);
+        log.finish(timer.unwrap());
+        assert!(log.is_empty());
+
+        let cmd = CommandRequest::new_hmget("t1", ["k1", "k2"]);
+        let mut timer = log.start(&data(cmd), &session).unwrap();
+        timer.started -= Duration::from_millis(20);
+        log.finish(timer);
+
+        let entries = log.get(0);
+        assert_eq!(entries.len(), 1);
+        let entry = &entries[0];
+        assert_eq!(entry.command, "hmget");
+        assert_eq!(entry.table, "t1");
+        assert_eq!(entry.key_count, 2);
+        assert!(entry.duration_us >= 20_000);
+        assert_eq!(entry.client, "127.0.0.1:4000");
+
+        // not timed at all
+        let cmd = CommandRequest::new_subscribe("lobby");
+        assert!(log.start(&data(cmd), &session).is_none());
+    }
+
+    #[test]
+    fn slowlog_should_be_bounded() {
+        let log = Slowlog::new(Duration::ZERO, 3);
+        let session = Session::new();
+        for i in 0..5 {
+            let cmd = CommandRequest::new_hget(format!("t{}", i), "k1");
+            log.finish(log.start(&data(cmd), &session).unwrap());
+        }
+
+        let entries = log.get(0);
+        let ids: Vec<_> = entries.iter().map(|e| e.id).collect();
+        assert_eq!(ids, [4, 3, 2]);
+        assert_eq!(entries[0].table, "t4");
+        assert_eq!(log.get(1).len(), 1);
+
+        log.reset();
+        assert!(log.get(0).is_empty());
+    }
+}
@@ -0,0 +1,120 @@
+use crate::{service::topic::PubSub, CommandResponse, ServerInfo};
+use dashmap::DashMap;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Upper bounds of the latency histogram buckets, in seconds
+pub(crate) const LATENCY_BUCKETS: [f64; 10] =
+    [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// Counters behind `Info` and the metrics, updated by `Service` and
+/// `ServerStream`.
+#[derive(Debug)]
+pub(crate) struct Stats {
+    started: Instant,
+    pub(crate) clients: DashMap<&'static str, u64>,
+    pub(crate) commands: DashMap<&'static str, u64>,
+    pub(crate) errors: DashMap<u32, u64>,
+    pub(crate) latency: DashMap<&'static str, Histogram>,
+}
+
+/// Time until the first response of a command.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Histogram {
+    /// Samples up to `LATENCY_BUCKETS[i]` but above the bound before it
+    pub(crate) buckets: [u64; LATENCY_BUCKETS.len()],
+    pub(crate) count: u64,
+    pub(crate) sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|b| secs <= *b) {
+            self.buckets[i] += 1;
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            clients: DashMap::new(),
+            commands: DashMap::new(),
+            errors: DashMap::new(),
+            latency: DashMap::new(),
+        }
+    }
+}
+
+impl Stats {
+    /// Count a connection over `transport` until the guard is dropped.
+    pub(crate) fn connect(self: &Arc<Self>, transport: &'static str) -> ClientGuard {
+        *self.clients.entry(transport).or_default() += 1;
+        ClientGuard {
+            stats: Arc::clone(self),
+            transport,
+        }
+    }
+
+    pub(crate) fn command(&self, name: &'static str) {
+        *self.commands.entry(name).or_default() += 1;
+    }
+
+    pub(crate) fn latency(&self, name: &'static str, elapsed: Duration) {
+        self.latency.entry(name).or_default().observe(elapsed);
+    }
+
+    pub(crate) fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub(crate) fn response(&self, res: &CommandResponse) {
+        if res.status >= 400 {
+            *self.errors.entry(res.status).or_default() += 1;
+        }
+    }
+
+    /// Everything but the table sizes and the mode, which come from the store
+    /// and the service.
+    pub(crate) fn info(&self, broadcaster: &PubSub) -> ServerInfo {
+        ServerInfo {
+            uptime_secs: self.uptime().as_secs(),
+            clients: self
+                .clients
+                .iter()
+                .map(|e| (e.key().to_string(), *e.value()))
+                .collect(),
+            commands: self
+                .commands
+                .iter()
+                .map(|e| (e.key().to_string(), *e.value()))
+                .collect(),
+            errors: self.errors.iter().map(|e| (*e.key(), *e.value())).collect(),
+            topics: broadcaster.topics() as u64,
+            subscriptions: broadcaster.subscriptions() as u64,
+            subscription_lag: broadcaster.lags().into_iter().collect(),
+            ..Default::default()
+        }
+    }
+}
+
+/// An open connection, counted until dropped.
+#[derive(Debug)]
+pub(crate) struct ClientGuard {
+    stats: Arc<Stats>,
+    transport: &'static str,
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        if let Some(mut n) = self.stats.clients.get_mut(self.transport) {
+            *n -= 1;
+        }
+    }
+}
@@ -1,14 +1,37 @@
-use crate::{CommandResponse, KvError, Value};
+use super::{
+    acl::glob_match,
+    group::{Delivery, Group, GroupConfig, DEAD_LETTER_SUFFIX},
+    mailbox::{Mailbox, Overflow, Sender, Sent},
+};
+use crate::{
+    CommandResponse, KvError, MemoryLog, PendingMessage, Retention, Subscribe, SubscribeFrom,
+    TopicLog, Value,
+};
 use dashmap::{DashMap, DashSet};
-use std::sync::{
-    atomic::{AtomicU32, Ordering},
-    Arc,
+use futures::future;
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
 };
-use tokio::sync::mpsc;
+use tokio::{sync::Mutex, task};
 use tracing::{debug, info, instrument, warn};
 
+pub use super::mailbox::Subscriber;
+
+/// Messages buffered for a subscriber by default
 const CAPACITY: usize = 128;
 
+/// How long a publisher waits for a blocking subscriber by default
+const PATIENCE: Duration = Duration::from_secs(5);
+
+/// Messages read from a topic log at once when replaying
+const REPLAY_BATCH: usize = 256;
+
 static NEXT_ID: AtomicU32 = AtomicU32::new(1);
 
 /// generate next unique id in u32 format
@@ -16,19 +39,274 @@ fn get_next_subscription_id() -> u32 {
     NEXT_ID.fetch_add(1, Ordering::Relaxed)
 }
 
+/// Pass a replayed or live message on, false once the subscription is over.
+async fn forward(tx: &Sender, message: Arc<CommandResponse>) -> bool {
+    matches!(tx.send(message).await, Sent::Delivered | Sent::Dropped)
+}
+
+fn no_group((topic, group): (String, String)) -> KvError {
+    KvError::NotFound(topic, format!("group {}", group))
+}
+
+/// Where a subscription to a durable topic starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Start {
+    Latest,
+    Earliest,
+    Offset(u64),
+}
+
+impl From<&Subscribe> for Start {
+    fn from(sub: &Subscribe) -> Self {
+        match sub.from() {
+            SubscribeFrom::Latest => Start::Latest,
+            SubscribeFrom::Earliest => Start::Earliest,
+            SubscribeFrom::Offset => Start::Offset(sub.offset),
+        }
+    }
+}
+
 pub trait Topic: Send + Sync + 'static {
-    fn subscribe(self, name: String) -> mpsc::Receiver<Arc<CommandResponse>>;
+    fn subscribe(self, name: String) -> Subscriber;
+    /// Subscribe, first replaying the retained messages of a durable topic
+    /// from `start` on.
+    fn subscribe_from(self, name: String, start: Start) -> Subscriber;
     fn unsubscribe(self, name: String, id: u32) -> Result<u32, KvError>;
-    fn publish(self, name: String, value: Arc<CommandResponse>);
+    /// Hand `value` to the subscribers of topic `name`, returning its
+    /// sequence number in the topic. Waits while a subscriber that blocks
+    /// has no room for it. A topic that is not durable counts from 1 again
+    /// once nobody listens to it.
+    fn publish(self, name: String, value: Arc<CommandResponse>)
+        -> impl Future<Output = u64> + Send;
+    fn psubscribe(self, pattern: String) -> Subscriber;
+    fn punsubscribe(self, pattern: String, id: u32) -> Result<u32, KvError>;
+    /// Join consumer `group` of a durable topic, creating the group at
+    /// `start` if it is new.
+    fn join_group(self, name: String, group: String, consumer: String, start: Start) -> Subscriber;
+    /// Acknowledge messages of `group`, returning how many were pending.
+    fn ack(self, name: String, group: String, sequences: Vec<u64>) -> Result<u64, KvError>;
+    fn pending(
+        self,
+        name: String,
+        group: String,
+        consumer: Option<String>,
+    ) -> Result<Vec<PendingMessage>, KvError>;
+}
+
+/// The publishing side of a topic. Messages are numbered and handed out
+/// under its lock, so subscribers receive them in sequence order. Only kept
+/// while a publisher holds it or its count matters.
+#[derive(Debug, Default)]
+struct Outbox {
+    /// The last sequence number handed out, for topics without a log
+    last: u64,
 }
 
-#[derive(Default)]
+/// Pattern subscriptions indexed by the literal prefix of their pattern, the
+/// part before the first wildcard, so that a publish only tries the patterns
+/// whose prefix its topic starts with.
+#[derive(Debug, Default)]
+struct Patterns {
+    by_prefix: HashMap<String, HashMap<String, HashSet<u32>>>,
+    len: usize,
+}
+
+fn literal_prefix(pattern: &str) -> &str {
+    &pattern[..pattern.find(['*', '?']).unwrap_or(pattern.len())]
+}
+
+impl Patterns {
+    fn insert(&mut self, pattern: String, id: u32) {
+        let patterns = self
+            .by_prefix
+            .entry(literal_prefix(&pattern).into())
+            .or_default();
+        let ids = patterns.entry(pattern).or_insert_with(|| {
+            self.len += 1;
+            HashSet::new()
+        });
+        ids.insert(id);
+    }
+
+    fn remove(&mut self, pattern: &str, id: u32) -> bool {
+        let prefix = literal_prefix(pattern);
+        let Some(patterns) = self.by_prefix.get_mut(prefix) else {
+            return false;
+        };
+        let Some(ids) = patterns.get_mut(pattern) else {
+            return false;
+        };
+        let removed = ids.remove(&id);
+        if ids.is_empty() {
+            info!("Pattern: {:?} is deleted", pattern);
+            patterns.remove(pattern);
+            self.len -= 1;
+            if patterns.is_empty() {
+                self.by_prefix.remove(prefix);
+            }
+        }
+        removed
+    }
+
+    /// The subscriptions whose pattern matches `topic`, with their pattern.
+    fn matches(&self, topic: &str) -> Vec<(String, u32)> {
+        let ends = topic.char_indices().map(|(i, _)| i).chain([topic.len()]);
+        ends.filter_map(|end| self.by_prefix.get(&topic[..end]))
+            .flatten()
+            .filter(|(pattern, _)| glob_match(pattern, topic))
+            .flat_map(|(pattern, ids)| ids.iter().map(|id| (pattern.clone(), *id)))
+            .collect()
+    }
+}
+
+/// Topics, exact or by pattern, fanned out to their subscribers. Topics made
+/// durable with `PubSub::durable` also append every message to a log, so
+/// it is kept with no subscriber around and can be replayed later.
+///
+/// Every message gets the next sequence number of its topic, taken from the
+/// log for durable topics and counted in memory while somebody listens
+/// otherwise, and the messages of a topic are handed out one at a time, so
+/// subscribers receive them in sequence order whatever the number of
+/// publishers. A subscriber that blocks holds the publishers back until it
+/// has room, which bounds what it buffers, and never the other subscribers.
 pub struct PubSub {
+    log: Arc<dyn TopicLog>,
+    durable: Vec<(String, Retention)>,
+    groups: DashMap<(String, String), Group>,
+    group_config: GroupConfig,
+    /// Whether the task expiring pending group messages runs
+    expiring: AtomicBool,
     topics: DashMap<String, DashSet<u32>>,
-    subscriptions: DashMap<u32, mpsc::Sender<Arc<CommandResponse>>>,
+    outboxes: DashMap<String, Arc<Mutex<Outbox>>>,
+    patterns: RwLock<Patterns>,
+    subscriptions: DashMap<u32, Sender>,
+    capacity: usize,
+    overflow: Overflow,
+    patience: Duration,
+    published: AtomicU64,
+    delivered: AtomicU64,
+    dropped: AtomicU64,
+    overflowed: AtomicU64,
+}
+
+impl Default for PubSub {
+    fn default() -> Self {
+        Self {
+            log: Arc::new(MemoryLog::new()),
+            durable: Vec::new(),
+            groups: DashMap::new(),
+            group_config: GroupConfig::default(),
+            expiring: AtomicBool::new(false),
+            topics: DashMap::new(),
+            outboxes: DashMap::new(),
+            patterns: Default::default(),
+            subscriptions: DashMap::new(),
+            capacity: CAPACITY,
+            overflow: Overflow::default(),
+            patience: PATIENCE,
+            published: AtomicU64::new(0),
+            delivered: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            overflowed: AtomicU64::new(0),
+        }
+    }
 }
 
 impl PubSub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep the durable topics in `log` instead of memory.
+    pub fn log(mut self, log: impl TopicLog) -> Self {
+        self.log = Arc::new(log);
+        self
+    }
+
+    /// Make the topics matching glob `pattern` durable, keeping what
+    /// `retention` allows. The first matching pattern applies.
+    pub fn durable(mut self, pattern: impl Into<String>, retention: Retention) -> Self {
+        self.durable.push((pattern.into(), retention));
+        self
+    }
+
+    /// Buffer up to `capacity` messages for each subscriber, applying
+    /// `overflow` once a slow one fills its buffer. Consumer groups hand a
+    /// message to another member instead, whatever the policy.
+    pub fn buffer(mut self, capacity: usize, overflow: Overflow) -> Self {
+        self.capacity = capacity;
+        self.overflow = overflow;
+        self
+    }
+
+    /// How long a publisher waits for room with `Overflow::Block` before the
+    /// subscriber still full is disconnected.
+    pub fn block_timeout(mut self, timeout: Duration) -> Self {
+        self.patience = timeout;
+        self
+    }
+
+    /// Set the acknowledgement timeout and attempts of consumer groups.
+    pub fn consumer_groups(mut self, config: GroupConfig) -> Self {
+        self.group_config = config;
+        self
+    }
+
+    /// How long the messages of topic `name` are kept, `None` if it is not
+    /// durable. The dead-letter topic of a durable topic is durable too,
+    /// keeping what the topic does unless a pattern says otherwise.
+    fn retention(&self, name: &str) -> Option<Retention> {
+        let find = |name: &str| {
+            self.durable
+                .iter()
+                .find(|(pattern, _)| glob_match(pattern, name))
+                .map(|(_, retention)| *retention)
+        };
+        find(name).or_else(|| find(name.strip_suffix(DEAD_LETTER_SUFFIX)?))
+    }
+
+    /// Number of topics with at least one subscriber.
+    pub fn topics(&self) -> usize {
+        self.topics.len()
+    }
+
+    /// Number of patterns with at least one subscriber.
+    pub fn patterns(&self) -> usize {
+        self.patterns.read().unwrap().len
+    }
+
+    pub fn subscriptions(&self) -> usize {
+        self.subscriptions.len()
+    }
+
+    /// Messages published so far.
+    pub fn published(&self) -> u64 {
+        self.published.load(Ordering::Relaxed)
+    }
+
+    /// Messages handed to subscribers so far, one per subscriber.
+    pub fn delivered(&self) -> u64 {
+        self.delivered.load(Ordering::Relaxed)
+    }
+
+    /// Messages lost because their subscriber was gone.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Messages lost because their subscriber's buffer was full.
+    pub fn overflowed(&self) -> u64 {
+        self.overflowed.load(Ordering::Relaxed)
+    }
+
+    /// Messages buffered for each subscription, waiting for its subscriber.
+    pub fn lags(&self) -> Vec<(u32, u64)> {
+        self.subscriptions
+            .iter()
+            .map(|s| (*s.key(), s.lag() as u64))
+            .collect()
+    }
+
     pub fn remove_subscription(&self, name: &String, id: u32) -> Option<u32> {
         if let Some(v) = self.topics.get_mut(name) {
             v.remove(&id);
@@ -37,17 +315,325 @@ impl PubSub {
                 info!("Topic: {:?} is deleted", name);
                 drop(v);
                 self.topics.remove(name);
+                self.release_outbox(name);
             }
         }
 
         debug!("Subscription {} is removed!", id);
         self.subscriptions.remove(&id).map(|(id, _)| id)
     }
+
+    fn remove_pattern_subscription(&self, pattern: &str, id: u32) -> Option<u32> {
+        self.patterns.write().unwrap().remove(pattern, id);
+        self.release_outboxes();
+        debug!("Pattern subscription {} is removed!", id);
+        self.subscriptions.remove(&id).map(|(id, _)| id)
+    }
+
+    /// Whether `outbox` of topic `name` can go: no publisher holds it, and
+    /// the topic is numbered by its log or has nobody to number it for.
+    fn idle(&self, name: &str, outbox: &Arc<Mutex<Outbox>>) -> bool {
+        Arc::strong_count(outbox) == 1
+            && (self.retention(name).is_some()
+                || !self.topics.contains_key(name)
+                    && self.patterns.read().unwrap().matches(name).is_empty())
+    }
+
+    /// Forget the outbox of topic `name` if it is idle.
+    fn release_outbox(&self, name: &str) {
+        self.outboxes
+            .remove_if(name, |name, outbox| self.idle(name, outbox));
+    }
+
+    /// Forget the idle outboxes, once a pattern has fewer subscribers.
+    fn release_outboxes(&self) {
+        self.outboxes
+            .retain(|name, outbox| !self.idle(name, outbox));
+    }
+
+    /// Run `f` on the topic log in a blocking thread, as its reads and
+    /// writes may hit the disk.
+    async fn with_log<T, F>(&self, f: F) -> Result<T, KvError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn TopicLog) -> Result<T, KvError> + Send + 'static,
+    {
+        let log = Arc::clone(&self.log);
+        task::spawn_blocking(move || f(log.as_ref())).await?
+    }
+
+    /// Register a subscriber, the first message it gets being its id.
+    fn add_subscription(&self, id: u32) -> Subscriber {
+        let (tx, rx) = Mailbox::channel(self.capacity, self.overflow, self.patience);
+        // queued right away, so no published message can overtake it
+        let val: Value = (id as i64).into();
+        tx.force(Arc::new(val.into()));
+        self.subscriptions.insert(id, tx);
+        rx
+    }
+
+    /// Hand a group message to subscription `id` if it has room.
+    fn try_deliver(&self, id: u32, message: Arc<CommandResponse>) -> Delivery {
+        let Some(mailbox) = self.subscriptions.get(&id).map(|tx| tx.mailbox()) else {
+            return Delivery::Gone;
+        };
+        match mailbox.try_send(message) {
+            Ok(()) => {
+                self.delivered.fetch_add(1, Ordering::Relaxed);
+                Delivery::Sent
+            }
+            Err(Sent::Closed) => {
+                self.subscriptions.remove(&id);
+                Delivery::Gone
+            }
+            Err(_) => Delivery::Full,
+        }
+    }
+
+    /// Hand out what the consumer groups of `topic` have waiting. Reads the
+    /// log, so it runs in a blocking thread.
+    fn pump_groups(&self, topic: &str) {
+        for mut group in self.groups.iter_mut().filter(|g| g.key().0 == topic) {
+            let deliver = |id, message| self.try_deliver(id, message);
+            if let Err(e) = group.pump(self.log.as_ref(), topic, &deliver) {
+                warn!("failed to deliver to group {}: {}", group.key().1, e);
+            }
+        }
+    }
+
+    /// Redeliver the group messages not acknowledged in time, and move those
+    /// out of attempts to the dead-letter topic. Reads the log, so it runs in
+    /// a blocking thread.
+    fn expire_groups(self: &Arc<Self>) {
+        self.drop_empty_groups();
+        let now = Instant::now();
+        let mut dead = vec![];
+        let mut letters = vec![];
+        for mut group in self.groups.iter_mut() {
+            let topic = group.key().0.clone();
+            for seq in group.expire(&self.group_config, now) {
+                dead.push((topic.clone(), seq));
+            }
+            let deliver = |id, message| self.try_deliver(id, message);
+            if let Err(e) = group.pump(self.log.as_ref(), &topic, &deliver) {
+                warn!("failed to deliver to group {}: {}", group.key().1, e);
+            }
+        }
+
+        for (topic, seq) in dead {
+            let message = match self.log.read(&topic, seq, 1) {
+                Ok(messages) => messages.into_iter().find(|m| m.sequence == seq),
+                Err(e) => {
+                    warn!("failed to read dead letter {} of {}: {}", seq, topic, e);
+                    None
+                }
+            };
+            if let Some(mut message) = message {
+                warn!("message {} of {} is out of attempts", seq, topic);
+                message.sequence = 0;
+                message.topic = topic.clone();
+                let name = format!("{}{}", topic, DEAD_LETTER_SUFFIX);
+                letters.push((name, Arc::new(message)));
+            }
+        }
+        // published apart, so a dead-letter subscriber without room does not
+        // hold up redelivery
+        if !letters.is_empty() {
+            let pubsub = Arc::clone(self);
+            tokio::spawn(async move {
+                for (name, message) in letters {
+                    pubsub.clone().publish(name, message).await;
+                }
+            });
+        }
+    }
+
+    /// Forget the members whose subscription is over, and the groups left
+    /// without any and with nothing to deliver. A group with messages pending
+    /// or unread is kept for its consumers to come back to.
+    fn drop_empty_groups(&self) {
+        let alive = |id| {
+            self.subscriptions
+                .get(&id)
+                .is_some_and(|tx| !tx.is_closed())
+        };
+        self.groups.retain(|(topic, group), g| {
+            let last = self.log.last(topic).unwrap_or_else(|e| {
+                warn!("failed to read topic {}: {}", topic, e);
+                0
+            });
+            let keep = g.retain_members(alive, last);
+            if !keep {
+                info!("Group {} of {} is deleted", group, topic);
+            }
+            keep
+        });
+    }
+
+    /// Join consumer `group` of a durable topic from connection `owner`.
+    fn join_group_as(
+        self: Arc<Self>,
+        name: String,
+        group: String,
+        consumer: String,
+        start: Start,
+        owner: Option<u64>,
+    ) -> Subscriber {
+        if self.retention(&name).is_none() {
+            let (tx, rx) = Mailbox::channel(1, Overflow::Block, self.patience);
+            let e = KvError::InvalidCommand(format!(
+                "topic {} is not durable, consumer groups need one",
+                name
+            ));
+            tx.force(Arc::new(e.into()));
+            return rx;
+        }
+
+        // members that went away hand back what they held before anyone joins
+        self.drop_empty_groups();
+        let id = get_next_subscription_id();
+        let rx = self.add_subscription(id);
+        let consumer = match consumer.is_empty() {
+            true => id.to_string(),
+            false => consumer,
+        };
+        debug!(
+            "add member {} ({}) to group {} of {}",
+            id, consumer, group, name
+        );
+        self.groups
+            .entry((name.clone(), group))
+            .or_insert_with(|| {
+                let next = match start {
+                    Start::Latest => {
+                        self.log.last(&name).unwrap_or_else(|e| {
+                            warn!("failed to read topic {}: {}", name, e);
+                            0
+                        }) + 1
+                    }
+                    Start::Earliest => 0,
+                    Start::Offset(offset) => offset,
+                };
+                Group::new(next)
+            })
+            .join(id, consumer, owner);
+        self.start_expiring();
+        let pubsub = Arc::clone(&self);
+        task::spawn_blocking(move || pubsub.pump_groups(&name));
+        rx
+    }
+
+    /// Acknowledge messages of `group` held by connection `owner`, or by
+    /// anyone if `None`.
+    fn ack_as(
+        &self,
+        name: String,
+        group: String,
+        sequences: Vec<u64>,
+        owner: Option<u64>,
+    ) -> Result<u64, KvError> {
+        let key = (name, group);
+        match self.groups.get_mut(&key) {
+            Some(mut g) => Ok(g.ack(&sequences, owner)),
+            None => Err(no_group(key)),
+        }
+    }
+
+    fn start_expiring(self: &Arc<Self>) {
+        if self.expiring.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let pubsub = Arc::downgrade(self);
+        let mut interval = tokio::time::interval(self.group_config.tick());
+        tokio::spawn(async move {
+            loop {
+                interval.tick().await;
+                let Some(pubsub) = pubsub.upgrade() else {
+                    break;
+                };
+                if let Err(e) = task::spawn_blocking(move || pubsub.expire_groups()).await {
+                    warn!("failed to expire group messages: {}", e);
+                }
+            }
+        });
+    }
+
+    /// The mailboxes of the subscribers of topic `name` and of the patterns
+    /// matching it.
+    fn recipients(&self, name: &String) -> Vec<Arc<Mailbox>> {
+        let mut ids: Vec<_> = match self.topics.get(name) {
+            Some(topic) => topic.iter().map(|id| *id).collect(),
+            None => vec![],
+        };
+        let matches = self.patterns.read().unwrap().matches(name);
+        ids.extend(matches.into_iter().map(|(_, id)| id));
+        ids.into_iter()
+            .filter_map(|id| self.subscriptions.get(&id).map(|tx| tx.mailbox()))
+            .collect()
+    }
+
+    /// Hand `value` to the subscribers of topic `name` and of the patterns
+    /// matching it, returning the subscriptions found over.
+    fn fan_out(&self, name: &String, value: Arc<CommandResponse>) -> Vec<(Option<String>, u32)> {
+        let mut over = vec![];
+        if let Some(topic) = self.topics.get(name) {
+            let subscriptions = topic.value().clone();
+            drop(topic);
+
+            for id in subscriptions.into_iter() {
+                if !self.deliver(id, value.clone()) {
+                    over.push((None, id));
+                }
+            }
+        }
+
+        let matches = self.patterns.read().unwrap().matches(name);
+        if matches.is_empty() {
+            return over;
+        }
+        // pattern subscribers are told which topic the message is from
+        let mut annotated = value.as_ref().clone();
+        annotated.topic = name.clone();
+        let annotated = Arc::new(annotated);
+        for (pattern, id) in matches {
+            if !self.deliver(id, annotated.clone()) {
+                over.push((Some(pattern), id));
+            }
+        }
+        over
+    }
+
+    /// Hand `value` to subscription `id`, false if the subscription is over.
+    fn deliver(&self, id: u32, value: Arc<CommandResponse>) -> bool {
+        let Some(mailbox) = self.subscriptions.get(&id).map(|tx| tx.mailbox()) else {
+            return true;
+        };
+        match mailbox.push(value) {
+            Sent::Delivered => {
+                self.delivered.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            Sent::Dropped => {
+                self.overflowed.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            Sent::Disconnected => {
+                warn!("Subscription {} fell behind and is disconnected", id);
+                self.overflowed.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+            Sent::Closed => {
+                warn!("Publish to {} failed! subscriber is gone", id);
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        }
+    }
 }
 
 impl Topic for Arc<PubSub> {
     #[instrument(name = "topic_subscribe", skip_all)]
-    fn subscribe(self, name: String) -> mpsc::Receiver<Arc<CommandResponse>> {
+    fn subscribe(self, name: String) -> Subscriber {
         let id = {
             let entry = self.topics.entry(name.clone()).or_default();
             let id = get_next_subscription_id();
@@ -55,17 +641,8 @@ impl Topic for Arc<PubSub> {
             id
         };
 
-        let (tx, rx) = mpsc::channel(CAPACITY);
-        let txc = tx.clone();
-        tokio::spawn(async move {
-            let val: Value = (id as i64).into();
-            if let Err(err) = tx.send(Arc::new(val.into())).await {
-                warn!("failed to send: {}", err);
-            };
-        });
         debug!("add subscription with id {} name {}", id, name);
-        self.subscriptions.insert(id, txc);
-        rx
+        self.add_subscription(id)
     }
 
     #[instrument(name = "topic_unsubscribe", skip_all)]
@@ -77,6 +654,7 @@ impl Topic for Arc<PubSub> {
                 info!("Topic: {:?} is deleted", name);
                 drop(v);
                 self.topics.remove(&name);
+                self.release_outbox(&name);
             }
         }
 
@@ -84,40 +662,240 @@ impl Topic for Arc<PubSub> {
         let s = self.subscriptions.remove(&id);
 
         match s {
-            Some(sender) => {
+            Some((_, sender)) => {
                 debug!("send cancel msg");
-                tokio::spawn(async move {
-                    let resp = CommandResponse::unsubscribe_ack();
-                    sender.1.send(Arc::new(resp)).await.unwrap();
-                });
+                sender.force(Arc::new(CommandResponse::unsubscribe_ack()));
+                self.drop_empty_groups();
                 Ok(id)
             }
             None => Err(KvError::NotFound(name, format!("subscription {}", id))),
         }
     }
 
-    #[instrument(name = "topic_publish", skip_all)]
-    fn publish(self, name: String, value: Arc<CommandResponse>) {
+    #[instrument(name = "topic_subscribe_from", skip_all)]
+    fn subscribe_from(self, name: String, start: Start) -> Subscriber {
+        let from = match (start, self.retention(&name)) {
+            (Start::Earliest, Some(_)) => 0,
+            (Start::Offset(offset), Some(_)) => offset,
+            _ => return self.subscribe(name),
+        };
+
+        // subscribe first so nothing published during the replay is missed,
+        // then skip the live messages the replay already covered
+        let mut live = self.clone().subscribe(name.clone());
+        let (tx, rx) = Mailbox::channel(self.capacity, self.overflow, self.patience);
         tokio::spawn(async move {
-            let mut ids = vec![];
-            if let Some(topic) = self.topics.get(&name) {
-                let subscriptions = topic.value().clone();
-                drop(topic);
-
-                for id in subscriptions.into_iter() {
-                    if let Some(tx) = self.subscriptions.get(&id) {
-                        if let Err(e) = tx.send(value.clone()).await {
-                            warn!("Publish to {} failed! error: {:?}", id, e);
-                            ids.push(id);
-                        }
+            let Some(id) = live.recv().await else {
+                return;
+            };
+            tx.force(id);
+
+            let retention = self.retention(&name).unwrap_or_default();
+            let topic = name.clone();
+            let trimmed = self.with_log(move |log| log.trim(&topic, &retention));
+            if let Err(e) = trimmed.await {
+                warn!("failed to trim topic {}: {}", name, e);
+            }
+            let mut next = from;
+            loop {
+                let topic = name.clone();
+                let read = self.with_log(move |log| log.read(&topic, next, REPLAY_BATCH));
+                let messages = match read.await {
+                    Ok(messages) => messages,
+                    Err(e) => {
+                        tx.force(Arc::new(e.into()));
+                        return;
+                    }
+                };
+                let Some(last) = messages.last() else {
+                    break;
+                };
+                next = last.sequence + 1;
+                for message in messages {
+                    if !forward(&tx, Arc::new(message)).await {
+                        return;
                     }
                 }
             }
 
-            for id in ids {
-                self.remove_subscription(&name, id);
+            while let Some(res) = live.recv().await {
+                if res.sequence != 0 && res.sequence < next {
+                    continue;
+                }
+                if !forward(&tx, res).await {
+                    return;
+                }
             }
         });
+        rx
+    }
+
+    #[instrument(name = "topic_publish", skip_all)]
+    async fn publish(self, name: String, value: Arc<CommandResponse>) -> u64 {
+        self.published.fetch_add(1, Ordering::Relaxed);
+        // wait for room first, so a blocking subscriber that does not read
+        // holds back this publisher rather than piling messages up, for its
+        // patience at most whoever else is full
+        let recipients = self.recipients(&name);
+        future::join_all(recipients.iter().map(|mailbox| mailbox.reserve())).await;
+
+        let retention = self.retention(&name);
+        // numbered, logged and handed out under the lock of this topic's
+        // outbox alone, so the subscribers get them in sequence order with
+        // concurrent publishers and other topics go on meanwhile
+        let outbox = Arc::clone(&self.outboxes.entry(name.clone()).or_default());
+        let (seq, over) = {
+            let mut outbox = outbox.lock().await;
+            let seq = match retention {
+                Some(retention) => {
+                    let (topic, message) = (name.clone(), Arc::clone(&value));
+                    let logged = self.with_log(move |log| {
+                        let seq = log.append(&topic, &message)?;
+                        if let Err(e) = log.trim(&topic, &retention) {
+                            warn!("failed to trim topic {}: {}", topic, e);
+                        }
+                        Ok(seq)
+                    });
+                    logged.await.unwrap_or_else(|e| {
+                        warn!("failed to log message of topic {}: {}", name, e);
+                        0
+                    })
+                }
+                None => {
+                    outbox.last += 1;
+                    outbox.last
+                }
+            };
+            let mut value = value.as_ref().clone();
+            value.sequence = seq;
+            (seq, self.fan_out(&name, Arc::new(value)))
+        };
+        drop(outbox);
+
+        for (pattern, id) in over {
+            match pattern {
+                Some(pattern) => self.remove_pattern_subscription(&pattern, id),
+                None => self.remove_subscription(&name, id),
+            };
+        }
+        self.release_outbox(&name);
+        if retention.is_some() && seq != 0 {
+            let pubsub = Arc::clone(&self);
+            if let Err(e) = task::spawn_blocking(move || pubsub.pump_groups(&name)).await {
+                warn!("failed to deliver to groups: {}", e);
+            }
+        }
+        seq
+    }
+
+    #[instrument(name = "topic_join_group", skip_all)]
+    fn join_group(self, name: String, group: String, consumer: String, start: Start) -> Subscriber {
+        self.join_group_as(name, group, consumer, start, None)
+    }
+
+    fn ack(self, name: String, group: String, sequences: Vec<u64>) -> Result<u64, KvError> {
+        self.ack_as(name, group, sequences, None)
+    }
+
+    fn pending(
+        self,
+        name: String,
+        group: String,
+        consumer: Option<String>,
+    ) -> Result<Vec<PendingMessage>, KvError> {
+        let key = (name, group);
+        match self.groups.get(&key) {
+            Some(g) => Ok(g.pending(consumer.as_deref())),
+            None => Err(no_group(key)),
+        }
+    }
+
+    #[instrument(name = "topic_psubscribe", skip_all)]
+    fn psubscribe(self, pattern: String) -> Subscriber {
+        let id = get_next_subscription_id();
+        debug!(
+            "add pattern subscription with id {} pattern {}",
+            id, pattern
+        );
+        self.patterns.write().unwrap().insert(pattern, id);
+        self.add_subscription(id)
+    }
+
+    #[instrument(name = "topic_punsubscribe", skip_all)]
+    fn punsubscribe(self, pattern: String, id: u32) -> Result<u32, KvError> {
+        if !self.patterns.write().unwrap().remove(&pattern, id) {
+            return Err(KvError::NotFound(pattern, format!("subscription {}", id)));
+        }
+        self.release_outboxes();
+
+        info!("Pattern subscription {} is removed!", id);
+        if let Some((_, sender)) = self.subscriptions.remove(&id) {
+            sender.force(Arc::new(CommandResponse::unsubscribe_ack()));
+        }
+        Ok(id)
+    }
+}
+
+/// The pubsub as one connection uses it: the consumer group messages it is
+/// handed can only be acknowledged by that connection.
+#[derive(Clone)]
+pub(crate) struct Connection {
+    pubsub: Arc<PubSub>,
+    owner: u64,
+}
+
+impl Connection {
+    pub(crate) fn new(pubsub: Arc<PubSub>, owner: u64) -> Self {
+        Self { pubsub, owner }
+    }
+}
+
+impl Topic for Connection {
+    fn subscribe(self, name: String) -> Subscriber {
+        self.pubsub.subscribe(name)
+    }
+
+    fn subscribe_from(self, name: String, start: Start) -> Subscriber {
+        self.pubsub.subscribe_from(name, start)
+    }
+
+    fn unsubscribe(self, name: String, id: u32) -> Result<u32, KvError> {
+        self.pubsub.unsubscribe(name, id)
+    }
+
+    fn publish(
+        self,
+        name: String,
+        value: Arc<CommandResponse>,
+    ) -> impl Future<Output = u64> + Send {
+        self.pubsub.publish(name, value)
+    }
+
+    fn psubscribe(self, pattern: String) -> Subscriber {
+        self.pubsub.psubscribe(pattern)
+    }
+
+    fn punsubscribe(self, pattern: String, id: u32) -> Result<u32, KvError> {
+        self.pubsub.punsubscribe(pattern, id)
+    }
+
+    fn join_group(self, name: String, group: String, consumer: String, start: Start) -> Subscriber {
+        let owner = Some(self.owner);
+        self.pubsub
+            .join_group_as(name, group, consumer, start, owner)
+    }
+
+    fn ack(self, name: String, group: String, sequences: Vec<u64>) -> Result<u64, KvError> {
+        self.pubsub.ack_as(name, group, sequences, Some(self.owner))
+    }
+
+    fn pending(
+        self,
+        name: String,
+        group: String,
+        consumer: Option<String>,
+    ) -> Result<Vec<PendingMessage>, KvError> {
+        self.pubsub.pending(name, group, consumer)
     }
 }
 
@@ -125,7 +903,274 @@ impl Topic for Arc<PubSub> {
 mod tests {
     use super::*;
     use crate::{assert_res_error, assert_res_ok};
-    use std::convert::TryInto;
+    use std::{convert::TryInto, time::Duration};
+
+    #[test]
+    fn patterns_should_match_by_prefix() {
+        let mut patterns = Patterns::default();
+        patterns.insert("orders.*".into(), 1);
+        patterns.insert("orders.eu".into(), 2);
+        patterns.insert("*.eu".into(), 3);
+        patterns.insert("users.?".into(), 4);
+        assert_eq!(patterns.len, 4);
+
+        let mut ids: Vec<_> = patterns
+            .matches("orders.eu")
+            .into_iter()
+            .map(|m| m.1)
+            .collect();
+        ids.sort();
+        assert_eq!(ids, [1, 2, 3]);
+        assert_eq!(patterns.matches("users.1"), [("users.?".into(), 4)]);
+        assert!(patterns.matches("users.12").is_empty());
+
+        assert!(patterns.remove("orders.*", 1));
+        assert!(!patterns.remove("orders.*", 1));
+        assert_eq!(patterns.len, 3);
+        assert_eq!(patterns.matches("orders.us"), []);
+    }
+
+    async fn recv_values(rx: &mut Subscriber, n: usize) -> Vec<u64> {
+        let mut sequences = vec![];
+        for _ in 0..n {
+            sequences.push(rx.recv().await.unwrap().sequence);
+        }
+        sequences
+    }
+
+    #[tokio::test]
+    async fn durable_topics_should_replay_from_offset() {
+        let log = crate::SledLog::temporary().unwrap();
+        let retention = Retention::new().max_messages(3);
+        let b = Arc::new(PubSub::new().log(log).durable("orders.*", retention));
+
+        // kept with nobody subscribed, and trimmed to the retention
+        for n in 1..=4i64 {
+            let v: Value = n.into();
+            b.clone()
+                .publish("orders.eu".into(), Arc::new(v.into()))
+                .await;
+        }
+        b.clone()
+            .publish("users".into(), Arc::new(Value::from(1i64).into()))
+            .await;
+
+        let mut earliest = b
+            .clone()
+            .subscribe_from("orders.eu".into(), Start::Earliest);
+        earliest.recv().await.unwrap();
+        assert_eq!(recv_values(&mut earliest, 3).await, [2, 3, 4]);
+
+        let mut at = b
+            .clone()
+            .subscribe_from("orders.eu".into(), Start::Offset(4));
+        at.recv().await.unwrap();
+        let res = at.recv().await.unwrap();
+        assert_res_ok(res.as_ref().clone(), &[4i64.into()], &[]);
+
+        let mut latest = b.clone().subscribe_from("orders.eu".into(), Start::Latest);
+        latest.recv().await.unwrap();
+        b.clone()
+            .publish("orders.eu".into(), Arc::new(Value::from(5i64).into()))
+            .await;
+        for rx in [&mut earliest, &mut at, &mut latest] {
+            assert_eq!(recv_values(rx, 1).await, [5]);
+        }
+
+        // other topics are neither logged nor replayed, and numbered afresh
+        // once nobody listened
+        let mut users = b.clone().subscribe_from("users".into(), Start::Earliest);
+        users.recv().await.unwrap();
+        b.clone()
+            .publish("users".into(), Arc::new(Value::from(2i64).into()))
+            .await;
+        let res = users.recv().await.unwrap();
+        assert_eq!(
+            (res.sequence, res.values.as_slice()),
+            (1, &[2i64.into()][..])
+        );
+    }
+
+    #[tokio::test]
+    async fn slow_subscribers_should_be_told_of_gaps() {
+        let b = Arc::new(PubSub::new().buffer(2, Overflow::DropNewest));
+        let mut slow = b.clone().subscribe("lobby".into());
+        let id: i64 = slow.recv().await.unwrap().as_ref().try_into().unwrap();
+
+        for n in 1..=5i64 {
+            b.clone()
+                .publish("lobby".into(), Arc::new(Value::from(n).into()))
+                .await;
+        }
+        while b.delivered() + b.overflowed() < 5 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(b.lags(), [(id as u32, 2)]);
+        assert_eq!(b.overflowed(), 3);
+
+        let mut received = vec![];
+        for _ in 0..3 {
+            let res = slow.recv().await.unwrap();
+            received.push((res.gap, res.values.clone()));
+        }
+        assert_eq!(
+            received,
+            [(0, vec![1i64.into()]), (0, vec![2i64.into()]), (3, vec![])]
+        );
+        assert_eq!(b.lags(), [(id as u32, 0)]);
+    }
+
+    #[tokio::test]
+    async fn blocking_subscribers_should_hold_publishers_back() {
+        let b = Arc::new(PubSub::new().buffer(2, Overflow::Block));
+        let mut stalled = b.clone().subscribe("lobby".into());
+        let id: i64 = stalled.recv().await.unwrap().as_ref().try_into().unwrap();
+
+        let publisher = tokio::spawn({
+            let b = b.clone();
+            async move {
+                for n in 1..=100i64 {
+                    b.clone()
+                        .publish("lobby".into(), Arc::new(Value::from(n).into()))
+                        .await;
+                }
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // nothing piles up past the buffer, the third publish is waiting
+        assert!(!publisher.is_finished());
+        assert_eq!(b.published(), 3);
+        assert_eq!(b.lags(), [(id as u32, 2)]);
+
+        let expected: Vec<u64> = (1..=100).collect();
+        assert_eq!(recv_values(&mut stalled, 100).await, expected);
+        publisher.await.unwrap();
+        assert_eq!(b.lags(), [(id as u32, 0)]);
+    }
+
+    #[tokio::test]
+    async fn full_subscribers_should_not_stall_the_others() {
+        let b = Arc::new(PubSub::new().buffer(2, Overflow::Block));
+        let mut stalled = b.clone().subscribe("lobby".into());
+        let mut reader = b.clone().subscribe("lobby".into());
+        let id: i64 = stalled.recv().await.unwrap().as_ref().try_into().unwrap();
+        reader.recv().await.unwrap();
+
+        let publisher = tokio::spawn({
+            let b = b.clone();
+            async move {
+                for n in 1..=5i64 {
+                    b.clone()
+                        .publish("lobby".into(), Arc::new(Value::from(n).into()))
+                        .await;
+                }
+            }
+        });
+        let received = tokio::time::timeout(Duration::from_secs(1), recv_values(&mut reader, 2));
+        assert_eq!(received.await.unwrap(), [1, 2]);
+        assert!(!publisher.is_finished());
+
+        // once the stalled one is gone, the publisher goes on
+        b.clone().unsubscribe("lobby".into(), id as _).unwrap();
+        assert_eq!(recv_values(&mut reader, 3).await, [3, 4, 5]);
+        publisher.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stalled_subscribers_should_be_disconnected() {
+        let b = PubSub::new()
+            .buffer(2, Overflow::Block)
+            .block_timeout(Duration::from_millis(50));
+        let b = Arc::new(b);
+        let mut stalled = b.clone().psubscribe("*".into());
+        let mut reader = b.clone().subscribe("lobby".into());
+        stalled.recv().await.unwrap();
+        reader.recv().await.unwrap();
+
+        let reader = tokio::spawn(async move { recv_values(&mut reader, 5).await });
+        let publish = async {
+            for n in 1..=5i64 {
+                b.clone()
+                    .publish("lobby".into(), Arc::new(Value::from(n).into()))
+                    .await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(1), publish)
+            .await
+            .unwrap();
+        assert_eq!(reader.await.unwrap(), [1, 2, 3, 4, 5]);
+
+        // what fit in its buffer, then the gap it was disconnected at
+        assert_eq!(recv_values(&mut stalled, 2).await, [1, 2]);
+        assert_eq!(stalled.recv().await.unwrap().gap, 1);
+        assert!(stalled.recv().await.is_none());
+        assert_eq!(b.overflowed(), 1);
+    }
+
+    #[tokio::test]
+    async fn outboxes_should_be_dropped_when_idle() {
+        let b = Arc::new(PubSub::new().durable("orders", Retention::new()));
+        let publish = |name: &str| {
+            b.clone()
+                .publish(name.into(), Arc::new(Value::from(1i64).into()))
+        };
+
+        // numbered by the log, or for nobody
+        publish("orders").await;
+        publish("nobody").await;
+        assert_eq!(b.outboxes.len(), 0);
+
+        let mut rx = b.clone().subscribe("lobby".into());
+        let id: i64 = rx.recv().await.unwrap().as_ref().try_into().unwrap();
+        let mut pattern = b.clone().psubscribe("lob*".into());
+        let pid: i64 = pattern.recv().await.unwrap().as_ref().try_into().unwrap();
+        publish("lobby").await;
+        publish("lobby.eu").await;
+        assert_eq!(b.outboxes.len(), 2);
+
+        b.clone().punsubscribe("lob*".into(), pid as _).unwrap();
+        assert_eq!(b.outboxes.len(), 1);
+        b.clone().unsubscribe("lobby".into(), id as _).unwrap();
+        assert_eq!(b.outboxes.len(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_publishers_should_be_received_in_sequence() {
+        let b = Arc::new(PubSub::new());
+        let mut rx = b.clone().subscribe("lobby".into());
+        let mut pattern = b.clone().psubscribe("lob*".into());
+        rx.recv().await.unwrap();
+        pattern.recv().await.unwrap();
+
+        let publishers: Vec<_> = (0..4i64)
+            .map(|p| {
+                let b = b.clone();
+                tokio::spawn(async move {
+                    for n in 0..50i64 {
+                        let v: Value = (p * 100 + n).into();
+                        b.clone().publish("lobby".into(), Arc::new(v.into())).await;
+                    }
+                })
+            })
+            .collect();
+
+        // read along, since the publishers wait once the buffers are full
+        let mut last = [-1i64; 4];
+        for seq in 1..=200u64 {
+            let res = rx.recv().await.unwrap();
+            assert_eq!(res.sequence, seq);
+            assert_eq!(pattern.recv().await.unwrap().sequence, seq);
+            // and each publisher's messages in the order it sent them
+            let v: i64 = res.as_ref().try_into().unwrap();
+            let p = (v / 100) as usize;
+            assert!(v % 100 > last[p]);
+            last[p] = v % 100;
+        }
+        for publisher in publishers {
+            publisher.await.unwrap();
+        }
+    }
 
     #[tokio::test]
     async fn pub_sub_should_work() {
@@ -138,7 +1183,9 @@ mod tests {
 
         // publish
         let v: Value = "hello".into();
-        b.clone().publish(cae.clone(), Arc::new(v.clone().into()));
+        b.clone()
+            .publish(cae.clone(), Arc::new(v.clone().into()))
+            .await;
 
         // get id first
         let id1: i64 = stream1.recv().await.unwrap().as_ref().try_into().unwrap();
@@ -157,7 +1204,9 @@ mod tests {
 
         // publish
         let v: Value = "world".into();
-        b.clone().publish(cae.clone(), Arc::new(v.clone().into()));
+        b.clone()
+            .publish(cae.clone(), Arc::new(v.clone().into()))
+            .await;
 
         let cancel = stream1.recv().await.unwrap();
         assert_res_error(Arc::clone(&cancel).as_ref().to_owned(), 0, "");
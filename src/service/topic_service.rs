@@ -1,13 +1,20 @@
+use futures::stream;
 use std::sync::Arc;
 
-use tokio_stream::wrappers::ReceiverStream;
-
-use crate::{CommandResponse, Publish, Subscribe, TopicService, Unsubscribe};
+use super::topic::Start;
+use crate::{
+    Ack, CommandResponse, Pending, Psubscribe, Publish, Punsubscribe, Subscribe, TopicService,
+    Unsubscribe, Value,
+};
 
 impl TopicService for Subscribe {
     fn execute(self, chan: impl super::topic::Topic) -> crate::StreamingResponse {
-        let rx = chan.subscribe(self.topic);
-        Box::pin(ReceiverStream::new(rx))
+        let start = Start::from(&self);
+        let rx = match self.group.is_empty() {
+            true => chan.subscribe_from(self.topic, start),
+            false => chan.join_group(self.topic, self.group, self.consumer, start),
+        };
+        Box::pin(rx.into_stream())
     }
 }
 
@@ -23,8 +30,51 @@ impl TopicService for Unsubscribe {
 
 impl TopicService for Publish {
     fn execute(self, chan: impl super::topic::Topic) -> crate::StreamingResponse {
-        chan.publish(self.topic, Arc::new(self.data.into()));
-        CommandResponse::ok().into()
+        // answered once the message is handed out, which waits for room if
+        // a subscriber blocks
+        Box::pin(stream::once(async move {
+            let mut res = CommandResponse::ok();
+            res.sequence = chan.publish(self.topic, Arc::new(self.data.into())).await;
+            Arc::new(res)
+        }))
+    }
+}
+
+impl TopicService for Psubscribe {
+    fn execute(self, chan: impl super::topic::Topic) -> crate::StreamingResponse {
+        let rx = chan.psubscribe(self.pattern);
+        Box::pin(rx.into_stream())
+    }
+}
+
+impl TopicService for Punsubscribe {
+    fn execute(self, chan: impl super::topic::Topic) -> crate::StreamingResponse {
+        let res = match chan.punsubscribe(self.pattern, self.id) {
+            Ok(_) => CommandResponse::ok(),
+            Err(e) => e.into(),
+        };
+        res.into()
+    }
+}
+
+impl TopicService for Ack {
+    fn execute(self, chan: impl super::topic::Topic) -> crate::StreamingResponse {
+        let res: CommandResponse = match chan.ack(self.topic, self.group, self.sequences) {
+            Ok(n) => Value::from(n as i64).into(),
+            Err(e) => e.into(),
+        };
+        res.into()
+    }
+}
+
+impl TopicService for Pending {
+    fn execute(self, chan: impl super::topic::Topic) -> crate::StreamingResponse {
+        let consumer = (!self.consumer.is_empty()).then_some(self.consumer);
+        let res: CommandResponse = match chan.pending(self.topic, self.group, consumer) {
+            Ok(pending) => pending.into(),
+            Err(e) => e.into(),
+        };
+        res.into()
     }
 }
 
@@ -43,10 +93,17 @@ mod tests {
     #[tokio::test]
     async fn dispatch_publish_should_work() {
         let topic = Arc::new(PubSub::default());
+        let mut sub = CommandRequest::new_subscribe("cae").dispatch_streaming(topic.clone());
+        get_id(&mut sub).await;
         let cmd = CommandRequest::new_publish("cae", vec!["hello".into()]);
-        let mut res = cmd.dispatch_streaming(topic);
+        let mut res = cmd.dispatch_streaming(topic.clone());
         let data = res.next().await.unwrap();
         assert_res_ref_ok(&data, &[], &[]);
+        assert_eq!(data.sequence, 1);
+
+        let cmd = CommandRequest::new_publish("cae", vec!["world".into()]);
+        let data = cmd.dispatch_streaming(topic).next().await.unwrap();
+        assert_eq!(data.sequence, 2);
     }
 
     #[tokio::test]
@@ -71,7 +128,7 @@ mod tests {
 
         // this subscription shoud be deletd since it is invalid
         let cmd = CommandRequest::new_publish("cae", vec!["hello".into()]);
-        cmd.dispatch_streaming(topic.clone());
+        cmd.dispatch_streaming(topic.clone()).next().await;
         time::sleep(Duration::from_millis(10)).await;
 
         // try to delete again, should return KvError
@@ -104,6 +161,59 @@ mod tests {
         assert_res_ref_error(&data, 404, "subscription 114514");
     }
 
+    #[tokio::test]
+    async fn dispatch_psubscribe_should_annotate_topics() {
+        let topic = Arc::new(PubSub::default());
+        let cmd = CommandRequest::new_psubscribe("orders.*");
+        let mut res = cmd.dispatch_streaming(topic.clone());
+        let id = get_id(&mut res).await;
+
+        for name in ["users.new", "orders.eu", "orders.us"] {
+            let cmd = CommandRequest::new_publish(name, vec![name.into()]);
+            cmd.dispatch_streaming(topic.clone()).next().await;
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        for name in ["orders.eu", "orders.us"] {
+            let data = res.next().await.unwrap();
+            assert_res_ref_ok(&data, &[name.into()], &[]);
+            assert_eq!(data.topic, name);
+        }
+
+        let cmd = CommandRequest::new_punsubscribe("orders.*", id);
+        let mut ack = cmd.dispatch_streaming(topic.clone());
+        assert_res_ref_ok(&ack.next().await.unwrap(), &[], &[]);
+        assert_eq!(topic.patterns(), 0);
+
+        let cmd = CommandRequest::new_punsubscribe("orders.*", id);
+        let mut ack = cmd.dispatch_streaming(topic);
+        assert_res_ref_error(&ack.next().await.unwrap(), 404, "subscription");
+    }
+
+    #[tokio::test]
+    async fn dispatch_group_commands_should_work() {
+        let topic = Arc::new(PubSub::new().durable("jobs", crate::Retention::new()));
+        let cmd = CommandRequest::new_subscribe_group("cae", "workers", "w1");
+        let mut res = cmd.dispatch_streaming(topic.clone());
+        assert_res_ref_error(&res.next().await.unwrap(), 400, "topic cae is not durable");
+
+        let cmd = CommandRequest::new_subscribe_group("jobs", "workers", "w1");
+        let mut res = cmd.dispatch_streaming(topic.clone());
+        get_id(&mut res).await;
+        let cmd = CommandRequest::new_publish("jobs", vec!["hello".into()]);
+        cmd.dispatch_streaming(topic.clone()).next().await;
+        assert_eq!(res.next().await.unwrap().sequence, 1);
+
+        let cmd = CommandRequest::new_pending("jobs", "workers", "w1");
+        let pending = cmd.dispatch_streaming(topic.clone()).next().await.unwrap();
+        assert_eq!(pending.pending[0].consumer, "w1");
+        let cmd = CommandRequest::new_ack("jobs", "workers", vec![1]);
+        let acked = cmd.dispatch_streaming(topic.clone()).next().await.unwrap();
+        assert_res_ref_ok(&acked, &[1i64.into()], &[]);
+        let cmd = CommandRequest::new_ack("jobs", "other", vec![1]);
+        let acked = cmd.dispatch_streaming(topic).next().await.unwrap();
+        assert_res_ref_error(&acked, 404, "group other");
+    }
+
     pub async fn get_id(res: &mut StreamingResponse) -> u32 {
         let id: i64 = res.next().await.unwrap().as_ref().try_into().unwrap();
         id as u32
@@ -0,0 +1,121 @@
+use bytes::Bytes;
+use futures::Stream;
+use std::sync::Arc;
+use tokio::{sync::mpsc, task};
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::{AsyncStorage, KvError, Kvpair, Storage, Value};
+
+/// Pairs buffered ahead of the consumer of `get_iter`
+const ITER_CAPACITY: usize = 1024;
+
+/// Adapt a blocking `Storage` to `AsyncStorage` by running every call on
+/// tokio's blocking thread pool, so a slow disk never stalls the reactor.
+pub struct BlockingStorage<S> {
+    inner: Arc<S>,
+}
+
+impl<S> BlockingStorage<S>
+where
+    S: Storage + Send + Sync + 'static,
+{
+    pub fn new(store: S) -> Self {
+        Self {
+            inner: Arc::new(store),
+        }
+    }
+
+    async fn run<T, F>(&self, f: F) -> Result<T, KvError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&S) -> Result<T, KvError> + Send + 'static,
+    {
+        let store = Arc::clone(&self.inner);
+        task::spawn_blocking(move || f(&store)).await?
+    }
+}
+
+impl<S> AsyncStorage for BlockingStorage<S>
+where
+    S: Storage + Send + Sync + 'static,
+{
+    async fn get(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        let (table, key) = (table.to_owned(), key.to_owned());
+        self.run(move |s| s.get(&table, &key)).await
+    }
+
+    async fn set(&self, table: &str, key: Bytes, value: Value) -> Result<Option<Value>, KvError> {
+        let table = table.to_owned();
+        self.run(move |s| s.set(&table, key, value)).await
+    }
+
+    async fn contains(&self, table: &str, key: &[u8]) -> Result<bool, KvError> {
+        let (table, key) = (table.to_owned(), key.to_owned());
+        self.run(move |s| s.contains(&table, &key)).await
+    }
+
+    async fn del(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        let (table, key) = (table.to_owned(), key.to_owned());
+        self.run(move |s| s.del(&table, &key)).await
+    }
+
+    async fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        let table = table.to_owned();
+        self.run(move |s| s.get_all(&table)).await
+    }
+
+    fn get_iter(
+        &self,
+        table: &str,
+    ) -> impl Stream<Item = Result<Kvpair, KvError>> + Send + 'static {
+        let store = Arc::clone(&self.inner);
+        let table = table.to_owned();
+        let (tx, rx) = mpsc::channel(ITER_CAPACITY);
+
+        task::spawn_blocking(move || match store.get_iter(&table) {
+            Ok(iter) => {
+                for pair in iter {
+                    // stop walking the table once the consumer is gone
+                    if tx.blocking_send(Ok(pair)).is_err() {
+                        break;
+                    }
+                }
+            }
+            Err(e) => {
+                let _ = tx.blocking_send(Err(e));
+            }
+        });
+
+        ReceiverStream::new(rx)
+    }
+
+    async fn key_counts(&self) -> Result<Vec<(String, u64)>, KvError> {
+        self.run(|s| s.key_counts()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SledDb;
+    use futures::TryStreamExt;
+
+    #[tokio::test]
+    async fn blocking_storage_should_work() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = BlockingStorage::new(SledDb::new(dir.path()));
+
+        let v = store.set("t1", "k1".into(), "v1".into()).await.unwrap();
+        assert_eq!(v, None);
+        let v = store.set("t1", "k2".into(), "v2".into()).await.unwrap();
+        assert_eq!(v, None);
+
+        assert_eq!(store.get("t1", b"k1").await, Ok(Some("v1".into())));
+        assert_eq!(store.contains("t1", b"k2").await, Ok(true));
+        assert_eq!(store.del("t1", b"k2").await, Ok(Some("v2".into())));
+
+        let data: Vec<_> = store.get_iter("t1").try_collect().await.unwrap();
+        assert_eq!(data, vec![Kvpair::new("k1", "v1".into())]);
+        assert_eq!(store.get_all("t1").await, Ok(data));
+    }
+}
@@ -1,88 +1,214 @@
-use sled::{Db, IVec};
-use std::{convert::TryInto, path::Path, str};
+use bytes::Bytes;
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionError},
+    Db, IVec, Transactional, Tree,
+};
+use std::{collections::BTreeMap, convert::Infallible, convert::TryInto, path::Path};
 
-use crate::{KvError, Kvpair, Storage, StorageIter, Value};
+use crate::{storage::display_key, KvError, Kvpair, Storage, StorageIter, Value};
 
+/// Pairs live in their own tree, keyed by table and key. The number of keys
+/// of every table is kept in a separate tree, updated in the same
+/// transaction as the pair.
 #[derive(Debug)]
-pub struct SledDb(Db);
+pub struct SledDb {
+    pairs: Tree,
+    counts: Tree,
+}
 
 impl SledDb {
     pub fn new(path: impl AsRef<Path>) -> Self {
-        Self(sled::open(path).unwrap())
+        Self::open(sled::open(path).unwrap())
+    }
+
+    /// Open a database in a temporary directory that is removed on drop.
+    pub fn temporary() -> Self {
+        Self::open(sled::Config::new().temporary(true).open().unwrap())
+    }
+
+    fn open(db: Db) -> Self {
+        let pairs = db.open_tree(PAIRS_TREE).unwrap();
+        let counts = db.open_tree(COUNTS_TREE).unwrap();
+        if !db.is_empty() {
+            migrate(&db, &pairs).unwrap();
+            counts.clear().unwrap();
+        }
+        if counts.is_empty() && !pairs.is_empty() {
+            rebuild_counts(&pairs, &counts).unwrap();
+        }
+        Self { pairs, counts }
+    }
+
+    /// Keys are stored as `<table prefix><raw key bytes>`, so entries of a
+    /// table are scanned in the byte order of their keys.
+    fn get_full_key(table: &str, key: &[u8]) -> Vec<u8> {
+        let mut name = SledDb::get_table_prefix(table);
+        name.extend_from_slice(key);
+        name
+    }
+
+    /// The length of the table name comes first, so no table's keys can
+    /// start with another table's prefix whatever the bytes of either.
+    fn get_table_prefix(table: &str) -> Vec<u8> {
+        let mut prefix = Vec::with_capacity(table.len() + 4);
+        prefix.extend_from_slice(&(table.len() as u32).to_be_bytes());
+        prefix.extend_from_slice(table.as_bytes());
+        prefix
+    }
+
+    /// Insert (`Some`) or remove (`None`) the pair at `name`, adjusting the
+    /// key count of `table`, and return the previous value.
+    fn update(
+        &self,
+        table: &str,
+        name: &[u8],
+        value: Option<&[u8]>,
+    ) -> Result<Option<IVec>, sled::Error> {
+        (&self.pairs, &self.counts)
+            .transaction(|(data, counts)| {
+                let old = match value {
+                    Some(v) => data.insert(name, v)?,
+                    None => data.remove(name)?,
+                };
+                let n = counts.get(table)?.map(|n| decode_count(&n)).unwrap_or(0);
+                match (old.is_some(), value.is_some()) {
+                    (false, true) => {
+                        counts.insert(table, &(n + 1).to_be_bytes())?;
+                    }
+                    (true, false) if n > 1 => {
+                        counts.insert(table, &(n - 1).to_be_bytes())?;
+                    }
+                    (true, false) => {
+                        counts.remove(table)?;
+                    }
+                    _ => {}
+                }
+                Ok::<_, ConflictableTransactionError<Infallible>>(old)
+            })
+            .map_err(|e| match e {
+                TransactionError::Storage(e) => e,
+                TransactionError::Abort(never) => match never {},
+            })
     }
+}
+
+const PAIRS_TREE: &str = "pairs";
+const COUNTS_TREE: &str = "key_counts";
 
-    fn get_full_key(table: &str, key: &str) -> String {
-        format!("{}:{}", table, key)
+/// Move the pairs of a database written as `<table>:<key>` in the default
+/// tree to the pairs tree. That layout does not record where the table name
+/// ends, so a table whose name contains `:` is taken as the part before the
+/// first `:`, as it was read then.
+fn migrate(db: &Db, pairs: &Tree) -> Result<(), sled::Error> {
+    for entry in db.iter() {
+        let (name, value) = entry?;
+        let Some(at) = name.iter().position(|b| *b == b':') else {
+            continue;
+        };
+        let table = String::from_utf8_lossy(&name[..at]);
+        pairs.insert(SledDb::get_full_key(&table, &name[at + 1..]), value)?;
     }
+    db.clear()
+}
 
-    fn get_table_prefix(table: &str) -> String {
-        format!("{}:", table)
+/// The table a stored key belongs to, read back from its prefix.
+fn table_of(name: &[u8]) -> Option<&[u8]> {
+    let len = u32::from_be_bytes(name.get(..4)?.try_into().ok()?) as usize;
+    name.get(4..4 + len)
+}
+
+/// Count the keys of a database written before counts were kept.
+fn rebuild_counts(pairs: &Tree, counts: &Tree) -> Result<(), sled::Error> {
+    let mut tables = BTreeMap::new();
+    for key in pairs.iter().keys() {
+        let key = key?;
+        if let Some(table) = table_of(&key) {
+            *tables.entry(table.to_vec()).or_insert(0u64) += 1;
+        }
+    }
+    for (table, n) in tables {
+        counts.insert(table, &n.to_be_bytes())?;
     }
+    Ok(())
+}
+
+fn decode_count(n: &[u8]) -> u64 {
+    n.try_into().map(u64::from_be_bytes).unwrap_or_default()
 }
 
 impl Storage for SledDb {
-    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+    fn get(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
         let name = SledDb::get_full_key(table, key);
-        let result = self.0.get(name.as_bytes())?.map(|v| v.as_ref().try_into());
+        let result = self.pairs.get(name)?.map(|v| v.as_ref().try_into());
         result.transpose()
     }
 
-    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+    fn set(&self, table: &str, key: Bytes, value: Value) -> Result<Option<Value>, KvError> {
         let name = SledDb::get_full_key(table, &key);
         let data: Vec<u8> = value.try_into()?;
 
         let result = self
-            .0
-            .insert(name, data)
-            .map_err(|e| KvError::StorageError("set", table.to_string(), key, e.to_string()))?
+            .update(table, &name, Some(&data))
+            .map_err(|e| {
+                KvError::StorageError("set", table.to_string(), display_key(&key), e.to_string())
+            })?
             .map(|v| v.as_ref().try_into());
         result.transpose()
     }
 
-    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+    fn contains(&self, table: &str, key: &[u8]) -> Result<bool, KvError> {
         let name = SledDb::get_full_key(table, key);
 
-        Ok(self.0.contains_key(name)?)
+        Ok(self.pairs.contains_key(name)?)
     }
 
-    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+    fn del(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
         let name = SledDb::get_full_key(table, key);
 
-        let result = self.0.remove(name)?.map(|v| v.as_ref().try_into());
+        let result = self
+            .update(table, &name, None)?
+            .map(|v| v.as_ref().try_into());
         result.transpose()
     }
 
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
-        let prefix = SledDb::get_table_prefix(table);
-        let result = self.0.scan_prefix(prefix).map(|v| v.into()).collect();
-
-        Ok(result)
+        Ok(self.get_iter(table)?.collect())
     }
 
     fn get_iter(&self, table: &str) -> Result<impl Iterator<Item = Kvpair>, KvError> {
         let prefix = SledDb::get_table_prefix(table);
-        let iter = StorageIter::new(self.0.scan_prefix(prefix));
-        Ok(iter)
+        let len = prefix.len();
+        let iter = self
+            .pairs
+            .scan_prefix(prefix)
+            .map(move |v| to_kvpair(v, len));
+        Ok(StorageIter::new(iter))
     }
-}
 
-impl From<Result<(IVec, IVec), sled::Error>> for Kvpair {
-    fn from(v: Result<(IVec, IVec), sled::Error>) -> Self {
-        match v {
-            Ok((k, v)) => match v.as_ref().try_into() {
-                Ok(v) => Kvpair::new(ivec_to_key(k.as_ref()), v),
-                Err(_) => Kvpair::default(),
-            },
-            _ => Kvpair::default(),
-        }
+    fn key_counts(&self) -> Result<Vec<(String, u64)>, KvError> {
+        self.counts
+            .iter()
+            .map(|v| {
+                let (table, n) = v?;
+                Ok((
+                    String::from_utf8_lossy(&table).into_owned(),
+                    decode_count(&n),
+                ))
+            })
+            .collect()
     }
 }
 
-fn ivec_to_key(ivec: &[u8]) -> &str {
-    let s = str::from_utf8(ivec).unwrap();
-    let mut iter = s.split(':');
-    iter.next();
-    iter.next().unwrap()
+/// Convert a scanned sled entry into a `Kvpair`, stripping the first
+/// `prefix_len` bytes (the table prefix) from the stored key.
+fn to_kvpair(v: Result<(IVec, IVec), sled::Error>, prefix_len: usize) -> Kvpair {
+    match v {
+        Ok((k, v)) => match v.as_ref().try_into() {
+            Ok(v) => Kvpair::new(Bytes::copy_from_slice(&k[prefix_len..]), v),
+            Err(_) => Kvpair::default(),
+        },
+        _ => Kvpair::default(),
+    }
 }
 
 #[cfg(test)]
@@ -91,13 +217,102 @@ mod tests {
 
     #[test]
     fn test_sledb() {
-        let db = SledDb::new("kv_db");
-        db.set("table", "abc".to_string(), "v".into()).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let db = SledDb::new(dir.path());
+        db.set("table", "abc".into(), "v".into()).unwrap();
 
-        let v = db.get("table", "abc").unwrap();
+        let v = db.get("table", b"abc").unwrap();
         assert_eq!(v, Some(Value::from("v")));
 
-        let v = db.get("table1", "abc").unwrap();
+        let v = db.get("table1", b"abc").unwrap();
         assert_eq!(v, None);
     }
+
+    #[test]
+    fn sledb_should_count_keys_per_table() {
+        let db = SledDb::temporary();
+        db.set("t1", "k1".into(), "v".into()).unwrap();
+        db.set("t1", "a:b".into(), "v".into()).unwrap();
+        db.set("t2", "k1".into(), "v".into()).unwrap();
+        db.set("t2:x", "k1".into(), "v".into()).unwrap();
+        db.set("t2:x", "k2".into(), "v".into()).unwrap();
+        db.set("t2:x", "k2".into(), "v2".into()).unwrap();
+        db.del("t1", b"k1").unwrap();
+        db.del("t1", b"k1").unwrap();
+
+        let counts = db.key_counts().unwrap();
+        assert_eq!(
+            counts,
+            vec![("t1".into(), 1), ("t2".into(), 1), ("t2:x".into(), 2)]
+        );
+    }
+
+    #[test]
+    fn sledb_should_rebuild_missing_counts() {
+        let raw = sled::Config::new().temporary(true).open().unwrap();
+        let db = SledDb::open(raw.clone());
+        db.set("t1", "k1".into(), "v".into()).unwrap();
+        db.set("t1", "k2".into(), "v".into()).unwrap();
+        db.counts.clear().unwrap();
+
+        let db = SledDb::open(raw);
+        assert_eq!(db.key_counts().unwrap(), vec![("t1".into(), 2)]);
+    }
+
+    #[test]
+    fn sledb_tables_should_not_alias() {
+        let db = SledDb::temporary();
+        db.set("t", "x:k1".into(), "a".into()).unwrap();
+        db.set("t:x", "k1".into(), "b".into()).unwrap();
+
+        assert_eq!(
+            db.get_all("t").unwrap(),
+            vec![Kvpair::new("x:k1", "a".into())]
+        );
+        assert_eq!(
+            db.key_counts().unwrap(),
+            vec![("t".into(), 1), ("t:x".into(), 1)]
+        );
+        assert_eq!(db.del("t", b"x:k1").unwrap(), Some("a".into()));
+        assert!(!db.contains("t", b"x:k1").unwrap());
+        assert_eq!(db.get("t:x", b"k1").unwrap(), Some("b".into()));
+    }
+
+    #[test]
+    fn sledb_should_migrate_the_old_layout() {
+        let raw = sled::Config::new().temporary(true).open().unwrap();
+        let v: Vec<u8> = Value::from("v").try_into().unwrap();
+        raw.insert("t1:k1", v.clone()).unwrap();
+        raw.insert("t1:a:b", v).unwrap();
+
+        let db = SledDb::open(raw.clone());
+        assert_eq!(db.get("t1", b"a:b").unwrap(), Some("v".into()));
+        assert_eq!(db.get_all("t1").unwrap().len(), 2);
+        assert_eq!(db.key_counts().unwrap(), vec![("t1".into(), 2)]);
+        assert!(raw.is_empty());
+    }
+
+    #[test]
+    fn sledb_binary_keys_should_scan_in_byte_order() {
+        let db = SledDb::temporary();
+        let keys: [&'static [u8]; 4] = [b"\xff\x00", b"a:b", b"\x00\x01", b"\x7f"];
+        for (i, key) in keys.iter().enumerate() {
+            db.set("binary", Bytes::from_static(key), (i as i64).into())
+                .unwrap();
+        }
+
+        let v = db.get("binary", b"a:b").unwrap();
+        assert_eq!(v, Some(Value::from(1)));
+
+        let data = db.get_all("binary").unwrap();
+        assert_eq!(
+            data,
+            vec![
+                Kvpair::new(Bytes::from_static(b"\x00\x01"), 2.into()),
+                Kvpair::new(Bytes::from_static(b"a:b"), 1.into()),
+                Kvpair::new(Bytes::from_static(b"\x7f"), 3.into()),
+                Kvpair::new(Bytes::from_static(b"\xff\x00"), 0.into()),
+            ]
+        );
+    }
 }
@@ -0,0 +1,303 @@
+use crate::{CommandResponse, KvError};
+use dashmap::DashMap;
+use prost::Message;
+use sled::Db;
+use std::{
+    collections::VecDeque,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// How many messages of a durable topic are kept, and for how long. The
+/// default keeps everything.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Retention {
+    max_messages: Option<u64>,
+    max_age: Option<Duration>,
+}
+
+impl Retention {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep at most the last `n` messages.
+    pub fn max_messages(mut self, n: u64) -> Self {
+        self.max_messages = Some(n);
+        self
+    }
+
+    /// Drop messages published longer than `age` ago.
+    pub fn max_age(mut self, age: Duration) -> Self {
+        self.max_age = Some(age);
+        self
+    }
+
+    /// The oldest timestamp still kept, in milliseconds since the epoch.
+    fn cutoff_ms(&self) -> Option<u64> {
+        self.max_age
+            .map(|age| now_ms().saturating_sub(age.as_millis() as u64))
+    }
+}
+
+/// An append-only log of the messages published to durable topics.
+///
+/// Sequence numbers start at 1 in every topic and are never reused, even
+/// once the messages holding them are trimmed, so a subscriber can resume
+/// from the last one it saw.
+pub trait TopicLog: Send + Sync + 'static {
+    /// Append `message` to `topic`, returning its sequence number.
+    fn append(&self, topic: &str, message: &CommandResponse) -> Result<u64, KvError>;
+    /// Up to `limit` messages of `topic` from sequence `from` on, oldest
+    /// first, with `sequence` set.
+    fn read(&self, topic: &str, from: u64, limit: usize) -> Result<Vec<CommandResponse>, KvError>;
+    /// Drop the oldest messages of `topic` that `retention` no longer keeps.
+    fn trim(&self, topic: &str, retention: &Retention) -> Result<(), KvError>;
+    /// The last sequence number handed out in `topic`, 0 if none was.
+    fn last(&self, topic: &str) -> Result<u64, KvError>;
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[derive(Debug, Default)]
+struct MemoryTopic {
+    last: u64,
+    /// Sequence number, publish time in milliseconds and message
+    messages: VecDeque<(u64, u64, CommandResponse)>,
+}
+
+/// A `TopicLog` kept in memory, lost on restart.
+#[derive(Debug, Default)]
+pub struct MemoryLog {
+    topics: DashMap<String, MemoryTopic>,
+}
+
+impl MemoryLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl TopicLog for MemoryLog {
+    fn append(&self, topic: &str, message: &CommandResponse) -> Result<u64, KvError> {
+        let mut topic = self.topics.entry(topic.into()).or_default();
+        topic.last += 1;
+        let seq = topic.last;
+        let mut message = message.clone();
+        message.sequence = seq;
+        topic.messages.push_back((seq, now_ms(), message));
+        Ok(seq)
+    }
+
+    fn read(&self, topic: &str, from: u64, limit: usize) -> Result<Vec<CommandResponse>, KvError> {
+        let Some(topic) = self.topics.get(topic) else {
+            return Ok(vec![]);
+        };
+        // sequence numbers are contiguous from the oldest message on
+        let first = topic.messages.front().map_or(0, |m| m.0);
+        let skip = from.saturating_sub(first) as usize;
+        Ok(topic
+            .messages
+            .iter()
+            .skip(skip)
+            .take(limit)
+            .map(|m| m.2.clone())
+            .collect())
+    }
+
+    fn trim(&self, topic: &str, retention: &Retention) -> Result<(), KvError> {
+        let Some(mut topic) = self.topics.get_mut(topic) else {
+            return Ok(());
+        };
+        if let Some(max) = retention.max_messages {
+            while topic.messages.len() as u64 > max {
+                topic.messages.pop_front();
+            }
+        }
+        if let Some(cutoff) = retention.cutoff_ms() {
+            while topic.messages.front().is_some_and(|m| m.1 < cutoff) {
+                topic.messages.pop_front();
+            }
+        }
+        Ok(())
+    }
+
+    fn last(&self, topic: &str) -> Result<u64, KvError> {
+        Ok(self.topics.get(topic).map_or(0, |t| t.last))
+    }
+}
+
+/// A `TopicLog` kept in sled, one tree per topic keyed by the big-endian
+/// sequence number. Its calls may block on the disk, so `PubSub` makes them
+/// from blocking threads.
+#[derive(Debug)]
+pub struct SledLog {
+    db: Db,
+    /// The last sequence number handed out in each topic
+    sequences: sled::Tree,
+}
+
+impl SledLog {
+    pub fn new(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        Self::from_db(sled::open(path)?)
+    }
+
+    /// Open a log in a temporary directory that is removed on drop.
+    pub fn temporary() -> Result<Self, KvError> {
+        Self::from_db(sled::Config::new().temporary(true).open()?)
+    }
+
+    fn from_db(db: Db) -> Result<Self, KvError> {
+        let sequences = db.open_tree("sequences")?;
+        Ok(Self { db, sequences })
+    }
+
+    fn tree(&self, topic: &str) -> Result<sled::Tree, KvError> {
+        Ok(self.db.open_tree(format!("topic:{}", topic))?)
+    }
+}
+
+fn sequence(key: &[u8]) -> Result<u64, KvError> {
+    key.try_into()
+        .map(u64::from_be_bytes)
+        .map_err(|_| KvError::Internal("malformed topic log key".into()))
+}
+
+/// The publish time in milliseconds a value starts with.
+fn published_ms(value: &[u8]) -> u64 {
+    value
+        .get(..8)
+        .and_then(|v| v.try_into().ok())
+        .map_or(0, u64::from_be_bytes)
+}
+
+/// Values are the publish time in milliseconds followed by the message.
+fn decode(key: &[u8], value: &[u8]) -> Result<CommandResponse, KvError> {
+    let mut message = CommandResponse::decode(value.get(8..).unwrap_or_default())?;
+    message.sequence = sequence(key)?;
+    Ok(message)
+}
+
+impl TopicLog for SledLog {
+    fn append(&self, topic: &str, message: &CommandResponse) -> Result<u64, KvError> {
+        let last = self.sequences.update_and_fetch(topic, |old| {
+            let last = old
+                .and_then(|v| v.try_into().ok())
+                .map_or(0, u64::from_be_bytes);
+            Some((last + 1).to_be_bytes().to_vec())
+        })?;
+        let seq = last
+            .and_then(|v| v.as_ref().try_into().ok())
+            .map(u64::from_be_bytes)
+            .unwrap_or_default();
+
+        let mut value = now_ms().to_be_bytes().to_vec();
+        message.encode(&mut value)?;
+        self.tree(topic)?.insert(seq.to_be_bytes(), value)?;
+        Ok(seq)
+    }
+
+    fn read(&self, topic: &str, from: u64, limit: usize) -> Result<Vec<CommandResponse>, KvError> {
+        self.tree(topic)?
+            .range(from.to_be_bytes()..)
+            .take(limit)
+            .map(|entry| {
+                let (key, value) = entry?;
+                decode(&key, &value)
+            })
+            .collect()
+    }
+
+    /// Only visits the messages dropped and the oldest one kept, so trimming
+    /// after every append stays cheap however long the topic is.
+    fn trim(&self, topic: &str, retention: &Retention) -> Result<(), KvError> {
+        let tree = self.tree(topic)?;
+        if let Some(max) = retention.max_messages {
+            let keep_from = self.last(topic)?.saturating_sub(max) + 1;
+            for key in tree.range(..keep_from.to_be_bytes()).keys() {
+                tree.remove(key?)?;
+            }
+        }
+        if let Some(cutoff) = retention.cutoff_ms() {
+            for entry in tree.iter() {
+                let (key, value) = entry?;
+                if published_ms(&value) >= cutoff {
+                    break;
+                }
+                tree.remove(key)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn last(&self, topic: &str) -> Result<u64, KvError> {
+        match self.sequences.get(topic)? {
+            Some(last) => sequence(&last),
+            None => Ok(0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Value;
+
+    fn message(n: i64) -> CommandResponse {
+        Value::from(n).into()
+    }
+
+    fn sequences(log: &impl TopicLog, from: u64) -> Vec<u64> {
+        let messages = log.read("t1", from, 100).unwrap();
+        messages.iter().map(|m| m.sequence).collect()
+    }
+
+    fn log_should_retain_by_count(log: impl TopicLog) {
+        for n in 1..=5 {
+            assert_eq!(log.append("t1", &message(n)).unwrap(), n as u64);
+        }
+        assert_eq!(log.append("t2", &message(1)).unwrap(), 1);
+
+        assert_eq!(sequences(&log, 0), [1, 2, 3, 4, 5]);
+        assert_eq!(sequences(&log, 4), [4, 5]);
+        let read: Vec<_> = log.read("t1", 2, 2).unwrap();
+        let read: Vec<_> = read.into_iter().map(|m| (m.sequence, m.values)).collect();
+        assert_eq!(read, [(2, vec![2i64.into()]), (3, vec![3i64.into()])]);
+
+        log.trim("t1", &Retention::new().max_messages(2)).unwrap();
+        assert_eq!(sequences(&log, 0), [4, 5]);
+        // trimmed sequence numbers are not reused
+        assert_eq!(log.append("t1", &message(6)).unwrap(), 6);
+        assert_eq!(log.last("t1").unwrap(), 6);
+        assert_eq!(log.last("t3").unwrap(), 0);
+        assert_eq!(sequences(&log, 1), [4, 5, 6]);
+        assert!(log.read("t3", 0, 100).unwrap().is_empty());
+    }
+
+    fn log_should_retain_by_age(log: impl TopicLog) {
+        log.append("t1", &message(1)).unwrap();
+        std::thread::sleep(Duration::from_millis(30));
+        log.append("t1", &message(2)).unwrap();
+
+        let retention = Retention::new().max_age(Duration::from_millis(20));
+        log.trim("t1", &retention).unwrap();
+        assert_eq!(sequences(&log, 0), [2]);
+    }
+
+    #[test]
+    fn memory_log_should_work() {
+        log_should_retain_by_count(MemoryLog::new());
+        log_should_retain_by_age(MemoryLog::new());
+    }
+
+    #[test]
+    fn sled_log_should_work() {
+        log_should_retain_by_count(SledLog::temporary().unwrap());
+        log_should_retain_by_age(SledLog::temporary().unwrap());
+    }
+}
@@ -1,9 +1,14 @@
-use crate::{KvError, Kvpair, Storage, StorageIter, Value};
+use crate::{AsyncStorage, KvError, Kvpair, Storage, Value};
+use bytes::Bytes;
 use dashmap::{mapref::one::Ref, DashMap};
+use futures::{stream, Stream};
+use std::{sync::Arc, vec};
 
-#[derive(Clone, Debug, Default)]
+type Table = Arc<DashMap<Bytes, Value>>;
+
+#[derive(Debug, Default)]
 pub struct MemTable {
-    tables: DashMap<String, DashMap<String, Value>>,
+    tables: DashMap<String, Table>,
 }
 
 impl MemTable {
@@ -11,7 +16,7 @@ impl MemTable {
         Self::default()
     }
 
-    fn get_or_create_table(&self, name: &str) -> Ref<String, DashMap<String, Value>> {
+    fn get_or_create_table(&self, name: &str) -> Ref<String, Table> {
         match self.tables.get(name) {
             Some(table) => table,
             None => {
@@ -20,25 +25,44 @@ impl MemTable {
             }
         }
     }
+
+    fn table_iter(&self, table: &str) -> TableIter {
+        TableIter {
+            table: Arc::clone(&self.get_or_create_table(table)),
+            shard: 0,
+            buf: Vec::new().into_iter(),
+        }
+    }
+}
+
+impl Clone for MemTable {
+    fn clone(&self) -> Self {
+        let tables = self
+            .tables
+            .iter()
+            .map(|t| (t.key().clone(), Arc::new(DashMap::clone(t.value()))))
+            .collect();
+        Self { tables }
+    }
 }
 
 impl Storage for MemTable {
-    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+    fn get(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
         let table = self.get_or_create_table(table);
         Ok(table.get(key).map(|v| v.value().clone()))
     }
 
-    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+    fn set(&self, table: &str, key: Bytes, value: Value) -> Result<Option<Value>, KvError> {
         let table = self.get_or_create_table(table);
         Ok(table.insert(key, value))
     }
 
-    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+    fn contains(&self, table: &str, key: &[u8]) -> Result<bool, KvError> {
         let table = self.get_or_create_table(table);
         Ok(table.contains_key(key))
     }
 
-    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+    fn del(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
         let table = self.get_or_create_table(table);
         Ok(table.remove(key).map(|(_k, v)| v))
     }
@@ -47,12 +71,110 @@ impl Storage for MemTable {
         let table = self.get_or_create_table(table);
         Ok(table
             .iter()
-            .map(|v| Kvpair::new(v.key(), v.value().clone()))
+            .map(|v| Kvpair::new(v.key().clone(), v.value().clone()))
             .collect())
     }
 
+    /// Iterate the table lazily, one shard at a time.
+    ///
+    /// Only the shard being read is copied, and its lock is released before
+    /// any item is yielded, so the caller may write to the table while
+    /// iterating. The result is weakly consistent: every shard is a snapshot
+    /// taken when the iterator reaches it, keys that stay untouched during
+    /// the iteration are yielded exactly once, keys inserted or removed
+    /// concurrently may or may not be seen, and no key is yielded twice.
     fn get_iter(&self, table: &str) -> Result<impl Iterator<Item = Kvpair>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(StorageIter::new(DashMap::to_owned(&table).into_iter()))
+        Ok(self.table_iter(table))
+    }
+
+    fn key_counts(&self) -> Result<Vec<(String, u64)>, KvError> {
+        Ok(self
+            .tables
+            .iter()
+            .map(|t| (t.key().clone(), t.value().len() as u64))
+            .collect())
+    }
+}
+
+/// Everything is in memory, so calls complete inline without leaving the
+/// runtime thread.
+impl AsyncStorage for MemTable {
+    async fn get(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        Storage::get(self, table, key)
+    }
+
+    async fn set(&self, table: &str, key: Bytes, value: Value) -> Result<Option<Value>, KvError> {
+        Storage::set(self, table, key, value)
+    }
+
+    async fn contains(&self, table: &str, key: &[u8]) -> Result<bool, KvError> {
+        Storage::contains(self, table, key)
+    }
+
+    async fn del(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        Storage::del(self, table, key)
+    }
+
+    async fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        Storage::get_all(self, table)
+    }
+
+    fn get_iter(
+        &self,
+        table: &str,
+    ) -> impl Stream<Item = Result<Kvpair, KvError>> + Send + 'static {
+        stream::iter(self.table_iter(table).map(Ok))
+    }
+
+    async fn key_counts(&self) -> Result<Vec<(String, u64)>, KvError> {
+        Storage::key_counts(self)
+    }
+}
+
+struct TableIter {
+    table: Table,
+    shard: usize,
+    buf: vec::IntoIter<Kvpair>,
+}
+
+impl Iterator for TableIter {
+    type Item = Kvpair;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(pair) = self.buf.next() {
+                return Some(pair);
+            }
+
+            let shard = self.table.shards().get(self.shard)?;
+            self.shard += 1;
+            self.buf = shard
+                .read()
+                .iter()
+                .map(|(k, v)| Kvpair::new(k.clone(), v.get().clone()))
+                .collect::<Vec<_>>()
+                .into_iter();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memtable_get_iter_should_allow_writes_while_iterating() {
+        let store = MemTable::new();
+        for i in 0..100 {
+            Storage::set(&store, "t1", format!("k{}", i).into(), i.into()).unwrap();
+        }
+
+        let mut count = 0;
+        for pair in Storage::get_iter(&store, "t1").unwrap() {
+            Storage::del(&store, "t1", &pair.key).unwrap();
+            count += 1;
+        }
+        assert_eq!(count, 100);
+        assert!(Storage::get_all(&store, "t1").unwrap().is_empty());
     }
 }
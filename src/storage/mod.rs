@@ -1,15 +1,66 @@
+mod blocking;
 mod db;
+mod log;
 pub mod memory;
 
 use crate::{KvError, Kvpair, Value};
+use bytes::Bytes;
+use futures::Stream;
+use std::future::Future;
 
+pub use blocking::BlockingStorage;
+pub use db::SledDb;
+pub use log::{MemoryLog, Retention, SledLog, TopicLog};
+
+/// Keys are raw bytes, so binary ids (e.g. 16-byte UUIDs) can be stored
+/// without hex-encoding them first.
 pub trait Storage {
-    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError>;
-    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError>;
-    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError>;
-    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError>;
+    fn get(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError>;
+    fn set(&self, table: &str, key: Bytes, value: Value) -> Result<Option<Value>, KvError>;
+    fn contains(&self, table: &str, key: &[u8]) -> Result<bool, KvError>;
+    fn del(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError>;
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError>;
     fn get_iter(&self, table: &str) -> Result<impl Iterator<Item = Kvpair>, KvError>;
+    /// Every table with the number of keys it holds. Called on every `Info`
+    /// and metrics scrape, so backends should keep counts rather than scan.
+    fn key_counts(&self) -> Result<Vec<(String, u64)>, KvError> {
+        Err(KvError::Unavailable(
+            "this storage backend does not count keys".into(),
+        ))
+    }
+}
+
+/// Storage as seen by `Service`. Implementations must not block the runtime:
+/// backends doing disk IO (e.g. `SledDb`) are wrapped in `BlockingStorage`,
+/// which runs them on tokio's blocking thread pool.
+pub trait AsyncStorage: Send + Sync + 'static {
+    fn get(
+        &self,
+        table: &str,
+        key: &[u8],
+    ) -> impl Future<Output = Result<Option<Value>, KvError>> + Send;
+    fn set(
+        &self,
+        table: &str,
+        key: Bytes,
+        value: Value,
+    ) -> impl Future<Output = Result<Option<Value>, KvError>> + Send;
+    fn contains(
+        &self,
+        table: &str,
+        key: &[u8],
+    ) -> impl Future<Output = Result<bool, KvError>> + Send;
+    fn del(
+        &self,
+        table: &str,
+        key: &[u8],
+    ) -> impl Future<Output = Result<Option<Value>, KvError>> + Send;
+    fn get_all(&self, table: &str) -> impl Future<Output = Result<Vec<Kvpair>, KvError>> + Send;
+    /// Stream the pairs of a table. The stream does not borrow the storage,
+    /// so it can outlive the request that created it.
+    fn get_iter(&self, table: &str)
+        -> impl Stream<Item = Result<Kvpair, KvError>> + Send + 'static;
+    fn key_counts(&self) -> impl Future<Output = Result<Vec<(String, u64)>, KvError>> + Send;
 }
 
 pub struct StorageIter<T> {
@@ -33,3 +84,12 @@ where
         self.data.next().map(|v| v.into())
     }
 }
+
+/// Render a key for error messages: UTF-8 keys are shown as-is, binary keys
+/// are escaped.
+pub(crate) fn display_key(key: &[u8]) -> String {
+    match std::str::from_utf8(key) {
+        Ok(s) => s.to_string(),
+        Err(_) => key.escape_ascii().to_string(),
+    }
+}
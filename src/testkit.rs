@@ -0,0 +1,315 @@
+//! Conformance tests for `Storage` backends.
+//!
+//! Every check takes a `&impl Storage` and panics on failure. Checks use their
+//! own tables, so they can share one store. A third-party backend enables the
+//! `testkit` feature and either calls [`run_all`] or generates one test per
+//! check:
+//!
+//! ```ignore
+//! mod conformance {
+//!     kv::storage_conformance_tests!(my_backend::Store::new());
+//! }
+//! ```
+
+use crate::{Kvpair, Storage, Value};
+use bytes::Bytes;
+use std::thread;
+
+/// Run every conformance check against `store`.
+pub fn run_all<S: Storage + Sync>(store: &S) {
+    basic_interface(store);
+    get_all(store);
+    get_iter(store);
+    empty_table(store);
+    table_isolation(store);
+    key_counts(store);
+    edge_case_keys(store);
+    value_types(store);
+    large_values(store);
+    concurrency(store);
+}
+
+/// Generate a `#[test]` for every conformance check, each against a fresh
+/// store built by `$store`.
+#[macro_export]
+macro_rules! storage_conformance_tests {
+    ($store:expr) => {
+        $crate::storage_conformance_tests!(@test $store;
+            basic_interface, get_all, get_iter, empty_table, table_isolation,
+            key_counts, edge_case_keys, value_types, large_values, concurrency);
+    };
+    (@test $store:expr; $($name:ident),*) => {
+        $(
+            #[test]
+            fn $name() {
+                let store = $store;
+                $crate::testkit::$name(&store);
+            }
+        )*
+    };
+}
+
+/// get / set / contains / del on single keys.
+pub fn basic_interface(store: &impl Storage) {
+    // insert new kv pair
+    let v = store.set("basic", "hello".into(), "world".into());
+    assert!(v.unwrap().is_none());
+    // update existed key
+    let v1 = store.set("basic", "hello".into(), "world1".into());
+    assert_eq!(v1, Ok(Some("world".into())));
+
+    // get existed key
+    let v = store.get("basic", b"hello");
+    assert_eq!(v, Ok(Some("world1".into())));
+
+    // get non-existed key or table
+    assert_eq!(Ok(None), store.get("basic", b"hello1"));
+    assert!(store.get("basic_none", b"hello1").unwrap().is_none());
+
+    // contains
+    assert_eq!(store.contains("basic", b"hello"), Ok(true));
+    assert_eq!(store.contains("basic", b"hello1"), Ok(false));
+    assert_eq!(store.contains("basic_none", b"hello"), Ok(false));
+
+    // del
+    let v = store.del("basic", b"hello");
+    assert_eq!(v, Ok(Some("world1".into())));
+    assert_eq!(store.contains("basic", b"hello"), Ok(false));
+
+    // del
+    assert_eq!(Ok(None), store.del("basic", b"hello1"));
+    assert_eq!(Ok(None), store.del("basic_none", b"hello"));
+}
+
+pub fn get_all(store: &impl Storage) {
+    store.set("get_all", "k1".into(), "v1".into()).unwrap();
+    store.set("get_all", "k2".into(), "v2".into()).unwrap();
+    let data = sorted(store.get_all("get_all").unwrap());
+    assert_eq!(
+        data,
+        vec![
+            Kvpair::new("k1", "v1".into()),
+            Kvpair::new("k2", "v2".into())
+        ]
+    )
+}
+
+pub fn get_iter(store: &impl Storage) {
+    store.set("get_iter", "k1".into(), "v1".into()).unwrap();
+    store.set("get_iter", "k2".into(), "v2".into()).unwrap();
+    let data = sorted(store.get_iter("get_iter").unwrap().collect());
+    assert_eq!(
+        data,
+        vec![
+            Kvpair::new("k1", "v1".into()),
+            Kvpair::new("k2", "v2".into())
+        ]
+    )
+}
+
+/// Reading a table that was never written to is not an error.
+pub fn empty_table(store: &impl Storage) {
+    assert_eq!(store.get_all("empty"), Ok(vec![]));
+    assert_eq!(store.get_iter("empty").unwrap().count(), 0);
+
+    store.set("emptied", "k".into(), "v".into()).unwrap();
+    store.del("emptied", b"k").unwrap();
+    assert_eq!(store.get_all("emptied"), Ok(vec![]));
+    assert_eq!(store.get_iter("emptied").unwrap().count(), 0);
+}
+
+/// The same key in different tables, including tables whose names are
+/// prefixes of each other or contain a separator, never aliases, nor does a
+/// key holding or starting with a separator.
+pub fn table_isolation(store: &impl Storage) {
+    let tables = ["iso", "iso1", "iso10", "iso_"];
+    for (i, table) in tables.iter().enumerate() {
+        store.set(table, "k".into(), (i as i64).into()).unwrap();
+    }
+
+    for (i, table) in tables.iter().enumerate() {
+        assert_eq!(store.get(table, b"k"), Ok(Some((i as i64).into())));
+        assert_eq!(
+            store.get_all(table),
+            Ok(vec![Kvpair::new("k", (i as i64).into())])
+        );
+    }
+
+    store.del("iso", b"k").unwrap();
+    assert_eq!(store.contains("iso1", b"k"), Ok(true));
+
+    let pairs: [(&str, &str); 6] = [
+        ("sep", "x:k1"),
+        ("sep", ":x"),
+        ("sep", "/x"),
+        ("sep:x", "k1"),
+        ("sep/x", "k1"),
+        ("sep:", "x"),
+    ];
+    for (i, (table, key)) in pairs.iter().enumerate() {
+        store.set(table, (*key).into(), (i as i64).into()).unwrap();
+    }
+    let own = vec![
+        Kvpair::new("/x", 2.into()),
+        Kvpair::new(":x", 1.into()),
+        Kvpair::new("x:k1", 0.into()),
+    ];
+    assert_eq!(sorted(store.get_all("sep").unwrap()), own);
+    assert_eq!(sorted(store.get_iter("sep").unwrap().collect()), own);
+    assert_eq!(store.contains("sep", b"k1"), Ok(false));
+    assert_eq!(store.contains("sep:", b":x"), Ok(false));
+
+    let counts = store.key_counts().unwrap();
+    for (table, n) in [("sep", 3), ("sep:x", 1), ("sep/x", 1), ("sep:", 1)] {
+        assert!(
+            counts.contains(&(table.into(), n)),
+            "{} has {} keys",
+            table,
+            n
+        );
+    }
+
+    assert_eq!(store.del("sep", b"x:k1"), Ok(Some(0.into())));
+    assert_eq!(store.del("sep", b"x"), Ok(None));
+    assert_eq!(store.get("sep:x", b"k1"), Ok(Some(3.into())));
+    assert_eq!(store.get("sep:", b"x"), Ok(Some(5.into())));
+}
+
+/// Overwrites are not counted twice, deletes are, and a table whose name
+/// contains a separator-like character is counted on its own.
+pub fn key_counts(store: &impl Storage) {
+    store.set("counted", "k1".into(), "v".into()).unwrap();
+    store.set("counted", "k2".into(), "v".into()).unwrap();
+    store.set("counted", "k2".into(), "v2".into()).unwrap();
+    store.set("counted:sub", "k1".into(), "v".into()).unwrap();
+    store.set("counted_gone", "k1".into(), "v".into()).unwrap();
+    store.del("counted_gone", b"k1").unwrap();
+    store.del("counted_gone", b"k1").unwrap();
+
+    let count = |table: &str| {
+        store
+            .key_counts()
+            .unwrap()
+            .into_iter()
+            .find(|(t, _)| t == table)
+            .map(|(_, n)| n)
+            .unwrap_or_default()
+    };
+    assert_eq!(count("counted"), 2);
+    assert_eq!(count("counted:sub"), 1);
+    assert_eq!(count("counted_gone"), 0);
+}
+
+/// Empty, unicode, binary, separator-like and long keys, and unicode table
+/// names.
+pub fn edge_case_keys(store: &impl Storage) {
+    let keys: Vec<Bytes> = vec![
+        Bytes::new(),
+        "键🔑".into(),
+        "a:b:c".into(),
+        Bytes::from_static(b"\x00nul\x00"),
+        Bytes::from_static(b"\xff\xfe\x80"),
+        Bytes::from(vec![b'x'; 4096]),
+    ];
+
+    for table in ["edge", "表格"] {
+        for (i, key) in keys.iter().enumerate() {
+            let v = store.set(table, key.clone(), (i as i64).into()).unwrap();
+            assert_eq!(v, None);
+        }
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(store.get(table, key), Ok(Some((i as i64).into())));
+            assert_eq!(store.contains(table, key), Ok(true));
+        }
+
+        let expected = sorted(
+            keys.iter()
+                .enumerate()
+                .map(|(i, k)| Kvpair::new(k.clone(), (i as i64).into()))
+                .collect(),
+        );
+        assert_eq!(sorted(store.get_all(table).unwrap()), expected);
+        assert_eq!(sorted(store.get_iter(table).unwrap().collect()), expected);
+
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(store.del(table, key), Ok(Some((i as i64).into())));
+        }
+        assert_eq!(store.get_all(table), Ok(vec![]));
+    }
+}
+
+/// Every kind of `Value` round-trips unchanged.
+pub fn value_types(store: &impl Storage) {
+    let values: Vec<Value> = vec![
+        Value::default(),
+        "".into(),
+        "string ✓".into(),
+        Bytes::from_static(b"\x00\x01\xff").into(),
+        i64::MIN.into(),
+        i64::MAX.into(),
+        f64::MIN_POSITIVE.into(),
+        (-0.5).into(),
+        true.into(),
+        false.into(),
+    ];
+
+    for (i, value) in values.iter().enumerate() {
+        let key = format!("v{}", i);
+        store
+            .set("values", key.clone().into(), value.clone())
+            .unwrap();
+        assert_eq!(store.get("values", key.as_bytes()), Ok(Some(value.clone())));
+    }
+}
+
+/// A multi-megabyte value and a table with many entries.
+pub fn large_values(store: &impl Storage) {
+    let big: Value = Bytes::from(vec![0x5a; 8 * 1024 * 1024]).into();
+    store.set("large", "big".into(), big.clone()).unwrap();
+    assert_eq!(store.get("large", b"big"), Ok(Some(big)));
+
+    for i in 0..10_000i64 {
+        let key = format!("key-{:05}", i);
+        store.set("large_table", key.into(), i.into()).unwrap();
+    }
+    assert_eq!(store.get_all("large_table").unwrap().len(), 10_000);
+    assert_eq!(store.get_iter("large_table").unwrap().count(), 10_000);
+}
+
+/// Writers on disjoint keys never lose updates, and racing writers on one key
+/// leave one of the written values.
+pub fn concurrency<S: Storage + Sync>(store: &S) {
+    const THREADS: i64 = 8;
+    const KEYS: i64 = 500;
+
+    thread::scope(|s| {
+        for t in 0..THREADS {
+            s.spawn(move || {
+                for i in 0..KEYS {
+                    let key = format!("{}-{}", t, i);
+                    store
+                        .set("concurrent", key.clone().into(), i.into())
+                        .unwrap();
+                    assert_eq!(store.get("concurrent", key.as_bytes()), Ok(Some(i.into())));
+                    store.set("contended", "k".into(), t.into()).unwrap();
+                }
+            });
+        }
+    });
+
+    let data = store.get_all("concurrent").unwrap();
+    assert_eq!(data.len(), (THREADS * KEYS) as usize);
+
+    let v: i64 = store
+        .get("contended", b"k")
+        .unwrap()
+        .unwrap()
+        .try_into()
+        .unwrap();
+    assert!((0..THREADS).contains(&v));
+}
+
+fn sorted(mut data: Vec<Kvpair>) -> Vec<Kvpair> {
+    data.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    data
+}